@@ -0,0 +1,185 @@
+use std::fmt;
+
+/// A region of the source: byte offsets `start..end`, with the lines of its first and last token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub end_line: usize,
+}
+
+impl Span {
+    /// The span covering both `self` and `other`, `other` must not start before `self`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end,
+            line: self.line,
+            end_line: other.end_line,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ident<'a> {
+    pub name: &'a str,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Literal<'a> {
+    Number(f64),
+    String(&'a str),
+    True,
+    False,
+    Nil,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogicalOp {
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr<'a> {
+    pub kind: ExprKind<'a>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind<'a> {
+    Literal(Literal<'a>),
+    Variable(Ident<'a>),
+    Assign {
+        name: Ident<'a>,
+        value: Box<Expr<'a>>,
+    },
+    Unary {
+        op: UnaryOp,
+        operand: Box<Expr<'a>>,
+    },
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr<'a>>,
+        right: Box<Expr<'a>>,
+    },
+    Logical {
+        op: LogicalOp,
+        left: Box<Expr<'a>>,
+        right: Box<Expr<'a>>,
+    },
+    Grouping(Box<Expr<'a>>),
+    Call {
+        callee: Box<Expr<'a>>,
+        args: Vec<Expr<'a>>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt<'a> {
+    pub kind: StmtKind<'a>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind<'a> {
+    Expression(Expr<'a>),
+    Print(Expr<'a>),
+    Var {
+        name: Ident<'a>,
+        init: Option<Expr<'a>>,
+    },
+    Fun(FunDecl<'a>),
+    Block(Vec<Stmt<'a>>),
+    If {
+        cond: Expr<'a>,
+        then_branch: Box<Stmt<'a>>,
+        else_branch: Option<Box<Stmt<'a>>>,
+    },
+    While {
+        cond: Expr<'a>,
+        body: Box<Stmt<'a>>,
+    },
+    For {
+        init: Option<Box<Stmt<'a>>>,
+        cond: Option<Expr<'a>>,
+        increment: Option<Expr<'a>>,
+        body: Box<Stmt<'a>>,
+    },
+    Return(Option<Expr<'a>>),
+    /// `test "name" { ... }`, only run by `--test`. The span of `name` covers the quotes.
+    Test {
+        name: Ident<'a>,
+        body: Vec<Stmt<'a>>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunDecl<'a> {
+    pub name: Ident<'a>,
+    pub params: Vec<Ident<'a>>,
+    pub body: Vec<Stmt<'a>>,
+}
+
+/// The statements of a whole script, in source order.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Program<'a> {
+    pub stmts: Vec<Stmt<'a>>,
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UnaryOp::Negate => "-",
+            UnaryOp::Not => "!",
+        })
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Subtract => "-",
+            BinaryOp::Multiply => "*",
+            BinaryOp::Divide => "/",
+            BinaryOp::Equal => "==",
+            BinaryOp::NotEqual => "!=",
+            BinaryOp::Less => "<",
+            BinaryOp::LessEqual => "<=",
+            BinaryOp::Greater => ">",
+            BinaryOp::GreaterEqual => ">=",
+        })
+    }
+}
+
+impl fmt::Display for LogicalOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogicalOp::And => "and",
+            LogicalOp::Or => "or",
+        })
+    }
+}
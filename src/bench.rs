@@ -0,0 +1,147 @@
+//! Timing the VM on a suite of scripts, behind `rlox bench`.
+//!
+//! Every script is compiled once, run once to warm up, then run the given number of times with
+//! its output discarded. Only `VM::run` is timed. The results can be saved as JSON and later
+//! compared with, to see what a change did to each script.
+
+use std::{
+    fs, io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{golden, json::Json, load_file, vm::VM, Options, Result};
+
+/// The times of one script.
+#[derive(Debug, Clone)]
+pub struct Measurement {
+    pub name: String,
+    pub runs: Vec<Duration>,
+    /// Instructions executed by a single run.
+    pub instructions: u64,
+}
+
+impl Measurement {
+    /// Mean time of a run, in seconds.
+    pub fn mean(&self) -> f64 {
+        self.runs.iter().map(Duration::as_secs_f64).sum::<f64>() / self.runs.len().max(1) as f64
+    }
+
+    /// Sample standard deviation of the times, in seconds.
+    pub fn stddev(&self) -> f64 {
+        if self.runs.len() < 2 {
+            return 0.0;
+        }
+        let mean = self.mean();
+        let squares: f64 = self
+            .runs
+            .iter()
+            .map(|run| (run.as_secs_f64() - mean).powi(2))
+            .sum();
+        (squares / (self.runs.len() - 1) as f64).sqrt()
+    }
+
+    pub fn instructions_per_second(&self) -> f64 {
+        self.instructions as f64 / self.mean().max(f64::MIN_POSITIVE)
+    }
+
+    fn to_json(&self) -> Json {
+        Json::object([
+            ("mean_ms", Json::from(self.mean() * 1e3)),
+            ("stddev_ms", Json::from(self.stddev() * 1e3)),
+            ("instructions", Json::from(self.instructions as f64)),
+            ("runs", Json::from(self.runs.len())),
+        ])
+    }
+}
+
+/// Runs the script at `path` `runs` times.
+pub fn measure(path: &Path, runs: usize, options: &Options) -> Result<Measurement> {
+    let code = load_file(&path.to_string_lossy(), options)?;
+    let name = path.file_stem().map_or_else(
+        || path.display().to_string(),
+        |stem| stem.to_string_lossy().into(),
+    );
+    let mut measurement = Measurement {
+        name,
+        runs: Vec::with_capacity(runs),
+        instructions: 0,
+    };
+    // the first run warms up caches and the allocator, and counts the instructions, which the
+    // timed runs don't pay for
+    for run in 0..=runs {
+        let mut vm = VM::with_config(code.clone(), options.vm);
+        vm.set_output(Box::new(io::sink()));
+        if run == 0 {
+            vm.count_instructions();
+        }
+        let start = Instant::now();
+        if let Err(error) = vm.run() {
+            eprintln!("{} failed:", path.display());
+            return Err(error);
+        }
+        let elapsed = start.elapsed();
+        if run > 0 {
+            measurement.runs.push(elapsed);
+        } else {
+            measurement.instructions = vm.instructions_executed();
+        }
+    }
+    Ok(measurement)
+}
+
+/// Benchmarks every script at `path`, a file or a directory, and prints a table of the
+/// results. They are compared with the ones saved in `baseline` if given, and saved to `save`.
+pub fn run(
+    path: &str,
+    runs: usize,
+    baseline: Option<&str>,
+    save: Option<&str>,
+    options: &Options,
+) -> Result<()> {
+    let baseline = match baseline {
+        Some(baseline) => Some(Json::parse(&fs::read_to_string(baseline)?)?),
+        None => None,
+    };
+    let mut scripts = Vec::new();
+    golden::scripts(Path::new(path), &mut scripts)?;
+
+    println!(
+        "{:<16} {:>12} {:>12} {:>14} {:>10}",
+        "script", "mean", "stddev", "instructions/s", "change"
+    );
+    let mut results = Vec::new();
+    for script in scripts.iter() {
+        let measurement = measure(script, runs, options)?;
+        let saved = baseline.as_ref().map(|baseline| {
+            baseline
+                .get(&measurement.name)
+                .and_then(|saved| saved.get("mean_ms"))
+                .and_then(Json::as_f64)
+        });
+        let change = match saved {
+            Some(Some(saved)) => {
+                format!("{:+.1}%", 100.0 * (measurement.mean() * 1e3 / saved - 1.0))
+            }
+            // the script was added since the baseline was saved
+            Some(None) => "new".to_string(),
+            None => String::new(),
+        };
+        println!(
+            "{:<16} {:>12} {:>12} {:>13.1}M {:>10}",
+            measurement.name,
+            format!("{:.3?}", Duration::from_secs_f64(measurement.mean())),
+            format!("{:.3?}", Duration::from_secs_f64(measurement.stddev())),
+            measurement.instructions_per_second() / 1e6,
+            change
+        );
+        results.push(measurement);
+    }
+
+    if let Some(save) = save {
+        let json = Json::object(results.iter().map(|m| (m.name.as_str(), m.to_json())));
+        fs::write(save, format!("{}\n", json))?;
+        println!("\nResults saved to {}", save);
+    }
+    Ok(())
+}
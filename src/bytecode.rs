@@ -1,4 +1,4 @@
-use std::{fmt, rc::Rc};
+use std::{fmt, mem, rc::Rc};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
 pub enum OpCode {
@@ -31,23 +31,137 @@ pub enum OpCode {
     Less,
     Greater,
     Equal,
+    // fused forms of `Greater`, `Less` and `Equal` followed by `Not`, see `optimizer`
+    LessEqual,
+    GreaterEqual,
+    NotEqual,
 
     True,
     False,
     Nil,
 }
+
+/// Tag bytes of the encoded instructions, see `OpCode::encode`.
+pub(crate) mod tag {
+    pub const CONSTANT: u8 = 0;
+    pub const RETURN: u8 = 1;
+    pub const PRINT: u8 = 2;
+    pub const POP: u8 = 3;
+
+    pub const DEFINE_GLOBAL: u8 = 4;
+    pub const GET_GLOBAL: u8 = 5;
+    pub const SET_GLOBAL: u8 = 6;
+
+    pub const GET_LOCAL: u8 = 7;
+    pub const SET_LOCAL: u8 = 8;
+
+    pub const JUMP_IF_FALSE: u8 = 9;
+    pub const JUMP: u8 = 10;
+
+    pub const LOOP: u8 = 11;
+
+    pub const CALL: u8 = 12;
+
+    pub const NEGATE: u8 = 13;
+    pub const NOT: u8 = 14;
+    pub const ADD: u8 = 15;
+    pub const SUBTRACT: u8 = 16;
+    pub const MULTIPLY: u8 = 17;
+    pub const DIVIDE: u8 = 18;
+
+    pub const LESS: u8 = 19;
+    pub const GREATER: u8 = 20;
+    pub const EQUAL: u8 = 21;
+
+    pub const TRUE: u8 = 22;
+    pub const FALSE: u8 = 23;
+    pub const NIL: u8 = 24;
+
+    pub const LESS_EQUAL: u8 = 25;
+    pub const GREATER_EQUAL: u8 = 26;
+    pub const NOT_EQUAL: u8 = 27;
+}
+
 #[derive(Debug, Clone)]
 pub struct Chunk {
-    code: Vec<OpCode>,
+    // instructions encoded by `OpCode::encode`
+    code: Vec<u8>,
     constants: Vec<Value>,
-    lines: Vec<usize>,
+    lines: LineTable,
+}
+
+/// Maps byte offsets of a chunk's code to source lines.
+/// Consecutive instructions on the same line share a single run.
+#[derive(Debug, Clone, Default)]
+pub struct LineTable {
+    runs: Vec<LineRun>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct LineRun {
+    // offset of the first byte on this line
+    start: u32,
+    line: u32,
+}
+
+impl LineTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that code starting at `offset` comes from `line`.
+    /// Offsets must be pushed in increasing order.
+    pub fn push(&mut self, offset: usize, line: usize) {
+        match self.runs.last() {
+            Some(run) if run.line as usize == line => {}
+            _ => self.runs.push(LineRun {
+                start: offset as u32,
+                line: line as u32,
+            }),
+        }
+    }
+
+    /// Line of the byte at `offset`, in O(log n) of the number of runs.
+    /// Empty code has no lines, it is reported as line 0.
+    pub fn get(&self, offset: usize) -> usize {
+        let run = self
+            .runs
+            .partition_point(|run| run.start as usize <= offset);
+        run.checked_sub(1)
+            .map_or(0, |run| self.runs[run].line as usize)
+    }
+
+    /// Iterates over `(start offset, line)` of every run.
+    pub fn runs(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.runs
+            .iter()
+            .map(|run| (run.start as usize, run.line as usize))
+    }
+
+    fn heap_size(&self) -> usize {
+        self.runs.capacity() * mem::size_of::<LineRun>()
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct FunctionObj {
     name: String,
     arity: u8,
+    // the line it is declared on, 0 for the main function
+    line: usize,
     chunk: Chunk,
+    // names of the global slots used by the whole program, only set on the main function
+    globals: Vec<String>,
+    locals: Vec<LocalVar>,
+}
+
+/// Debug info of a named local, which lives in `slot` while the code in `start..end` runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalVar {
+    pub name: String,
+    pub slot: u16,
+    pub start: usize,
+    pub end: usize,
 }
 
 pub enum FunctionKind {
@@ -64,29 +178,39 @@ impl fmt::Display for FunctionObj {
 impl FunctionObj {
     const MAIN_FUNC_NAME: &'static str = "<Main>";
     pub fn new(name: String, arity: u8) -> Self {
+        Self::with_chunk(name, arity, Chunk::new())
+    }
+
+    pub fn with_chunk(name: String, arity: u8, chunk: Chunk) -> Self {
         Self {
             name,
             arity,
-            chunk: Chunk::new(),
+            line: 0,
+            chunk,
+            globals: Vec::new(),
+            locals: Vec::new(),
         }
     }
 
-    pub fn with_chunk(name: String, arity: u8, chunk: Chunk) -> Self {
-        Self { name, arity, chunk }
-    }
-
     pub fn new_main() -> Self {
-        Self {
-            name: Self::MAIN_FUNC_NAME.to_string(),
-            arity: 0,
-            chunk: Chunk::new(),
-        }
+        Self::new(Self::MAIN_FUNC_NAME.to_string(), 0)
     }
 
     pub fn is_main(&self) -> bool {
         self.name == Self::MAIN_FUNC_NAME
     }
 
+    /// The function a `test "name" { ... }` block compiles to. Its name can't be the name of
+    /// any other function, so tests are told apart by it.
+    pub fn new_test(name: &str) -> Self {
+        Self::new(format!("test \"{}\"", name), 0)
+    }
+
+    /// The name given to the test, if this function is a test block.
+    pub fn test_name(&self) -> Option<&str> {
+        self.name.strip_prefix("test \"")?.strip_suffix('"')
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -99,6 +223,15 @@ impl FunctionObj {
         &mut self.arity
     }
 
+    /// The line the function is declared on, 0 for the main function.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn set_line(&mut self, line: usize) {
+        self.line = line;
+    }
+
     pub fn chunk(&self) -> &Chunk {
         &self.chunk
     }
@@ -107,9 +240,62 @@ impl FunctionObj {
         &mut self.chunk
     }
 
+    /// Names of the global variables, indexed by the slot operand of the global instructions.
+    pub fn global_names(&self) -> &[String] {
+        &self.globals
+    }
+
+    pub fn set_global_names(&mut self, names: Vec<String>) {
+        self.globals = names;
+    }
+
+    /// Names of the local slots, for debuggers.
+    pub fn locals(&self) -> &[LocalVar] {
+        &self.locals
+    }
+
+    pub fn locals_mut(&mut self) -> &mut Vec<LocalVar> {
+        &mut self.locals
+    }
+
+    /// The locals in scope at the instruction at `offset`, by slot.
+    pub fn locals_at(&self, offset: usize) -> Vec<&LocalVar> {
+        let mut live: Vec<_> = self
+            .locals
+            .iter()
+            .filter(|local| (local.start..local.end).contains(&offset))
+            .collect();
+        live.sort_by_key(|local| local.slot);
+        live
+    }
+
     pub fn disassemble(&self) {
         self.chunk.disassemble(&self.name);
     }
+
+    /// Bytes owned by this function, excluding heap objects referenced from its constants.
+    pub fn heap_size(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.name.capacity()
+            + self.chunk.heap_size()
+            + self.globals.capacity() * mem::size_of::<String>()
+            + self.globals.iter().map(String::capacity).sum::<usize>()
+            + self.locals.capacity() * mem::size_of::<LocalVar>()
+            + self
+                .locals
+                .iter()
+                .map(|local| local.name.capacity())
+                .sum::<usize>()
+    }
+}
+
+/// A function implemented in Rust, called by scripts like their own functions.
+#[derive(Debug, Clone, Copy)]
+pub struct NativeFn {
+    pub name: &'static str,
+    pub arity: u8,
+    /// Computes the result from the arguments, or fails with the message of a runtime error.
+    pub fun: fn(&[Value]) -> std::result::Result<Value, String>,
 }
 
 #[derive(Debug)]
@@ -117,6 +303,7 @@ pub enum Value {
     Number(f64),
     String(Rc<String>),
     Function(Rc<FunctionObj>),
+    Native(NativeFn),
     Boolean(bool),
     Nil,
 }
@@ -129,6 +316,16 @@ impl Value {
             _ => true,
         }
     }
+
+    /// Bytes owned by the heap object behind this value, zero for inline values.
+    /// Objects referenced by a function's constants are not included.
+    pub fn heap_size(&self) -> usize {
+        match self {
+            Value::String(s) => mem::size_of::<String>() + s.capacity(),
+            Value::Function(f) => f.heap_size(),
+            Value::Number(_) | Value::Native(_) | Value::Boolean(_) | Value::Nil => 0,
+        }
+    }
 }
 
 impl fmt::Display for Value {
@@ -139,6 +336,7 @@ impl fmt::Display for Value {
             Value::Boolean(b) => write!(f, "{}", b),
             Value::Nil => write!(f, "<Nil>"),
             Value::Function(fun) => write!(f, "{}", fun),
+            Value::Native(native) => write!(f, "<native fn {}>", native.name),
         }
     }
 }
@@ -151,6 +349,7 @@ impl PartialEq for Value {
             (Value::String(a), Value::String(b)) => a == b,
             (Value::Nil, Value::Nil) => true,
             (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => a.name == b.name,
             _ => false,
         }
     }
@@ -162,6 +361,7 @@ impl Clone for Value {
             Self::Number(n) => Self::Number(*n),
             Self::String(s) => Self::String(Rc::clone(s)),
             Self::Function(f) => Self::Function(Rc::clone(f)),
+            Self::Native(native) => Self::Native(*native),
             Self::Boolean(b) => Self::Boolean(*b),
             Self::Nil => Self::Nil,
         }
@@ -206,12 +406,20 @@ impl Chunk {
         Chunk {
             code: Vec::new(),
             constants: Vec::new(),
-            lines: Vec::new(),
+            lines: LineTable::new(),
         }
     }
-    pub fn write_ins(&mut self, byte: OpCode, line: usize) {
-        self.lines.push(line);
-        self.code.push(byte);
+    /// A chunk from already encoded parts, as read back from a precompiled file.
+    pub fn from_parts(code: Vec<u8>, constants: Vec<Value>, lines: LineTable) -> Chunk {
+        Chunk {
+            code,
+            constants,
+            lines,
+        }
+    }
+    pub fn write_ins(&mut self, ins: OpCode, line: usize) {
+        self.lines.push(self.code.len(), line);
+        ins.encode(&mut self.code);
     }
     pub fn add_const(&mut self, value: Value) -> u16 {
         self.constants.push(value);
@@ -225,33 +433,75 @@ impl Chunk {
         &self.constants[index as usize]
     }
 
-    pub fn get_line(&self, index: usize) -> usize {
-        self.lines[index]
+    pub fn get_line(&self, offset: usize) -> usize {
+        self.lines.get(offset)
     }
 
+    /// Length of the encoded code in bytes.
     pub fn len(&self) -> usize {
         self.code.len()
     }
 
-    pub fn code(&self) -> &[OpCode] {
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    pub fn code(&self) -> &[u8] {
         &self.code
     }
 
-    pub fn code_mut(&mut self) -> &mut Vec<OpCode> {
-        &mut self.code
+    /// Decodes the instruction starting at byte `offset`, along with its encoded length.
+    #[inline]
+    pub fn decode_ins(&self, offset: usize) -> Option<(OpCode, usize)> {
+        OpCode::decode(&self.code, offset)
+    }
+
+    /// Iterates over the decoded instructions and their byte offsets.
+    pub fn instructions(&self) -> impl Iterator<Item = (usize, OpCode)> + '_ {
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            let (ins, len) = self.decode_ins(offset)?;
+            offset += len;
+            Some((offset - len, ins))
+        })
+    }
+
+    /// Fills in the offset of the jump instruction at byte `offset`.
+    pub fn patch_jump(&mut self, offset: usize, jump: u16) {
+        match self.code[offset] {
+            tag::JUMP_IF_FALSE | tag::JUMP => self.code[offset + 1..offset + OpCode::JUMP_SIZE]
+                .copy_from_slice(&jump.to_le_bytes()),
+            _ => unreachable!("Internal error: Tried to patch non jump insruction"),
+        }
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    pub fn lines(&self) -> &LineTable {
+        &self.lines
+    }
+
+    /// Bytes owned by the chunk's buffers, excluding heap objects referenced from constants.
+    pub fn heap_size(&self) -> usize {
+        self.code.capacity()
+            + self.constants.capacity() * mem::size_of::<Value>()
+            + self.lines.heap_size()
     }
 
     pub fn dissassemble_ins(&self, offset: usize) -> String {
-        let prefix = if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
+        let line = self.get_line(offset);
+        let prefix = if offset > 0 && line == self.get_line(offset - 1) {
             "   |".to_string()
         } else {
-            format!("{:04}", self.lines[offset])
+            format!("{:04}", line)
+        };
+        let ins = match self.decode_ins(offset) {
+            Some((ins, _)) => ins.dissassemble(self),
+            None => format!("<invalid byte {:#04x}>", self.code[offset]),
         };
-        format!(
-            "l{prefix}  #{:04} {}",
-            offset,
-            self.code[offset].dissassemble(self)
-        )
+        format!("l{prefix}  #{:04} {}", offset, ins)
     }
 
     pub fn disassemble(&self, name: &str) {
@@ -267,14 +517,225 @@ impl Default for Chunk {
 
 impl fmt::Display for Chunk {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for (offset, _) in self.code.iter().enumerate() {
+        for (offset, _) in self.instructions() {
             writeln!(f, "{}", self.dissassemble_ins(offset))?;
         }
         Ok(())
     }
 }
 
+// Operands that index constants or locals are LEB128 varints, so the common small indices
+// take a single byte. Jumps use a fixed two byte little-endian offset, so they can be patched.
+fn write_varint(code: &mut Vec<u8>, mut value: u16) {
+    while value >= 0x80 {
+        code.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    code.push(value as u8);
+}
+
+#[inline(always)]
+pub(crate) fn read_varint(code: &[u8], offset: usize) -> Option<(u16, usize)> {
+    let first = *code.get(offset)?;
+    if first < 0x80 {
+        return Some((first as u16, 1));
+    }
+    let mut value = 0u32;
+    for (i, &byte) in code.get(offset..)?.iter().take(3).enumerate() {
+        value |= ((byte & 0x7f) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((u16::try_from(value).ok()?, i + 1));
+        }
+    }
+    None
+}
+
 impl OpCode {
+    /// Encoded length of `Jump`, `JumpIfFalse` and `Loop`.
+    pub const JUMP_SIZE: usize = 3;
+    /// Encoded length of `Call`.
+    pub const CALL_SIZE: usize = 2;
+    /// Largest offset a jump can encode, `u16::MAX` marks an unpatched jump.
+    pub const MAX_JUMP: usize = u16::MAX as usize - 1;
+    const UNPATCHED_JUMP: u16 = u16::MAX;
+
+    /// Appends the byte encoding of the instruction to `code`.
+    pub fn encode(&self, code: &mut Vec<u8>) {
+        let jump = |code: &mut Vec<u8>, tag, offset: u16| {
+            code.push(tag);
+            code.extend_from_slice(&offset.to_le_bytes());
+        };
+        let indexed = |code: &mut Vec<u8>, tag, index| {
+            code.push(tag);
+            write_varint(code, index);
+        };
+
+        match *self {
+            OpCode::Constant(index) => indexed(code, tag::CONSTANT, index),
+            OpCode::Return => code.push(tag::RETURN),
+            OpCode::Print => code.push(tag::PRINT),
+            OpCode::Pop => code.push(tag::POP),
+
+            OpCode::DefineGlobal(index) => indexed(code, tag::DEFINE_GLOBAL, index),
+            OpCode::GetGlobal(index) => indexed(code, tag::GET_GLOBAL, index),
+            OpCode::SetGlobal(index) => indexed(code, tag::SET_GLOBAL, index),
+
+            OpCode::GetLocal(index) => indexed(code, tag::GET_LOCAL, index),
+            OpCode::SetLocal(index) => indexed(code, tag::SET_LOCAL, index),
+
+            OpCode::JumpIfFalse(offset) => jump(
+                code,
+                tag::JUMP_IF_FALSE,
+                offset.unwrap_or(Self::UNPATCHED_JUMP),
+            ),
+            OpCode::Jump(offset) => jump(code, tag::JUMP, offset.unwrap_or(Self::UNPATCHED_JUMP)),
+
+            OpCode::Loop(offset) => jump(code, tag::LOOP, offset),
+
+            OpCode::Call(arg_count) => code.extend_from_slice(&[tag::CALL, arg_count]),
+
+            OpCode::Negate => code.push(tag::NEGATE),
+            OpCode::Not => code.push(tag::NOT),
+            OpCode::Add => code.push(tag::ADD),
+            OpCode::Subtract => code.push(tag::SUBTRACT),
+            OpCode::Multiply => code.push(tag::MULTIPLY),
+            OpCode::Divide => code.push(tag::DIVIDE),
+
+            OpCode::Less => code.push(tag::LESS),
+            OpCode::Greater => code.push(tag::GREATER),
+            OpCode::Equal => code.push(tag::EQUAL),
+            OpCode::LessEqual => code.push(tag::LESS_EQUAL),
+            OpCode::GreaterEqual => code.push(tag::GREATER_EQUAL),
+            OpCode::NotEqual => code.push(tag::NOT_EQUAL),
+
+            OpCode::True => code.push(tag::TRUE),
+            OpCode::False => code.push(tag::FALSE),
+            OpCode::Nil => code.push(tag::NIL),
+        }
+    }
+
+    /// Decodes the instruction at byte `offset` of `code`, returning it with its encoded length.
+    /// Returns `None` if the bytes there are not a valid instruction.
+    #[inline]
+    pub fn decode(code: &[u8], offset: usize) -> Option<(OpCode, usize)> {
+        let operand = offset + 1;
+        Some(match *code.get(offset)? {
+            tag::CONSTANT => Self::decode_indexed(code, operand, OpCode::Constant)?,
+            tag::RETURN => (OpCode::Return, 1),
+            tag::PRINT => (OpCode::Print, 1),
+            tag::POP => (OpCode::Pop, 1),
+
+            tag::DEFINE_GLOBAL => Self::decode_indexed(code, operand, OpCode::DefineGlobal)?,
+            tag::GET_GLOBAL => Self::decode_indexed(code, operand, OpCode::GetGlobal)?,
+            tag::SET_GLOBAL => Self::decode_indexed(code, operand, OpCode::SetGlobal)?,
+
+            tag::GET_LOCAL => Self::decode_indexed(code, operand, OpCode::GetLocal)?,
+            tag::SET_LOCAL => Self::decode_indexed(code, operand, OpCode::SetLocal)?,
+
+            tag::JUMP_IF_FALSE => {
+                let jump = Self::decode_jump(code, operand)?;
+                (OpCode::JumpIfFalse(Self::patched(jump)), Self::JUMP_SIZE)
+            }
+            tag::JUMP => {
+                let jump = Self::decode_jump(code, operand)?;
+                (OpCode::Jump(Self::patched(jump)), Self::JUMP_SIZE)
+            }
+
+            tag::LOOP => (
+                OpCode::Loop(Self::decode_jump(code, operand)?),
+                Self::JUMP_SIZE,
+            ),
+
+            tag::CALL => (OpCode::Call(*code.get(operand)?), 2),
+
+            tag::NEGATE => (OpCode::Negate, 1),
+            tag::NOT => (OpCode::Not, 1),
+            tag::ADD => (OpCode::Add, 1),
+            tag::SUBTRACT => (OpCode::Subtract, 1),
+            tag::MULTIPLY => (OpCode::Multiply, 1),
+            tag::DIVIDE => (OpCode::Divide, 1),
+
+            tag::LESS => (OpCode::Less, 1),
+            tag::GREATER => (OpCode::Greater, 1),
+            tag::EQUAL => (OpCode::Equal, 1),
+            tag::LESS_EQUAL => (OpCode::LessEqual, 1),
+            tag::GREATER_EQUAL => (OpCode::GreaterEqual, 1),
+            tag::NOT_EQUAL => (OpCode::NotEqual, 1),
+
+            tag::TRUE => (OpCode::True, 1),
+            tag::FALSE => (OpCode::False, 1),
+            tag::NIL => (OpCode::Nil, 1),
+
+            _ => return None,
+        })
+    }
+
+    #[inline(always)]
+    fn decode_indexed(
+        code: &[u8],
+        offset: usize,
+        op: fn(u16) -> OpCode,
+    ) -> Option<(OpCode, usize)> {
+        let (index, len) = read_varint(code, offset)?;
+        Some((op(index), len + 1))
+    }
+
+    #[inline(always)]
+    fn decode_jump(code: &[u8], offset: usize) -> Option<u16> {
+        match code.get(offset..offset + 2)? {
+            &[lo, hi] => Some(u16::from_le_bytes([lo, hi])),
+            _ => None,
+        }
+    }
+
+    fn patched(jump: u16) -> Option<u16> {
+        (jump != Self::UNPATCHED_JUMP).then_some(jump)
+    }
+
+    /// The mnemonic of the instruction, without operands.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OpCode::Constant(_) => "OP_CONSTANT",
+            OpCode::Return => "OP_RETURN",
+            OpCode::Print => "OP_PRINT",
+            OpCode::Pop => "OP_POP",
+            OpCode::DefineGlobal(_) => "OP_DEFINE_GLOBAL",
+            OpCode::GetGlobal(_) => "OP_GET_GLOBAL",
+            OpCode::SetGlobal(_) => "OP_SET_GLOBAL",
+            OpCode::GetLocal(_) => "OP_GET_LOCAL",
+            OpCode::SetLocal(_) => "OP_SET_LOCAL",
+            OpCode::JumpIfFalse(_) => "OP_JUMP_IF_FALSE",
+            OpCode::Jump(_) => "OP_JUMP",
+            OpCode::Loop(_) => "OP_LOOP",
+            OpCode::Call(_) => "OP_CALL",
+            OpCode::Negate => "OP_NEGATE",
+            OpCode::Not => "OP_NOT",
+            OpCode::Add => "OP_ADD",
+            OpCode::Subtract => "OP_SUBTRACT",
+            OpCode::Multiply => "OP_MULTIPLY",
+            OpCode::Divide => "OP_DIVIDE",
+            OpCode::Less => "OP_LESS",
+            OpCode::Greater => "OP_GREATER",
+            OpCode::Equal => "OP_EQUAL",
+            OpCode::LessEqual => "OP_LESS_EQUAL",
+            OpCode::GreaterEqual => "OP_GREATER_EQUAL",
+            OpCode::NotEqual => "OP_NOT_EQUAL",
+            OpCode::True => "OP_TRUE",
+            OpCode::False => "OP_FALSE",
+            OpCode::Nil => "OP_NIL",
+        }
+    }
+
+    /// Byte offset the instruction at `offset` jumps to, for `Jump`, `JumpIfFalse` and `Loop`.
+    pub fn jump_target(&self, offset: usize) -> Option<usize> {
+        let end = offset + Self::JUMP_SIZE;
+        match *self {
+            OpCode::Jump(Some(jump)) | OpCode::JumpIfFalse(Some(jump)) => Some(end + jump as usize),
+            OpCode::Loop(jump) => end.checked_sub(jump as usize),
+            _ => None,
+        }
+    }
+
     pub fn dissassemble(&self, chunk: &Chunk) -> String {
         match self {
             OpCode::Constant(index) => {
@@ -284,9 +745,9 @@ impl OpCode {
             OpCode::Print => "OP_PRINT".to_string(),
             OpCode::Pop => "OP_POP".to_string(),
 
-            OpCode::DefineGlobal(index) => format!("OP_DEFINE_GLOBAL<#{:04}>", index),
-            OpCode::GetGlobal(index) => format!("OP_GET_GLOBAL<#{:04}>", index),
-            OpCode::SetGlobal(index) => format!("OP_SET_GLOBAL<#{:04}>", index),
+            OpCode::DefineGlobal(slot) => format!("OP_DEFINE_GLOBAL<g#{:04}>", slot),
+            OpCode::GetGlobal(slot) => format!("OP_GET_GLOBAL<g#{:04}>", slot),
+            OpCode::SetGlobal(slot) => format!("OP_SET_GLOBAL<g#{:04}>", slot),
 
             OpCode::GetLocal(index) => format!("OP_GET_LOCAL<s#{:04}>", index),
             OpCode::SetLocal(index) => format!("OP_SET_LOCAL<s#{:04}>", index),
@@ -308,6 +769,9 @@ impl OpCode {
             OpCode::Greater => "OP_GREATER".to_string(),
             OpCode::Less => "OP_LESS".to_string(),
             OpCode::Equal => "OP_EQUAL".to_string(),
+            OpCode::LessEqual => "OP_LESS_EQUAL".to_string(),
+            OpCode::GreaterEqual => "OP_GREATER_EQUAL".to_string(),
+            OpCode::NotEqual => "OP_NOT_EQUAL".to_string(),
 
             OpCode::True => "OP_TRUE".to_string(),
             OpCode::False => "OP_FALSE".to_string(),
@@ -1,517 +1,480 @@
-use std::{cell::RefCell, mem, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, mem, rc::Rc};
 
 use crate::{
-    bytecode::{self, FunctionObj, OpCode, Precedence, Value},
-    scanner::Scanner,
-    token::{self, Token, TokenKind},
+    ast::{
+        BinaryOp, Expr, ExprKind, FunDecl, Ident, Literal, LogicalOp, Program, Stmt, StmtKind,
+        UnaryOp,
+    },
+    bytecode::{self, FunctionObj, LocalVar, OpCode, Value},
+    diagnostic::Diagnostic,
+    optimizer::{self, OptLevel},
+    vm::VmConfig,
     Error, Result,
 };
 
-/*
-statement      → exprStmt
-               | forStmt
-               | ifStmt
-               | printStmt
-               | returnStmt
-               | whileStmt
-               | block ;
-
-declaration    → classDecl
-               | funDecl
-               | varDecl
-               | statement ;
-
-block          → "{" declaration* "}" ;
-
-*/
-
 struct Local<'a> {
     name: &'a str,
     depth: Option<u32>,
+    // offset of the first instruction that runs with the local initialized
+    start: usize,
 }
 
 impl<'a> Local<'a> {
     fn new(name: &'a str, depth: Option<u32>) -> Self {
-        Self { name, depth }
-    }
-}
-
-pub struct Parser<'a> {
-    scanner: Scanner<'a>,
-    current: Token<'a>,
-    previous: Token<'a>,
-}
-
-impl<'a> Parser<'a> {
-    fn new(scanner: Scanner<'a>, current: Token<'a>, previous: Token<'a>) -> Self {
         Self {
-            scanner,
-            current,
-            previous,
+            name,
+            depth,
+            start: 0,
         }
     }
+}
 
-    pub fn with_source(source: &'a str) -> Self {
-        let scanner = Scanner::new(source);
-        Self::new(scanner, Token::none(), Token::none())
-    }
-
-    fn current(&self) -> &Token<'a> {
-        &self.current
-    }
+// Global slots are shared by the main compiler and every nested function compiler,
+// so a global keeps the same slot no matter which function refers to it.
+#[derive(Default)]
+struct GlobalSlots {
+    names: Vec<String>,
+    slots: HashMap<String, u16>,
+}
 
-    fn previous(&self) -> &Token<'a> {
-        &self.previous
-    }
+impl GlobalSlots {
+    const MAX_GLOBALS: usize = u16::MAX as usize + 1;
 
-    fn update_tokens(&mut self, new: Token<'a>) {
-        self.previous = mem::replace(&mut self.current, new);
+    fn resolve(&mut self, name: &str) -> Option<u16> {
+        if let Some(&slot) = self.slots.get(name) {
+            return Some(slot);
+        }
+        if self.names.len() == Self::MAX_GLOBALS {
+            return None;
+        }
+        let slot = self.names.len() as u16;
+        self.names.push(name.to_string());
+        self.slots.insert(name.to_string(), slot);
+        Some(slot)
     }
 }
+
+/// Generates bytecode from the syntax tree built by [`crate::parser::Parser`].
 pub struct Compiler<'a> {
-    parser: &'a RefCell<Parser<'a>>,
-    error_count: usize,
+    // errors of this function and the functions nested in it, in source order
+    diagnostics: Vec<Diagnostic>,
 
     fun: FunctionObj,
+    globals: Rc<RefCell<GlobalSlots>>,
 
     locals: Vec<Local<'a>>,
+    max_locals: usize,
     scope_depth: u32,
+
+    opt_level: OptLevel,
 }
 
 impl<'a> Compiler<'a> {
-    // pub fn with_source(source: &'a str) -> Self {
-    //     Self::new(Rc::new(Scanner::new(source)), FunctionObj::new_main())
-    // }
-
-    pub fn new(parser: &'a RefCell<Parser<'a>>, fun: FunctionObj) -> Self {
-        let mut locals = Vec::with_capacity(256);
-        locals.push(Local::new("", Some(0)));
+    pub fn new(fun: FunctionObj) -> Self {
+        let locals = vec![Local::new("", Some(0))];
         Self {
-            parser,
-            error_count: 0,
+            diagnostics: Vec::new(),
             fun,
+            globals: Rc::default(),
             locals,
+            max_locals: VmConfig::default().max_locals,
             scope_depth: 0,
+            opt_level: OptLevel::default(),
         }
     }
 
-    pub fn main_compiler(parser: &'a RefCell<Parser<'a>>) -> Self {
-        Self::new(parser, FunctionObj::new_main())
+    pub fn main_compiler() -> Self {
+        Self::new(FunctionObj::new_main())
     }
 
-    fn scan_token(&mut self) -> Result<Token<'a>> {
-        self.parser.borrow_mut().scanner.scan_token()
+    /// Sets the maximum number of local slots per function, clamped to what a `u16` operand can address.
+    pub fn with_max_locals(mut self, max_locals: usize) -> Self {
+        self.max_locals = max_locals.min(VmConfig::LOCALS_LIMIT);
+        self
     }
 
-    fn current_kind(&self) -> TokenKind<'a> {
-        self.parser.borrow().current().kind()
+    /// Sets how much the optimizer rewrites each compiled function.
+    pub fn with_opt_level(mut self, opt_level: OptLevel) -> Self {
+        self.opt_level = opt_level;
+        self
     }
 
-    fn previous_kind(&self) -> TokenKind<'a> {
-        self.parser.borrow().previous().kind()
+    // A compiler for a function nested in this one, sharing its settings and global slots.
+    fn function_compiler(&self, fun: FunctionObj) -> Compiler<'a> {
+        let mut compiler = Compiler::new(fun)
+            .with_max_locals(self.max_locals)
+            .with_opt_level(self.opt_level);
+        compiler.globals = Rc::clone(&self.globals);
+        compiler
     }
 
     fn curr_chunk(&mut self) -> &mut bytecode::Chunk {
         self.fun.chunk_mut()
     }
 
-    fn emit_ins(&mut self, ins: bytecode::OpCode) {
-        let line = self.parser.borrow().previous().line();
+    fn emit_ins(&mut self, ins: OpCode, line: usize) {
         self.curr_chunk().write_ins(ins, line);
     }
 
-    fn add_const(&mut self, val: Value) -> u16 {
-        self.curr_chunk().add_const(val)
-    }
-
-    fn emit_const_ins(&mut self, value: Value) {
-        let line = self.parser.borrow().previous().line();
+    fn emit_const_ins(&mut self, value: Value, line: usize) {
         self.curr_chunk().add_const_ins(value, line);
     }
 
-    pub fn compile(mut self) -> Result<FunctionObj> {
-        self.advance()?;
-        while !self.is_at_end() {
-            self.declaration();
+    pub fn compile(mut self, program: &Program<'a>) -> Result<FunctionObj> {
+        self.statements(&program.stmts);
+        for diagnostic in self.diagnostics.iter() {
+            eprintln!("Compile error: {}", diagnostic);
         }
-        if self.error_count != 0 {
+        if !self.diagnostics.is_empty() {
             return Err(Error::from(format!(
                 "\nAborting compilation due to {} errors",
-                self.error_count
+                self.diagnostics.len()
             )));
         }
 
+        let names = mem::take(&mut self.globals.borrow_mut().names);
+        self.fun.set_global_names(names);
+        optimizer::optimize(&mut self.fun, self.opt_level);
+
         #[cfg(feature = "print_code")]
         self.fun.disassemble();
 
         Ok(self.fun)
     }
 
-    fn synchronize(&mut self) {
-        let curr = loop {
-            if let Ok(token) = self.scan_token() {
-                match token.kind() {
-                    TokenKind::Semicolon => match self.scan_token() {
-                        Ok(next) => {
-                            self.parser.borrow_mut().previous = token;
-                            break next;
-                        }
-
-                        Err(error) => {
-                            self.report_error(error);
-                            continue;
-                        }
-                    },
-                    TokenKind::Eof
-                    | TokenKind::Class
-                    | TokenKind::Fun
-                    | TokenKind::Var
-                    | TokenKind::For
-                    | TokenKind::If
-                    | TokenKind::While
-                    | TokenKind::Print
-                    | TokenKind::Return => break token,
-                    _ => {}
-                }
-            };
-        };
-        self.parser.borrow_mut().update_tokens(curr);
-    }
-
-    fn write_ident_constant(&mut self, ident: &'a str) -> u16 {
-        let ident = Value::String(Rc::new(ident.to_string()));
-        self.add_const(ident)
+    /// The errors compiling `program` would report, without generating its code.
+    pub fn check(mut self, program: &Program<'a>) -> Vec<Diagnostic> {
+        self.statements(&program.stmts);
+        self.diagnostics
     }
 
-    fn declaration(&mut self) -> bool {
-        // true means it was successful
-        let result = match self.current_kind() {
-            TokenKind::Var => self.var_decl(),
-            TokenKind::Fun => self.fun_decl(),
-            _ => self.statement(),
-        };
-        if let Err(error) = result {
-            self.synchronize();
-            self.report_error(error);
-            return false;
+    // Compiles a sequence of statements, an error in one of them doesn't stop the others.
+    fn statements(&mut self, stmts: &[Stmt<'a>]) {
+        for stmt in stmts {
+            if let Err(error) = self.statement(stmt) {
+                self.report_error(error);
+            }
         }
-        true
     }
 
-    fn fun_decl(&mut self) -> Result<()> {
-        self.advance()?;
-
-        let (id, name) = self.declare_variable()?;
-        let name = name.to_string();
-
-        self.mark_initialized();
-
-        let fun_compiler = Compiler::new(self.parser, FunctionObj::new(name, 0));
-
-        let fun = fun_compiler.compile_fun()?;
-
-        self.emit_const_ins(Value::Function(Rc::new(fun)));
-        self.define_variable(id);
-        Ok(())
+    fn global_slot(&mut self, ident: &Ident<'a>) -> Result<u16> {
+        let slot = self.globals.borrow_mut().resolve(ident.name);
+        slot.ok_or_else(|| self.error_at(ident, "Too many global variables."))
     }
 
-    // fn func_scope(&mut self, name: String) -> Result<()> {
-    //     let old_scope = std::mem::replace(&mut self.scope_depth, 1);
-    //     let old_start = std::mem::replace(
-    //         &mut self.fun_local_start,
-    //         self.locals.len().saturating_sub(1),
-    //     );
-    //     let new_func = FunctionObj::new(name, 0);
-
-    //     let mut func = std::mem::replace(&mut self.curr_func, Some(new_func));
-
-    //     let result = self.parse_fun();
-
-    //     self.end_scope();
-
-    //     self.scope_depth = old_scope;
-    //     self.fun_local_start = old_start;
-    //     std::mem::swap(&mut self.curr_func, &mut func);
-
-    //     let func = Rc::new(func.unwrap());
-    //     self.emit_const_ins(Value::Function(func));
-
-    //     result
-    // }
-
-    fn parse_fun(&mut self) -> Result<()> {
-        self.scope_depth += 1;
-        self.consume(TokenKind::LeftParen, "Expect '(' after function name.")?;
-        if !self.check_curr(TokenKind::RightParen) {
-            loop {
-                if self.fun.arity() == u8::MAX {
-                    self.error_at_current("Cannot have more than 255 parameters.");
-                }
-                *self.fun.arity_mut() += 1;
-                let (id, _) = self.declare_variable()?;
-                self.define_variable(id);
-                if !self.match_curr(TokenKind::Comma)? {
-                    break;
+    fn statement(&mut self, stmt: &Stmt<'a>) -> Result<()> {
+        // most instructions of a statement are attributed to the line it ends on
+        let line = stmt.span.end_line;
+        match &stmt.kind {
+            StmtKind::Expression(expr) => {
+                self.expression(expr)?;
+                self.emit_ins(OpCode::Pop, line);
+            }
+            StmtKind::Print(expr) => {
+                self.expression(expr)?;
+                self.emit_ins(OpCode::Print, line);
+            }
+            StmtKind::Var { name, init } => {
+                let id = self.declare_variable(name)?;
+                let init = match init {
+                    Some(init) => self.expression(init),
+                    None => {
+                        self.emit_ins(OpCode::Nil, name.span.line);
+                        Ok(())
+                    }
+                };
+                // defined even when its initializer failed, so that its uses report nothing more
+                self.define_variable(id, line);
+                init?;
+            }
+            StmtKind::Fun(decl) => self.fun_decl(decl, line)?,
+            StmtKind::Block(stmts) => {
+                self.scope_depth += 1;
+                self.statements(stmts);
+                self.end_scope(last_line(stmts).unwrap_or(stmt.span.line));
+            }
+            StmtKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => self.if_stmt(cond, then_branch, else_branch.as_deref())?,
+            StmtKind::While { cond, body } => self.while_stmt(cond, body)?,
+            StmtKind::For {
+                init,
+                cond,
+                increment,
+                body,
+            } => {
+                self.scope_depth += 1;
+                let result =
+                    self.for_stmt(init.as_deref(), cond.as_ref(), increment.as_ref(), body);
+                self.end_scope(body.span.end_line);
+                result?;
+            }
+            StmtKind::Return(value) => {
+                match value {
+                    Some(value) => self.expression(value)?,
+                    None => self.emit_ins(OpCode::Nil, stmt.span.line),
                 }
+                self.emit_ins(OpCode::Return, line);
             }
+            StmtKind::Test { name, body } => self.test_decl(name, body, line),
         }
-        self.consume(TokenKind::RightParen, "Expect ')' after parameters")?;
-        if !self.check_curr(TokenKind::LeftBrace) {
-            return Err(self.error_at_current("Expect '{' before function body."));
-        }
-        // self.consume(TokenKind::LeftBrace, "Expect '{' before function body.")?;
-
-        self.block()?;
-
         Ok(())
     }
 
-    fn compile_fun(mut self) -> Result<FunctionObj> {
-        let result = self.parse_fun();
-
-        #[cfg(feature = "print_code")]
-        self.fun.disassemble();
+    // A test becomes a function that is only kept in the constants, no code refers to it.
+    fn test_decl(&mut self, name: &Ident<'a>, body: &[Stmt<'a>], line: usize) {
+        let mut compiler = self.function_compiler(FunctionObj::new_test(name.name));
+        compiler.function(&[], body, name.span.line, line);
+        self.diagnostics.append(&mut compiler.diagnostics);
+        self.curr_chunk()
+            .add_const(Value::Function(Rc::new(compiler.fun)));
+    }
 
-        result?;
+    fn fun_decl(&mut self, decl: &FunDecl<'a>, line: usize) -> Result<()> {
+        let id = self.declare_variable(&decl.name)?;
+        self.mark_initialized();
 
-        Ok(self.fun)
-    }
+        let mut compiler = self.function_compiler(FunctionObj::new(decl.name.name.to_string(), 0));
+        compiler.function(&decl.params, &decl.body, decl.name.span.line, line);
+        self.diagnostics.append(&mut compiler.diagnostics);
 
-    fn statement(&mut self) -> Result<()> {
-        match self.current_kind() {
-            TokenKind::Print => self.print_stmt(),
-            TokenKind::LeftBrace => self.block(),
-            TokenKind::If => self.if_stmt(),
-            TokenKind::While => self.while_stmt(),
-            TokenKind::For => self.for_stmt(),
-            TokenKind::Return => self.return_stmt(),
-            _ => self.expression_stmt(),
-        }
+        self.emit_const_ins(Value::Function(Rc::new(compiler.fun)), line);
+        self.define_variable(id, line);
+        Ok(())
     }
 
-    fn return_stmt(&mut self) -> Result<()> {
-        self.advance()?;
-        if self.check_curr(TokenKind::Semicolon) {
-            self.emit_ins(OpCode::Nil);
-            self.consume(TokenKind::Semicolon, "Expect ';' after return.")?;
-        } else {
-            if self.fun.is_main() {
-                return Err(self.error_at_current("Cannot return value from top-level code."));
+    // Compiles the parameters and body of a function declared on `start_line` into this
+    // compiler's function, `line` is where the body ends.
+    fn function(
+        &mut self,
+        params: &[Ident<'a>],
+        body: &[Stmt<'a>],
+        start_line: usize,
+        line: usize,
+    ) {
+        self.fun.set_line(start_line);
+        self.scope_depth += 1;
+        for param in params.iter() {
+            *self.fun.arity_mut() = self.fun.arity().saturating_add(1);
+            match self.declare_variable(param) {
+                Ok(_) => self.mark_initialized(),
+                Err(error) => self.report_error(error),
             }
-            self.expression()?;
-            self.consume(TokenKind::Semicolon, "Expect ';' after return value.")?;
         }
-        self.emit_ins(OpCode::Return);
-        Ok(())
-    }
 
-    fn for_stmt(&mut self) -> Result<()> {
-        self.advance()?;
         self.scope_depth += 1;
+        self.statements(body);
+        self.end_scope(last_line(body).unwrap_or(start_line));
+        // the parameters stay in scope until the frame is discarded by the return
+        let end = self.curr_chunk().len();
+        for slot in 1..self.locals.len() {
+            self.record_local(slot, end);
+        }
 
-        let result = self.parse_for();
+        // a function that runs off the end of its body returns nil
+        if !matches!(
+            body.last(),
+            Some(Stmt {
+                kind: StmtKind::Return(_),
+                ..
+            })
+        ) {
+            self.emit_ins(OpCode::Nil, line);
+            self.emit_ins(OpCode::Return, line);
+        }
+
+        if self.diagnostics.is_empty() {
+            optimizer::optimize(&mut self.fun, self.opt_level);
+        }
 
-        self.end_scope();
-        result
+        #[cfg(feature = "print_code")]
+        self.fun.disassemble();
     }
 
-    fn parse_for(&mut self) -> Result<()> {
-        self.consume(TokenKind::LeftParen, "Expect '(' after 'for'.")?;
-        match self.current_kind() {
-            TokenKind::Semicolon => {}
-            TokenKind::Var => {
-                self.var_decl()?;
-            }
-            _ => {
-                self.expression_stmt()?;
-            }
+    fn for_stmt(
+        &mut self,
+        init: Option<&Stmt<'a>>,
+        cond: Option<&Expr<'a>>,
+        increment: Option<&Expr<'a>>,
+        body: &Stmt<'a>,
+    ) -> Result<()> {
+        if let Some(init) = init {
+            self.statement(init)?;
         }
 
         let mut loop_start = self.curr_chunk().len();
         let mut exit_jump = None;
 
-        if !self.match_curr(TokenKind::Semicolon)? {
-            self.expression()?;
-            self.consume(TokenKind::Semicolon, "Expect ';' after loop condition.")?;
+        if let Some(cond) = cond {
+            self.expression(cond)?;
+            let line = cond.span.end_line;
 
             // Jump out of the loop if the condition is false.
-            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse(None)));
-            self.emit_ins(OpCode::Pop);
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse(None), line));
+            self.emit_ins(OpCode::Pop, line);
         }
 
-        if !self.match_curr(TokenKind::RightParen)? {
-            let body_jump = self.emit_jump(OpCode::Jump(None));
+        if let Some(increment) = increment {
+            let body_jump = self.emit_jump(OpCode::Jump(None), increment.span.line);
 
             let increment_start = self.curr_chunk().len();
-            self.expression()?;
-            self.emit_ins(OpCode::Pop);
-
-            self.consume(TokenKind::RightParen, "Expect ')' after for clauses.")?;
+            self.expression(increment)?;
+            let line = increment.span.end_line;
+            self.emit_ins(OpCode::Pop, line);
 
-            self.emit_loop(loop_start)?;
+            self.emit_loop(loop_start, line)?;
             loop_start = increment_start;
-            self.patch_jump(body_jump);
+            self.patch_jump(body_jump, line)?;
         }
 
-        self.statement()?;
+        self.statement(body)?;
 
-        self.emit_loop(loop_start)?;
+        let line = body.span.end_line;
+        self.emit_loop(loop_start, line)?;
 
         if let Some(exit_jump) = exit_jump {
-            self.patch_jump(exit_jump);
-            self.emit_ins(OpCode::Pop);
+            self.patch_jump(exit_jump, line)?;
+            self.emit_ins(OpCode::Pop, line);
         }
         Ok(())
     }
 
-    fn while_stmt(&mut self) -> Result<()> {
-        self.advance()?;
-
+    fn while_stmt(&mut self, cond: &Expr<'a>, body: &Stmt<'a>) -> Result<()> {
         let loop_start = self.curr_chunk().len();
 
-        self.consume(TokenKind::LeftParen, "Expect '(' after 'while'.")?;
-        self.expression()?;
-        self.consume(TokenKind::RightParen, "Expect ')' after condition.")?;
+        self.expression(cond)?;
+        let line = cond.span.end_line;
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse(None), line);
+        self.emit_ins(OpCode::Pop, line);
 
-        let exit_jump = self.emit_jump(OpCode::JumpIfFalse(None));
-        self.emit_ins(OpCode::Pop);
+        self.statement(body)?;
+        let line = body.span.end_line;
+        self.emit_loop(loop_start, line)?;
 
-        self.statement()?;
-        self.emit_loop(loop_start)?;
-
-        self.patch_jump(exit_jump);
-        self.emit_ins(OpCode::Pop);
+        self.patch_jump(exit_jump, line)?;
+        self.emit_ins(OpCode::Pop, line);
         Ok(())
     }
 
-    fn if_stmt(&mut self) -> Result<()> {
-        self.advance()?;
-
-        self.consume(TokenKind::LeftParen, "Expect '(' after 'if'.")?;
-        self.expression()?;
-        self.consume(TokenKind::RightParen, "Expect ')' after condition.")?;
+    fn if_stmt(
+        &mut self,
+        cond: &Expr<'a>,
+        then_branch: &Stmt<'a>,
+        else_branch: Option<&Stmt<'a>>,
+    ) -> Result<()> {
+        self.expression(cond)?;
+        let line = cond.span.end_line;
 
-        let then_jump = self.emit_jump(OpCode::JumpIfFalse(None));
-        self.emit_ins(OpCode::Pop);
-        self.statement()?;
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse(None), line);
+        self.emit_ins(OpCode::Pop, line);
+        self.statement(then_branch)?;
 
-        let else_jump = self.emit_jump(OpCode::Jump(None));
+        let line = then_branch.span.end_line;
+        let else_jump = self.emit_jump(OpCode::Jump(None), line);
 
-        self.patch_jump(then_jump);
-        self.emit_ins(OpCode::Pop);
+        self.patch_jump(then_jump, line)?;
+        self.emit_ins(OpCode::Pop, line);
 
-        if self.match_curr(TokenKind::Else)? {
-            self.statement()?;
+        if let Some(else_branch) = else_branch {
+            self.statement(else_branch)?;
         }
-        self.patch_jump(else_jump);
+        self.patch_jump(else_jump, line)?;
         Ok(())
     }
 
-    fn emit_loop(&mut self, loop_start: usize) -> Result<()> {
-        let offset = self.curr_chunk().len() - loop_start + 1;
+    fn emit_loop(&mut self, loop_start: usize, line: usize) -> Result<()> {
+        // the offset is taken from the end of the loop instruction itself
+        let offset = self.curr_chunk().len() + OpCode::JUMP_SIZE - loop_start;
         if offset > u16::MAX as usize {
-            return Err(self.error_at_previous("Loop body too large."));
+            return Err(self.error_at_line(line, "Loop body too large."));
         }
-        self.emit_ins(OpCode::Loop(offset as u16));
+        self.emit_ins(OpCode::Loop(offset as u16), line);
         Ok(())
     }
 
-    fn emit_jump(&mut self, ins: OpCode) -> usize {
-        self.emit_ins(ins);
-        self.curr_chunk().len() - 1
-    }
-
-    fn patch_jump(&mut self, index: usize) {
-        let jump_offset = (self.curr_chunk().len() - index - 1) as u16;
-
-        let code = self.curr_chunk().code_mut();
-        match code[index] {
-            OpCode::JumpIfFalse(None) => code[index] = OpCode::JumpIfFalse(Some(jump_offset)),
-            OpCode::Jump(None) => code[index] = OpCode::Jump(Some(jump_offset)),
-            _ => unreachable!("Internal error: Tried to patch non jump insruction"),
-        }
+    fn emit_jump(&mut self, ins: OpCode, line: usize) -> usize {
+        let offset = self.curr_chunk().len();
+        self.emit_ins(ins, line);
+        offset
     }
 
-    fn block(&mut self) -> Result<()> {
-        self.advance()?;
-        self.scope_depth += 1;
-
-        while !self.check_curr(TokenKind::RightBrace) && !self.is_at_end() {
-            if !self.declaration() {
-                self.end_scope();
-                return Ok(());
-            }
+    fn patch_jump(&mut self, offset: usize, line: usize) -> Result<()> {
+        let jump = self.curr_chunk().len() - offset - OpCode::JUMP_SIZE;
+        if jump > OpCode::MAX_JUMP {
+            return Err(self.error_at_line(line, "Too much code to jump over."));
         }
 
-        self.end_scope();
-        self.consume(TokenKind::RightBrace, "Expect '}' after block.")?;
+        self.curr_chunk().patch_jump(offset, jump as u16);
         Ok(())
     }
 
-    fn end_scope(&mut self) {
+    fn end_scope(&mut self, line: usize) {
         self.scope_depth -= 1;
         // discard all locals that are in this scope
-        while !self.locals.is_empty()
-            && self.locals.last().unwrap().depth.unwrap() > self.scope_depth
-        {
-            self.emit_ins(OpCode::Pop);
+        // a local whose initializer failed to compile has no depth yet, it belongs to this scope too
+        while let Some(local) = self.locals.last() {
+            if local.depth.is_some_and(|depth| depth <= self.scope_depth) {
+                break;
+            }
+            let end = self.curr_chunk().len();
+            self.record_local(self.locals.len() - 1, end);
+            self.emit_ins(OpCode::Pop, line);
             self.locals.pop();
         }
     }
 
-    fn var_decl(&mut self) -> Result<()> {
-        self.advance()?;
-        let (id, _) = self.declare_variable()?;
-
-        if self.match_curr(TokenKind::Equal)? {
-            self.expression()?;
-        } else {
-            self.emit_ins(OpCode::Nil);
+    // Keeps the name of the local in `slot` as debug info, it went out of scope at `end`.
+    fn record_local(&mut self, slot: usize, end: usize) {
+        let local = &self.locals[slot];
+        if local.depth.is_none() {
+            return;
         }
-
-        self.consume(
-            TokenKind::Semicolon,
-            "Expect ';' after variable declaration.",
-        )?;
-
-        self.define_variable(id);
-        Ok(())
+        let local = LocalVar {
+            name: local.name.to_string(),
+            slot: slot as u16,
+            start: local.start,
+            end,
+        };
+        self.fun.locals_mut().push(local);
     }
 
-    fn declare_local(&mut self, name: &'a str) -> Result<()> {
+    fn declare_local(&mut self, name: &Ident<'a>) -> Result<()> {
         for i in (0..self.locals.len()).rev() {
             let local = &self.locals[i];
             if local.depth.is_some() && local.depth.unwrap() < self.scope_depth {
                 break;
             }
-            if local.name == name {
-                return Err(self
-                    .error_at_previous("Variable with this name already declared in this scope."));
+            if local.name == name.name {
+                return Err(self.error_at(
+                    name,
+                    "Variable with this name already declared in this scope.",
+                ));
             }
         }
 
-        self.locals.push(Local::new(name, None));
+        if self.locals.len() >= self.max_locals {
+            return Err(self.error_at(name, "Too many local variables in function."));
+        }
+
+        self.locals.push(Local::new(name.name, None));
         Ok(())
     }
 
-    fn declare_variable(&mut self) -> Result<(u16, &str)> {
-        let msg = match self.previous_kind() {
-            TokenKind::Fun => "Expect function name.",
-            _ => "Expect variable name.",
-        };
-        let name = self.consume_ident(msg)?;
+    fn declare_variable(&mut self, name: &Ident<'a>) -> Result<u16> {
         if self.scope_depth == 0 {
             // a global
-            Ok((self.write_ident_constant(name), name))
+            self.global_slot(name)
         } else {
             self.declare_local(name)?;
-            Ok((0, name))
+            Ok(0)
         }
     }
 
-    fn define_variable(&mut self, id: u16) {
+    fn define_variable(&mut self, id: u16, line: usize) {
         if self.scope_depth == 0 {
-            self.emit_ins(OpCode::DefineGlobal(id));
+            self.emit_ins(OpCode::DefineGlobal(id), line);
         } else {
             self.mark_initialized();
         }
@@ -521,302 +484,166 @@ impl<'a> Compiler<'a> {
         if self.scope_depth == 0 {
             return;
         }
-        self.locals.last_mut().unwrap().depth = Some(self.scope_depth);
+        let start = self.curr_chunk().len();
+        let local = self.locals.last_mut().unwrap();
+        local.depth = Some(self.scope_depth);
+        local.start = start;
     }
 
-    fn variable(&mut self, ident: &'a str, can_assign: bool) -> Result<()> {
-        let (is_local, arg) = if let Some(offset) = self.resolve_local(ident) {
-            (true, offset)
-        } else {
-            (false, self.write_ident_constant(ident))
-        };
-
-        if can_assign && self.match_curr(TokenKind::Equal)? {
-            self.expression()?;
-            if is_local {
-                self.emit_ins(OpCode::SetLocal(arg));
-            } else {
-                self.emit_ins(OpCode::SetGlobal(arg));
-            }
-        } else if is_local {
-            self.emit_ins(OpCode::GetLocal(arg));
-        } else {
-            self.emit_ins(OpCode::GetGlobal(arg));
+    // The slot of a variable and whether it is a local.
+    fn resolve_variable(&mut self, name: &Ident<'a>) -> Result<(bool, u16)> {
+        match self.resolve_local(name)? {
+            Some(offset) => Ok((true, offset)),
+            None => Ok((false, self.global_slot(name)?)),
         }
-
-        Ok(())
     }
 
-    fn resolve_local(&mut self, name: &'a str) -> Option<u16> {
+    fn resolve_local(&self, name: &Ident<'a>) -> Result<Option<u16>> {
         for (i, local) in self.locals.iter().rev().enumerate() {
-            if local.name == name {
+            if local.name == name.name {
                 if local.depth.is_none() {
-                    self.error_at_previous("Cannot read local variable in its own initializer.");
+                    return Err(
+                        self.error_at(name, "Cannot read local variable in its own initializer.")
+                    );
                 }
-                return Some((self.locals.len() - 1 - i) as u16);
+                return Ok(Some((self.locals.len() - 1 - i) as u16));
             }
         }
 
-        None
-    }
-
-    fn print_stmt(&mut self) -> Result<()> {
-        self.advance()?;
-        self.expression()?;
-        self.consume(TokenKind::Semicolon, "Expect ';' after value.")?;
-        self.emit_ins(OpCode::Print);
-        Ok(())
-    }
-
-    fn expression_stmt(&mut self) -> Result<()> {
-        self.expression()?;
-        self.consume(TokenKind::Semicolon, "Expect ';' after expression.")?;
-        self.emit_ins(OpCode::Pop);
-        Ok(())
+        Ok(None)
     }
 
-    fn is_at_end(&self) -> bool {
-        self.check_curr(TokenKind::Eof)
-    }
-
-    fn match_curr(&mut self, kind: TokenKind) -> Result<bool> {
-        if self.check_curr(kind) {
-            self.advance()?;
-            Ok(true)
-        } else {
-            Ok(false)
-        }
-    }
-
-    fn check_curr(&self, kind: TokenKind) -> bool {
-        self.current_kind() == kind
-    }
-
-    fn advance(&mut self) -> Result<()> {
-        let token = self.scan_token();
-        match token {
-            Ok(token) => {
-                self.parser.borrow_mut().update_tokens(token);
-                Ok(())
+    fn expression(&mut self, expr: &Expr<'a>) -> Result<()> {
+        // an operation runs once its last operand has been evaluated
+        let line = expr.span.end_line;
+        match &expr.kind {
+            ExprKind::Literal(literal) => match *literal {
+                Literal::Number(val) => self.emit_const_ins(Value::Number(val), line),
+                Literal::String(s) => {
+                    self.emit_const_ins(Value::String(Rc::new(s.to_string())), line)
+                }
+                Literal::True => self.emit_ins(OpCode::True, line),
+                Literal::False => self.emit_ins(OpCode::False, line),
+                Literal::Nil => self.emit_ins(OpCode::Nil, line),
+            },
+            ExprKind::Variable(name) => {
+                let (is_local, arg) = self.resolve_variable(name)?;
+                let ins = if is_local {
+                    OpCode::GetLocal(arg)
+                } else {
+                    OpCode::GetGlobal(arg)
+                };
+                self.emit_ins(ins, line);
             }
-            Err(error) => Err(error),
-        }
-    }
-
-    fn report_error(&mut self, error: Error) {
-        self.error_count += 1;
-        eprintln!("Parsing error: {}", error);
-    }
-
-    fn error_at(&self, token: &Token<'a>, msg: &str) -> Error {
-        Error::from(format!(
-            "{} at line {}, at token '{}'",
-            msg,
-            token.line(),
-            token.kind()
-        ))
-    }
-
-    fn error_at_previous(&mut self, msg: &str) -> Error {
-        self.error_at(self.parser.borrow().previous(), msg)
-    }
-
-    fn error_at_current(&mut self, msg: &str) -> Error {
-        self.error_at(self.parser.borrow().current(), msg)
-    }
-
-    fn consume(&mut self, expected: TokenKind, msg: &str) -> Result<()> {
-        if self.check_curr(expected) {
-            self.advance()?;
-            Ok(())
-        } else {
-            Err(self.error_at_current(msg))
-        }
-    }
-
-    fn consume_ident(&mut self, msg: &str) -> Result<&'a str> {
-        if let TokenKind::Identifier(ident) = self.current_kind() {
-            self.advance()?;
-            Ok(ident)
-        } else {
-            Err(self.error_at_current(msg))
-        }
-    }
-
-    fn expression(&mut self) -> Result<()> {
-        self.parse_precedence(Precedence::Assignment)
-    }
-
-    fn grouping(&mut self) -> Result<()> {
-        self.expression()?;
-        self.consume(TokenKind::RightParen, "Expect ')' after expression")
-    }
-
-    fn unary(&mut self) -> Result<()> {
-        let op = self.previous_kind();
-        self.parse_precedence(Precedence::Unary)?;
-        self.emit_ins(match op {
-            TokenKind::Bang => OpCode::Not,
-            TokenKind::Minus => OpCode::Negate,
-            _ => unreachable!(),
-        });
-        Ok(())
-    }
-
-    // parse any expression at given precendece level or higher
-    fn parse_precedence(&mut self, precedence: Precedence) -> Result<()> {
-        self.advance()?;
-        let can_assign = precedence <= Precedence::Assignment;
-        self.prefix(self.previous_kind(), can_assign)?;
-
-        while precedence <= self.current_kind().precedence() {
-            self.advance()?;
-            self.infix(self.previous_kind())?;
-        }
-        if can_assign && self.match_curr(TokenKind::Equal)? {
-            return Err(self.error_at_previous("Invalid assignment target."));
-        }
-        Ok(())
-    }
-
-    fn binary(&mut self) -> Result<()> {
-        let operator = self.previous_kind();
-        let precedence = operator.precedence();
-        self.parse_precedence(precedence.higher())?;
-
-        match operator {
-            TokenKind::Plus => self.emit_ins(OpCode::Add),
-            TokenKind::Minus => self.emit_ins(OpCode::Subtract),
-            TokenKind::Star => self.emit_ins(OpCode::Multiply),
-            TokenKind::Slash => self.emit_ins(OpCode::Divide),
-            TokenKind::BangEqual => {
-                self.emit_ins(OpCode::Equal);
-                self.emit_ins(OpCode::Not);
+            ExprKind::Assign { name, value } => {
+                let (is_local, arg) = self.resolve_variable(name)?;
+                self.expression(value)?;
+                let ins = if is_local {
+                    OpCode::SetLocal(arg)
+                } else {
+                    OpCode::SetGlobal(arg)
+                };
+                self.emit_ins(ins, line);
             }
-            TokenKind::EqualEqual => self.emit_ins(OpCode::Equal),
-            TokenKind::Less => self.emit_ins(OpCode::Less),
-            TokenKind::LessEqual => {
-                self.emit_ins(OpCode::Greater);
-                self.emit_ins(OpCode::Not);
+            ExprKind::Unary { op, operand } => {
+                self.expression(operand)?;
+                self.emit_ins(
+                    match op {
+                        UnaryOp::Not => OpCode::Not,
+                        UnaryOp::Negate => OpCode::Negate,
+                    },
+                    line,
+                );
             }
-
-            TokenKind::Greater => self.emit_ins(OpCode::Greater),
-            TokenKind::GreaterEqual => {
-                self.emit_ins(OpCode::Less);
-                self.emit_ins(OpCode::Not);
+            ExprKind::Binary { op, left, right } => {
+                self.expression(left)?;
+                self.expression(right)?;
+                self.binary(*op, line);
             }
-
-            _ => unreachable!(),
-        }
-        Ok(())
-    }
-
-    fn or(&mut self) -> Result<()> {
-        let else_jump = self.emit_jump(OpCode::JumpIfFalse(None));
-        let end_jump = self.emit_jump(OpCode::Jump(None));
-
-        self.patch_jump(else_jump);
-        self.emit_ins(OpCode::Pop);
-
-        self.parse_precedence(Precedence::Or)?;
-        self.patch_jump(end_jump);
-
-        Ok(())
-    }
-
-    fn and(&mut self) -> Result<()> {
-        let end_jump = self.emit_jump(OpCode::JumpIfFalse(None));
-
-        self.emit_ins(OpCode::Pop);
-        self.parse_precedence(Precedence::And)?;
-
-        self.patch_jump(end_jump);
-        Ok(())
-    }
-
-    fn literal(&mut self) {
-        match self.previous_kind() {
-            TokenKind::True => self.emit_ins(OpCode::True),
-            TokenKind::False => self.emit_ins(OpCode::False),
-            TokenKind::Nil => self.emit_ins(OpCode::Nil),
-            _ => unreachable!(),
-        }
-    }
-
-    fn argument_list(&mut self) -> Result<u8> {
-        let mut arg_count = 0;
-        if !self.check_curr(TokenKind::RightParen) {
-            loop {
-                self.expression()?;
-                if arg_count == 255 {
-                    return Err(self.error_at_previous("Cannot have more than 255 arguments."));
+            ExprKind::Logical { op, left, right } => {
+                self.expression(left)?;
+                let line = left.span.end_line;
+                match op {
+                    LogicalOp::And => {
+                        let end_jump = self.emit_jump(OpCode::JumpIfFalse(None), line);
+                        self.emit_ins(OpCode::Pop, line);
+                        self.expression(right)?;
+                        self.patch_jump(end_jump, line)?;
+                    }
+                    LogicalOp::Or => {
+                        let else_jump = self.emit_jump(OpCode::JumpIfFalse(None), line);
+                        let end_jump = self.emit_jump(OpCode::Jump(None), line);
+
+                        self.patch_jump(else_jump, line)?;
+                        self.emit_ins(OpCode::Pop, line);
+
+                        self.expression(right)?;
+                        self.patch_jump(end_jump, line)?;
+                    }
                 }
-                arg_count += 1;
-                if !self.match_curr(TokenKind::Comma)? {
-                    break;
+            }
+            ExprKind::Grouping(inner) => self.expression(inner)?,
+            ExprKind::Call { callee, args } => {
+                self.expression(callee)?;
+                for arg in args {
+                    self.expression(arg)?;
                 }
+                // the parser rejects calls with more arguments than fit in the operand
+                self.emit_ins(OpCode::Call(args.len() as u8), line);
             }
         }
-        self.consume(TokenKind::RightParen, "Expect ')' after arguments.")?;
-        Ok(arg_count)
-    }
-
-    fn call(&mut self) -> Result<()> {
-        let arg_count = self.argument_list()?;
-        self.emit_ins(OpCode::Call(arg_count));
         Ok(())
     }
 
-    fn _trace(&mut self, token: &token::Token, prev_line: usize) {
-        if token.line() != prev_line {
-            println!("{:04}", token.line());
-        } else {
-            print!("   | ");
-        }
-        print!("{:?} ", token.kind());
-    }
-
-    fn prefix(&mut self, kind: TokenKind<'a>, can_assign: bool) -> Result<()> {
-        match kind {
-            TokenKind::LeftParen => self.grouping(),
-            TokenKind::Number(val) => {
-                self.emit_const_ins(Value::Number(val));
-                Ok(())
+    fn binary(&mut self, op: BinaryOp, line: usize) {
+        match op {
+            BinaryOp::Add => self.emit_ins(OpCode::Add, line),
+            BinaryOp::Subtract => self.emit_ins(OpCode::Subtract, line),
+            BinaryOp::Multiply => self.emit_ins(OpCode::Multiply, line),
+            BinaryOp::Divide => self.emit_ins(OpCode::Divide, line),
+            BinaryOp::NotEqual => {
+                self.emit_ins(OpCode::Equal, line);
+                self.emit_ins(OpCode::Not, line);
             }
-            TokenKind::Minus | TokenKind::Bang => self.unary(),
-            TokenKind::True | TokenKind::False | TokenKind::Nil => {
-                self.literal();
-                Ok(())
+            BinaryOp::Equal => self.emit_ins(OpCode::Equal, line),
+            BinaryOp::Less => self.emit_ins(OpCode::Less, line),
+            BinaryOp::LessEqual => {
+                self.emit_ins(OpCode::Greater, line);
+                self.emit_ins(OpCode::Not, line);
             }
-            TokenKind::String(s) => {
-                self.emit_const_ins(Value::String(Rc::new(s.to_string())));
-                Ok(())
+            BinaryOp::Greater => self.emit_ins(OpCode::Greater, line),
+            BinaryOp::GreaterEqual => {
+                self.emit_ins(OpCode::Less, line);
+                self.emit_ins(OpCode::Not, line);
             }
-            TokenKind::Identifier(ident) => self.variable(ident, can_assign),
-
-            _ => Err(self.error_at_previous(&format!("Unexpected token '{:?}'", kind))),
         }
     }
 
-    fn infix(&mut self, kind: TokenKind) -> Result<()> {
-        match kind {
-            TokenKind::Minus
-            | TokenKind::Plus
-            | TokenKind::Slash
-            | TokenKind::Star
-            | TokenKind::EqualEqual
-            | TokenKind::Bang
-            | TokenKind::Greater
-            | TokenKind::GreaterEqual
-            | TokenKind::Less
-            | TokenKind::LessEqual => self.binary(),
-
-            TokenKind::Or => self.or(),
-            TokenKind::And => self.and(),
+    fn report_error(&mut self, error: Error) {
+        let diagnostic = match error.downcast::<Diagnostic>() {
+            Ok(diagnostic) => *diagnostic,
+            Err(error) => Diagnostic::at_line(error.to_string(), 0),
+        };
+        self.diagnostics.push(diagnostic);
+    }
 
-            TokenKind::LeftParen => self.call(),
+    fn error_at(&self, ident: &Ident<'a>, msg: &str) -> Error {
+        let message = format!(
+            "{} at line {}, at token '{}'",
+            msg, ident.span.line, ident.name
+        );
+        Error::from(Diagnostic::new(message, ident.span))
+    }
 
-            _ => Ok(()),
-        }
+    fn error_at_line(&self, line: usize, msg: &str) -> Error {
+        Error::from(Diagnostic::at_line(
+            format!("{} at line {}", msg, line),
+            line,
+        ))
     }
 }
+
+// The line of the last token in `stmts`, where the instructions closing their scope go.
+fn last_line(stmts: &[Stmt]) -> Option<usize> {
+    stmts.last().map(|stmt| stmt.span.end_line)
+}
@@ -0,0 +1,188 @@
+//! Line and branch coverage, behind `--coverage`.
+//!
+//! Like the profiler, coverage is collected by a tracer. Every instruction executed is counted,
+//! and every `JumpIfFalse` records which way it went: the true side falls through to the next
+//! instruction, the false side jumps. Both are reported by source line in the lcov format,
+//! along with how often each function was called.
+
+use std::{collections::HashMap, fmt::Write as _};
+
+use crate::{
+    bytecode::{FunctionObj, OpCode},
+    disasm,
+    vm::Trace,
+};
+
+// A `JumpIfFalse` of a function.
+struct Branch {
+    offset: usize,
+    line: usize,
+    target: usize,
+    // times the condition was true and false
+    taken: [u64; 2],
+}
+
+struct FunctionCoverage {
+    name: String,
+    main: bool,
+    // the line of its declaration
+    line: usize,
+    calls: u64,
+    // (offset, line) of every instruction
+    instructions: Vec<(usize, usize)>,
+    // times each instruction ran, by their index in `instructions`
+    hits: Vec<u64>,
+    // by offset
+    branches: Vec<Branch>,
+}
+
+pub struct Coverage {
+    functions: Vec<FunctionCoverage>,
+    // the functions by their address, which is stable while the VM holds them
+    index: HashMap<*const FunctionObj, usize>,
+    // the branch that ran last, (function, branch, depth), its side is known from the next
+    // instruction
+    pending: Option<(usize, usize, usize)>,
+    // frames on the call stack at the last instruction
+    depth: usize,
+}
+
+impl Coverage {
+    /// Prepares to cover `main` and the functions nested in it. They have to be the ones the VM
+    /// runs, not a copy, see [`crate::vm::VM::call_stack`].
+    pub fn new(main: &FunctionObj) -> Self {
+        let mut index = HashMap::new();
+        let functions = disasm::functions(main)
+            .into_iter()
+            .enumerate()
+            .map(|(i, fun)| {
+                index.insert(fun as *const FunctionObj, i);
+                let chunk = fun.chunk();
+                let instructions: Vec<_> = chunk
+                    .instructions()
+                    .map(|(offset, _)| (offset, chunk.get_line(offset)))
+                    .collect();
+                let branches = chunk
+                    .instructions()
+                    .filter(|(_, ins)| matches!(ins, OpCode::JumpIfFalse(_)))
+                    .filter_map(|(offset, ins)| {
+                        Some(Branch {
+                            offset,
+                            line: chunk.get_line(offset),
+                            target: ins.jump_target(offset)?,
+                            taken: [0; 2],
+                        })
+                    })
+                    .collect();
+                FunctionCoverage {
+                    name: fun.name().to_string(),
+                    main: fun.is_main(),
+                    line: fun.line(),
+                    calls: 0,
+                    hits: vec![0; instructions.len()],
+                    instructions,
+                    branches,
+                }
+            })
+            .collect();
+        Self {
+            functions,
+            index,
+            pending: None,
+            depth: 0,
+        }
+    }
+
+    /// Accounts for the instruction about to run, to be called from a tracer.
+    pub fn record(&mut self, trace: &Trace) {
+        // a call adds one frame, whose function is the one running now
+        let called = trace.depth > self.depth;
+        self.depth = trace.depth;
+        let Some(&function) = self.index.get(&(trace.function as *const FunctionObj)) else {
+            return;
+        };
+        if called {
+            self.functions[function].calls += 1;
+        }
+        if let Some((branch_function, branch, depth)) = self.pending.take() {
+            if branch_function == function && depth == trace.depth {
+                let branch = &mut self.functions[function].branches[branch];
+                let side = usize::from(trace.offset == branch.target);
+                branch.taken[side] += 1;
+            }
+        }
+
+        let coverage = &mut self.functions[function];
+        if let Ok(i) = coverage
+            .instructions
+            .binary_search_by_key(&trace.offset, |&(offset, _)| offset)
+        {
+            coverage.hits[i] += 1;
+        }
+        if let OpCode::JumpIfFalse(_) = trace.ins {
+            let branch = coverage
+                .branches
+                .binary_search_by_key(&trace.offset, |branch| branch.offset);
+            if let Ok(branch) = branch {
+                self.pending = Some((function, branch, trace.depth));
+            }
+        }
+    }
+
+    /// The coverage of the script at `path` as an lcov tracefile.
+    pub fn to_lcov(&self, path: &str) -> String {
+        let mut out = String::new();
+        writeln!(out, "TN:\nSF:{}", path).unwrap();
+
+        // functions, the main function is the script itself rather than one of them
+        let functions: Vec<_> = self.functions.iter().filter(|fun| !fun.main).collect();
+        for fun in functions.iter() {
+            writeln!(out, "FN:{},{}", fun.line, fun.name).unwrap();
+        }
+        for fun in functions.iter() {
+            writeln!(out, "FNDA:{},{}", fun.calls, fun.name).unwrap();
+        }
+        let called = functions.iter().filter(|fun| fun.calls > 0).count();
+        writeln!(out, "FNF:{}\nFNH:{}", functions.len(), called).unwrap();
+
+        let (mut found, mut hit) = (0, 0);
+        for (block, branch) in self
+            .functions
+            .iter()
+            .flat_map(|fun| &fun.branches)
+            .enumerate()
+        {
+            let ran = branch.taken.iter().any(|&taken| taken > 0);
+            for (side, &taken) in branch.taken.iter().enumerate() {
+                // `-` is a branch whose condition never ran
+                let taken = if ran {
+                    taken.to_string()
+                } else {
+                    "-".to_string()
+                };
+                writeln!(out, "BRDA:{},{},{},{}", branch.line, block, side, taken).unwrap();
+            }
+            found += 2;
+            hit += branch.taken.iter().filter(|&&taken| taken > 0).count();
+        }
+        writeln!(out, "BRF:{}\nBRH:{}", found, hit).unwrap();
+
+        // a line counts as often as its most executed instruction
+        let mut lines: HashMap<usize, u64> = HashMap::new();
+        for fun in self.functions.iter() {
+            for (&(_, line), &hits) in fun.instructions.iter().zip(fun.hits.iter()) {
+                let count = lines.entry(line).or_default();
+                *count = (*count).max(hits);
+            }
+        }
+        let mut lines: Vec<_> = lines.into_iter().filter(|&(line, _)| line > 0).collect();
+        lines.sort();
+        for &(line, hits) in lines.iter() {
+            writeln!(out, "DA:{},{}", line, hits).unwrap();
+        }
+        let lines_hit = lines.iter().filter(|&&(_, hits)| hits > 0).count();
+        writeln!(out, "LF:{}\nLH:{}", lines.len(), lines_hit).unwrap();
+        out.push_str("end_of_record\n");
+        out
+    }
+}
@@ -0,0 +1,395 @@
+//! A Debug Adapter Protocol server, behind `rlox dap`.
+//!
+//! Messages are JSON bodies framed by a `Content-Length` header, see [`json::read_message`],
+//! read from `input` and written to `output`. The script runs on the same thread as the
+//! server, so requests are only handled while it is paused. Lines are numbered from 1 and
+//! frames by their depth, 0 being the innermost, which the client only passes back to us.
+
+use std::{
+    cell::RefCell,
+    io::{self, BufRead, Write},
+    rc::Rc,
+};
+
+use crate::{
+    bytecode::Value,
+    debugger::{Debugger, Resume, Stop},
+    json::{self, Json},
+    verifier, Options, Result,
+};
+
+// the only thread, scripts can't start others
+const THREAD_ID: usize = 1;
+// variable references of the scopes, frame `n` has its locals at `LOCALS_REF + n`
+const GLOBALS_REF: usize = 1;
+const LOCALS_REF: usize = 2;
+
+// Collects what the script prints, to forward it as `output` events.
+#[derive(Clone, Default)]
+struct Captured(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Session<'a, W: Write> {
+    output: W,
+    seq: usize,
+    options: &'a Options,
+    program: String,
+    debugger: Option<Debugger<'static>>,
+    printed: Captured,
+    // lines requested before the script was launched
+    breakpoints: Vec<usize>,
+    stop_on_entry: bool,
+    // the exit code once the script finished or failed, it can't be resumed after that
+    exit_code: Option<i32>,
+    // whether the client was told the script exited
+    exited: bool,
+}
+
+/// Serves one debugging session, until the client disconnects or closes `input`.
+pub fn serve(mut input: impl BufRead, output: impl Write, options: &Options) -> Result<()> {
+    let mut session = Session {
+        output,
+        seq: 0,
+        options,
+        program: String::new(),
+        debugger: None,
+        printed: Captured::default(),
+        breakpoints: Vec::new(),
+        stop_on_entry: false,
+        exit_code: None,
+        exited: false,
+    };
+    while let Some(message) = json::read_message(&mut input)? {
+        if message.get("type").and_then(Json::as_str) != Some("request") {
+            continue;
+        }
+        if !session.request(&message)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+impl<W: Write> Session<'_, W> {
+    fn send(&mut self, kind: &str, mut fields: Vec<(&str, Json)>) -> io::Result<()> {
+        self.seq += 1;
+        fields.splice(0..0, [("seq", self.seq.into()), ("type", kind.into())]);
+        json::write_message(&mut self.output, &Json::object(fields))
+    }
+
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send("event", vec![("event", event.into()), ("body", body)])
+    }
+
+    // Handles a request, returns whether the session goes on.
+    fn request(&mut self, request: &Json) -> Result<bool> {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+        let null = Json::Null;
+        let args = request.get("arguments").unwrap_or(&null);
+
+        let result = self.handle(command, args);
+        let mut fields = vec![
+            (
+                "request_seq",
+                request.get("seq").cloned().unwrap_or(Json::Null),
+            ),
+            ("success", result.is_ok().into()),
+            ("command", command.into()),
+        ];
+        let after = match result {
+            Ok((body, after)) => {
+                fields.push(("body", body));
+                after
+            }
+            Err(error) => {
+                fields.push(("message", error.to_string().trim().into()));
+                After::Nothing
+            }
+        };
+        self.send("response", fields)?;
+
+        // events that follow from the request go out after its response
+        match after {
+            After::Nothing => {}
+            After::Initialized => self.event("initialized", Json::object::<&str>([]))?,
+            After::Start if self.stop_on_entry => self.stopped("entry")?,
+            After::Start => self.resume(Resume::Continue)?,
+            After::Resume(mode) => self.resume(mode)?,
+            After::Disconnect => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn handle(&mut self, command: &str, args: &Json) -> Result<(Json, After)> {
+        let no_body = || Json::object::<&str>([]);
+        Ok(match command {
+            "initialize" => {
+                let capabilities = Json::object([
+                    ("supportsConfigurationDoneRequest", true.into()),
+                    ("supportsTerminateRequest", true.into()),
+                ]);
+                (capabilities, After::Initialized)
+            }
+            "launch" => {
+                let program = args
+                    .get("program")
+                    .and_then(Json::as_str)
+                    .ok_or("launch expects a program")?;
+                let code = crate::load_file(program, self.options)?;
+                // refuse broken precompiled files right away rather than on the first step
+                verifier::verify(&code)?;
+
+                let mut debugger = Debugger::new(code, self.options.vm);
+                debugger.vm_mut().set_output(Box::new(self.printed.clone()));
+                for &line in self.breakpoints.iter() {
+                    debugger.set_breakpoint(line);
+                }
+                self.debugger = Some(debugger);
+                self.program = program.to_string();
+                self.stop_on_entry = args
+                    .get("stopOnEntry")
+                    .and_then(Json::as_bool)
+                    .unwrap_or(false);
+                (no_body(), After::Nothing)
+            }
+            "setBreakpoints" => {
+                let lines: Vec<usize> = args
+                    .get("breakpoints")
+                    .and_then(Json::as_array)
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|bp| bp.get("line").and_then(Json::as_usize))
+                    .collect();
+                // a script has a single source, so every request replaces all breakpoints
+                let set: Vec<_> = match self.debugger.as_mut() {
+                    Some(debugger) => {
+                        let old: Vec<_> = debugger.breakpoints().collect();
+                        for line in old {
+                            debugger.clear_breakpoint(line);
+                        }
+                        lines
+                            .iter()
+                            .map(|&line| debugger.set_breakpoint(line))
+                            .collect()
+                    }
+                    // checked against the code once it is launched
+                    None => lines.iter().map(|_| None).collect(),
+                };
+                let breakpoints = lines.iter().zip(set).map(|(&requested, line)| {
+                    Json::object([
+                        ("verified", line.is_some().into()),
+                        ("line", line.unwrap_or(requested).into()),
+                    ])
+                });
+                let body = Json::object([("breakpoints", Json::Array(breakpoints.collect()))]);
+                self.breakpoints = lines;
+                (body, After::Nothing)
+            }
+            "configurationDone" => {
+                self.debugger()?;
+                (no_body(), After::Start)
+            }
+            "threads" => {
+                let thread = Json::object([("id", THREAD_ID.into()), ("name", "main".into())]);
+                (
+                    Json::object([("threads", vec![thread].into())]),
+                    After::Nothing,
+                )
+            }
+            "stackTrace" => {
+                let debugger = self.debugger()?;
+                let source = Json::object([("path", self.program.as_str().into())]);
+                let frames: Vec<_> = debugger
+                    .frames()
+                    .iter()
+                    .enumerate()
+                    .map(|(id, frame)| {
+                        Json::object([
+                            ("id", id.into()),
+                            ("name", frame.function.name().into()),
+                            ("line", frame.line().into()),
+                            ("column", 1.into()),
+                            ("source", source.clone()),
+                        ])
+                    })
+                    .collect();
+                let body = Json::object([
+                    ("totalFrames", frames.len().into()),
+                    ("stackFrames", Json::Array(frames)),
+                ]);
+                (body, After::Nothing)
+            }
+            "scopes" => {
+                let frame = args
+                    .get("frameId")
+                    .and_then(Json::as_usize)
+                    .ok_or("scopes expects a frameId")?;
+                let scope = |name: &str, reference: usize| {
+                    Json::object([
+                        ("name", name.into()),
+                        ("variablesReference", reference.into()),
+                        ("expensive", false.into()),
+                    ])
+                };
+                let scopes = vec![
+                    scope("Locals", LOCALS_REF + frame),
+                    scope("Globals", GLOBALS_REF),
+                ];
+                (Json::object([("scopes", scopes.into())]), After::Nothing)
+            }
+            "variables" => {
+                let reference = args
+                    .get("variablesReference")
+                    .and_then(Json::as_usize)
+                    .ok_or("variables expects a variablesReference")?;
+                let debugger = self.debugger()?;
+                let variable = |name: &str, value: Option<&Value>| {
+                    let (value, kind) = match value {
+                        Some(Value::String(s)) => (format!("{:?}", s), "string"),
+                        Some(value @ Value::Number(_)) => (value.to_string(), "number"),
+                        Some(value @ Value::Boolean(_)) => (value.to_string(), "boolean"),
+                        Some(Value::Nil) => ("nil".to_string(), "nil"),
+                        Some(value @ (Value::Function(_) | Value::Native(_))) => {
+                            (value.to_string(), "function")
+                        }
+                        None => ("<undefined>".to_string(), "undefined"),
+                    };
+                    Json::object([
+                        ("name", name.into()),
+                        ("value", value.into()),
+                        ("type", kind.into()),
+                        ("variablesReference", 0.into()),
+                    ])
+                };
+                let variables: Vec<_> = if reference == GLOBALS_REF {
+                    debugger
+                        .vm()
+                        .globals()
+                        .map(|(name, value)| variable(name, value))
+                        .collect()
+                } else {
+                    let frames = debugger.frames();
+                    let frame = reference
+                        .checked_sub(LOCALS_REF)
+                        .and_then(|depth| frames.get(depth))
+                        .ok_or("Unknown variablesReference")?;
+                    frame
+                        .locals()
+                        .into_iter()
+                        .map(|(name, value)| variable(name, Some(value)))
+                        .collect()
+                };
+                (
+                    Json::object([("variables", variables.into())]),
+                    After::Nothing,
+                )
+            }
+            "continue" => {
+                self.debugger()?;
+                let body = Json::object([("allThreadsContinued", true.into())]);
+                (body, After::Resume(Resume::Continue))
+            }
+            "next" | "stepIn" | "stepOut" => {
+                self.debugger()?;
+                let mode = match command {
+                    "next" => Resume::StepOver,
+                    "stepIn" => Resume::StepIn,
+                    _ => Resume::StepOut,
+                };
+                (no_body(), After::Resume(mode))
+            }
+            "disconnect" | "terminate" => (no_body(), After::Disconnect),
+            _ => return Err(format!("Unsupported request '{}'", command).into()),
+        })
+    }
+
+    fn debugger(&self) -> Result<&Debugger<'static>> {
+        self.debugger
+            .as_ref()
+            .ok_or_else(|| "No script was launched".into())
+    }
+
+    fn stopped(&mut self, reason: &str) -> io::Result<()> {
+        let body = Json::object([
+            ("reason", reason.into()),
+            ("threadId", THREAD_ID.into()),
+            ("allThreadsStopped", true.into()),
+        ]);
+        self.event("stopped", body)
+    }
+
+    fn resume(&mut self, mode: Resume) -> Result<()> {
+        let Some(debugger) = self.debugger.as_mut() else {
+            return Ok(());
+        };
+        if let Some(exit_code) = self.exit_code {
+            // a failed script stays stopped at its error until the client resumes it
+            return Ok(self.exited(exit_code)?);
+        }
+        let result = debugger.resume(mode);
+
+        let printed = String::from_utf8_lossy(&self.printed.0.take()).into_owned();
+        if !printed.is_empty() {
+            let body = Json::object([("category", "stdout".into()), ("output", printed.into())]);
+            self.event("output", body)?;
+        }
+
+        match result {
+            Ok(Stop::Breakpoint(_)) => self.stopped("breakpoint")?,
+            Ok(Stop::Step) => self.stopped("step")?,
+            Ok(Stop::Finished) => {
+                self.exit_code = Some(0);
+                self.exited(0)?;
+            }
+            Err(error) => {
+                // the frames are still there to inspect, but the script can't go on
+                self.exit_code = Some(crate::exit_code(&error));
+                let message = error.to_string();
+                let output = Json::object([
+                    ("category", "stderr".into()),
+                    ("output", format!("{}\n", message).into()),
+                ]);
+                self.event("output", output)?;
+                let body = Json::object([
+                    ("reason", "exception".into()),
+                    ("description", "Runtime error".into()),
+                    ("text", message.into()),
+                    ("threadId", THREAD_ID.into()),
+                    ("allThreadsStopped", true.into()),
+                ]);
+                self.event("stopped", body)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Tells the client the script exited, once.
+    fn exited(&mut self, exit_code: i32) -> io::Result<()> {
+        if self.exited {
+            return Ok(());
+        }
+        self.exited = true;
+        let body = Json::object([("exitCode", (exit_code as f64).into())]);
+        self.event("exited", body)?;
+        self.event("terminated", Json::object::<&str>([]))
+    }
+}
+
+// What happens once a request was answered.
+enum After {
+    Nothing,
+    Initialized,
+    Start,
+    Resume(Resume),
+    Disconnect,
+}
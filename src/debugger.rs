@@ -0,0 +1,397 @@
+//! Pausing a running script at breakpoints and stepping through it, behind `rlox debug`.
+
+use std::{
+    collections::BTreeSet,
+    io::{self, Write},
+};
+
+use crate::{
+    bytecode::{FunctionObj, Value},
+    disasm, sigint,
+    vm::{Frame, RuntimeError, RuntimeErrorKind, VmConfig, VM},
+    Options, Result,
+};
+
+/// How far [`Debugger::resume`] runs the script before pausing again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    /// Until a breakpoint.
+    Continue,
+    /// Until another line starts, including lines of called functions.
+    StepIn,
+    /// Until another line of the current function starts, or it returns.
+    StepOver,
+    /// Until the current function returns to its caller.
+    StepOut,
+}
+
+/// Why [`Debugger::resume`] paused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint(usize),
+    Step,
+    Finished,
+}
+
+// Where the innermost frame is paused.
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    depth: usize,
+    offset: usize,
+    line: usize,
+}
+
+/// A VM that runs one instruction at a time, pausing on breakpoint lines and after steps.
+pub struct Debugger<'a> {
+    vm: VM<'a>,
+    breakpoints: BTreeSet<usize>,
+    // lines with code, where breakpoints can go
+    code_lines: BTreeSet<usize>,
+}
+
+impl<'a> Debugger<'a> {
+    /// A debugger paused before the first instruction of `code`.
+    pub fn new(code: FunctionObj, config: VmConfig) -> Self {
+        let code_lines = disasm::functions(&code)
+            .into_iter()
+            .flat_map(|fun| fun.chunk().lines().runs().map(|(_, line)| line))
+            .collect();
+        Self {
+            vm: VM::with_config(code, config),
+            breakpoints: BTreeSet::new(),
+            code_lines,
+        }
+    }
+
+    pub fn vm(&self) -> &VM<'a> {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VM<'a> {
+        &mut self.vm
+    }
+
+    /// Sets a breakpoint on the first line with code at or after `line`, and returns that line.
+    pub fn set_breakpoint(&mut self, line: usize) -> Option<usize> {
+        let line = *self.code_lines.range(line..).next()?;
+        self.breakpoints.insert(line);
+        Some(line)
+    }
+
+    /// Removes the breakpoint on `line`, returns whether there was one.
+    pub fn clear_breakpoint(&mut self, line: usize) -> bool {
+        self.breakpoints.remove(&line)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// The call frames, innermost first.
+    pub fn frames(&self) -> Vec<Frame<'_>> {
+        self.vm.call_stack()
+    }
+
+    /// The value of `name` as seen from the frame at `depth`, 0 being the innermost: a local in
+    /// scope there, or else a global. The global is `Some(None)` before it is defined.
+    pub fn lookup(&self, depth: usize, name: &str) -> Option<Option<Value>> {
+        let frames = self.frames();
+        let local = frames.get(depth).and_then(|frame| {
+            // the highest slot is the innermost scope, it shadows the others
+            let locals = frame.locals();
+            let (_, value) = locals.into_iter().rev().find(|(local, _)| *local == name)?;
+            Some(value.clone())
+        });
+        if local.is_some() {
+            return Some(local);
+        }
+        self.vm
+            .globals()
+            .find(|(global, _)| *global == name)
+            .map(|(_, value)| value.cloned())
+    }
+
+    fn position(&self) -> Position {
+        let frames = self.frames();
+        Position {
+            depth: frames.len(),
+            offset: frames[0].offset,
+            line: frames[0].line(),
+        }
+    }
+
+    /// Runs the script until `mode` or a breakpoint pauses it, or it finishes.
+    pub fn resume(&mut self, mode: Resume) -> Result<Stop> {
+        if self.vm.is_finished() {
+            return Ok(Stop::Finished);
+        }
+        let start = self.position();
+        let mut prev = start;
+        loop {
+            if self.vm.step()? {
+                return Ok(Stop::Finished);
+            }
+            let pos = self.position();
+            // a line is entered by moving to it, calling into it or jumping back to its start,
+            // returning to the rest of the caller's line doesn't enter it again
+            let entered = pos.depth > prev.depth
+                || pos.depth == prev.depth && (pos.line != prev.line || pos.offset <= prev.offset);
+            prev = pos;
+            if entered && self.breakpoints.contains(&pos.line) {
+                return Ok(Stop::Breakpoint(pos.line));
+            }
+
+            let moved_on = pos.line != start.line || pos.offset <= start.offset;
+            let stop = match mode {
+                Resume::Continue => false,
+                Resume::StepIn => pos.depth != start.depth || moved_on,
+                Resume::StepOver => pos.depth < start.depth || pos.depth == start.depth && moved_on,
+                Resume::StepOut => pos.depth < start.depth,
+            };
+            if stop {
+                return Ok(Stop::Step);
+            }
+        }
+    }
+}
+
+const HELP: &str = "Commands:
+  b, break [line]     set a breakpoint, or list them without a line
+  d, delete <line>    remove a breakpoint
+  c, continue         run until a breakpoint
+  s, step             run to the next line, stepping into calls
+  n, next             run to the next line of this function
+  o, out              run until this function returns
+  bt, backtrace       show the call frames
+  f, frame <n>        select the frame to inspect, 0 is the innermost
+  l, locals           show the locals of the selected frame
+  g, globals          show the globals
+  p, print <name>     show a local of the selected frame or a global
+  list                show the source around the selected frame's line
+  q, quit             stop debugging";
+
+// Values as shown by the debugger, strings are quoted so they stand out.
+fn show(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("{:?}", s),
+        value => value.to_string(),
+    }
+}
+
+/// Debugs `code` interactively, reading commands from stdin. `source` shows the lines the
+/// script pauses on, precompiled files have none.
+pub fn run_cli(code: FunctionObj, source: Option<&str>, options: &Options) -> Result<()> {
+    let interrupt = crate::InterruptHandle::new();
+    sigint::install(&interrupt);
+
+    let mut cli = Cli {
+        debugger: Debugger::new(code, options.vm),
+        source: source
+            .map(|source| source.lines().collect())
+            .unwrap_or_default(),
+        selected: 0,
+        done: false,
+    };
+    cli.debugger
+        .vm_mut()
+        .set_interrupt_handle(interrupt.clone());
+
+    println!("Type 'help' for the commands.");
+    if cli.debugger.vm().is_finished() {
+        cli.finish();
+    } else {
+        cli.show_location();
+    }
+
+    loop {
+        print!("(rlox) ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line)? == 0 {
+            // EOF
+            println!();
+            break;
+        }
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            continue;
+        };
+        let arg = words.next();
+
+        let mode = match command {
+            "c" | "continue" => Resume::Continue,
+            "s" | "step" => Resume::StepIn,
+            "n" | "next" => Resume::StepOver,
+            "o" | "out" => Resume::StepOut,
+            "q" | "quit" => break,
+            _ => {
+                cli.inspect(command, arg);
+                continue;
+            }
+        };
+        if cli.done {
+            println!("The script has finished.");
+            continue;
+        }
+
+        interrupt.reset();
+        sigint::set_running(true);
+        let result = cli.debugger.resume(mode);
+        sigint::set_running(false);
+        cli.selected = 0;
+        match result {
+            Ok(Stop::Finished) => cli.finish(),
+            Ok(Stop::Breakpoint(line)) => {
+                println!("Breakpoint at line {}", line);
+                cli.show_location();
+            }
+            Ok(Stop::Step) => cli.show_location(),
+            // an interrupted script can go on, it only stopped between two instructions
+            Err(error)
+                if error
+                    .downcast_ref::<RuntimeError>()
+                    .is_some_and(|error| error.kind() == RuntimeErrorKind::Interrupted) =>
+            {
+                println!("Interrupted");
+                cli.show_location();
+            }
+            Err(error) => {
+                eprintln!("{}", error);
+                // the frames stay around to be inspected, but the script can't go on
+                cli.done = true;
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Cli<'a, 's> {
+    debugger: Debugger<'a>,
+    source: Vec<&'s str>,
+    // the frame inspected by `locals`, `print` and `list`
+    selected: usize,
+    done: bool,
+}
+
+impl Cli<'_, '_> {
+    fn finish(&mut self) {
+        println!("The script has finished.");
+        self.done = true;
+    }
+
+    fn show_location(&self) {
+        let frames = self.debugger.frames();
+        let Some(frame) = frames.get(self.selected) else {
+            return;
+        };
+        if frame.function.chunk().is_empty() {
+            return;
+        }
+        let line = frame.line();
+        println!("{}() at line {}", frame.function.name(), line);
+        if let Some(text) = self.source.get(line.wrapping_sub(1)) {
+            println!("{:>5} | {}", line, text);
+        }
+    }
+
+    // Commands that don't run the script.
+    fn inspect(&mut self, command: &str, arg: Option<&str>) {
+        let line_arg = || arg.and_then(|arg| arg.parse::<usize>().ok());
+        match command {
+            "h" | "help" => println!("{}", HELP),
+            "b" | "break" if arg.is_none() => {
+                let lines: Vec<_> = self.debugger.breakpoints().map(|l| l.to_string()).collect();
+                match lines.is_empty() {
+                    true => println!("No breakpoints."),
+                    false => println!("Breakpoints on lines {}", lines.join(", ")),
+                }
+            }
+            "b" | "break" => match line_arg().map(|line| self.debugger.set_breakpoint(line)) {
+                Some(Some(line)) => println!("Breakpoint set on line {}", line),
+                Some(None) => println!("No code at or after line {}", arg.unwrap()),
+                None => println!("break expects a line number"),
+            },
+            "d" | "delete" => match line_arg() {
+                Some(line) if self.debugger.clear_breakpoint(line) => {
+                    println!("Breakpoint on line {} removed", line)
+                }
+                Some(line) => println!("No breakpoint on line {}", line),
+                None => println!("delete expects a line number"),
+            },
+            "bt" | "backtrace" => {
+                for (i, frame) in self.debugger.frames().iter().enumerate() {
+                    let marker = if i == self.selected { '>' } else { ' ' };
+                    println!(
+                        "{}#{} {}() at line {}",
+                        marker,
+                        i,
+                        frame.function.name(),
+                        frame.line()
+                    );
+                }
+            }
+            "f" | "frame" => match line_arg() {
+                Some(n) if n < self.debugger.frames().len() => {
+                    self.selected = n;
+                    self.show_location();
+                }
+                _ => println!("frame expects a frame number from the backtrace"),
+            },
+            "l" | "locals" => {
+                let frames = self.debugger.frames();
+                let locals = frames
+                    .get(self.selected)
+                    .map(Frame::locals)
+                    .unwrap_or_default();
+                if locals.is_empty() {
+                    println!("No locals.");
+                }
+                for (name, value) in locals {
+                    println!("{} = {}", name, show(value));
+                }
+            }
+            "g" | "globals" => {
+                for (name, value) in self.debugger.vm().globals() {
+                    match value {
+                        Some(value) => println!("{} = {}", name, show(value)),
+                        None => println!("{} = <undefined>", name),
+                    }
+                }
+            }
+            "p" | "print" => {
+                match arg.map(|name| (name, self.debugger.lookup(self.selected, name))) {
+                    Some((name, Some(Some(value)))) => println!("{} = {}", name, show(&value)),
+                    Some((name, Some(None))) => println!("{} is not defined yet", name),
+                    Some((name, None)) => println!("No variable named '{}'", name),
+                    None => println!("print expects a variable name"),
+                }
+            }
+            "list" => {
+                let frames = self.debugger.frames();
+                let Some(frame) = frames.get(self.selected) else {
+                    return;
+                };
+                if frame.function.chunk().is_empty() {
+                    return;
+                }
+                let line = frame.line();
+                for n in line.saturating_sub(3).max(1)..=line + 3 {
+                    let Some(text) = self.source.get(n - 1) else {
+                        break;
+                    };
+                    let marker = if n == line { '>' } else { ' ' };
+                    let mark = if self.debugger.breakpoints.contains(&n) {
+                        '*'
+                    } else {
+                        ' '
+                    };
+                    println!("{}{}{:>4} | {}", marker, mark, n, text);
+                }
+            }
+            _ => println!(
+                "Unknown command '{}', type 'help' for the commands",
+                command
+            ),
+        }
+    }
+}
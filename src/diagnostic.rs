@@ -0,0 +1,50 @@
+use std::fmt;
+
+use crate::ast::Span;
+
+/// A compile error, with the part of the source it is about so tools can point at it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    message: String,
+    line: usize,
+    // `None` when only the line is known
+    span: Option<Span>,
+}
+
+impl Diagnostic {
+    pub fn new(message: String, span: Span) -> Self {
+        Self {
+            message,
+            line: span.line,
+            span: Some(span),
+        }
+    }
+
+    pub fn at_line(message: String, line: usize) -> Self {
+        Self {
+            message,
+            line,
+            span: None,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Diagnostic {}
@@ -0,0 +1,175 @@
+use std::fmt::Write;
+
+use crate::{
+    bytecode::{FunctionObj, OpCode, Value},
+    json::Json,
+};
+
+/// `main` followed by every function nested in its constants, depth first.
+pub fn functions(main: &FunctionObj) -> Vec<&FunctionObj> {
+    fn collect<'a>(fun: &'a FunctionObj, out: &mut Vec<&'a FunctionObj>) {
+        out.push(fun);
+        for constant in fun.chunk().constants() {
+            if let Value::Function(nested) = constant {
+                collect(nested, out);
+            }
+        }
+    }
+    let mut out = Vec::new();
+    collect(main, &mut out);
+    out
+}
+
+// Human-readable operands, `globals` names the global slots.
+fn operands(fun: &FunctionObj, offset: usize, ins: OpCode, globals: &[String]) -> String {
+    let global = |slot: u16| match globals.get(slot as usize) {
+        Some(name) => format!("g{} {}", slot, name),
+        None => format!("g{} <unknown>", slot),
+    };
+    match ins {
+        OpCode::Constant(index) => match fun.chunk().constants().get(index as usize) {
+            Some(Value::String(s)) => format!("#{} {:?}", index, s),
+            Some(value) => format!("#{} {}", index, value),
+            None => format!("#{} <out of range>", index),
+        },
+        OpCode::DefineGlobal(slot) | OpCode::GetGlobal(slot) | OpCode::SetGlobal(slot) => {
+            global(slot)
+        }
+        OpCode::GetLocal(slot) | OpCode::SetLocal(slot) => format!("s{}", slot),
+        OpCode::Jump(Some(jump)) | OpCode::JumpIfFalse(Some(jump)) => {
+            format!(
+                "+{} -> {:04}",
+                jump,
+                offset + OpCode::JUMP_SIZE + jump as usize
+            )
+        }
+        OpCode::Jump(None) | OpCode::JumpIfFalse(None) => "<unpatched>".to_string(),
+        OpCode::Loop(jump) => match ins.jump_target(offset) {
+            Some(target) => format!("-{} -> {:04}", jump, target),
+            None => format!("-{} -> <before start>", jump),
+        },
+        OpCode::Call(arg_count) => format!("{} args", arg_count),
+        _ => String::new(),
+    }
+}
+
+/// A listing of every function in `main`, with source lines, constant values, global
+/// names and the offsets jumps land on.
+pub fn to_text(main: &FunctionObj) -> String {
+    let globals = main.global_names();
+    let mut out = String::new();
+    for (i, fun) in functions(main).into_iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        let chunk = fun.chunk();
+        writeln!(
+            out,
+            "== {} ({} params, {} bytes) ==",
+            fun.name(),
+            fun.arity(),
+            chunk.len()
+        )
+        .unwrap();
+        let mut prev_line = None;
+        for (offset, ins) in chunk.instructions() {
+            let line = chunk.get_line(offset);
+            let line_col = if prev_line == Some(line) {
+                "|".to_string()
+            } else {
+                line.to_string()
+            };
+            prev_line = Some(line);
+            let operands = operands(fun, offset, ins, globals);
+            writeln!(
+                out,
+                "{:04} {:>4}  {:<18} {}",
+                offset,
+                line_col,
+                ins.name(),
+                operands
+            )
+            .unwrap();
+        }
+    }
+    out
+}
+
+/// The same listing as [`to_text`] as a JSON document:
+///
+/// ```text
+/// { "globals": [name...],
+///   "functions": [{ "name", "arity", "size", "constants": [constant...],
+///                   "code": [{ "offset", "line", "op", operands... }] }] }
+/// ```
+///
+/// Functions are numbered by their position in `functions`, which constants refer to.
+pub fn to_json(main: &FunctionObj) -> Json {
+    let functions = functions(main);
+    let index_of = |fun: &FunctionObj| {
+        functions
+            .iter()
+            .position(|other| std::ptr::eq(*other, fun))
+            .unwrap()
+    };
+    let constant = |value: &Value| match value {
+        Value::Number(n) => Json::object([("type", "number".into()), ("value", (*n).into())]),
+        Value::String(s) => Json::object([("type", "string".into()), ("value", s.as_str().into())]),
+        Value::Boolean(b) => Json::object([("type", "bool".into()), ("value", (*b).into())]),
+        Value::Nil => Json::object([("type", "nil".into())]),
+        Value::Function(f) => Json::object([
+            ("type", "function".into()),
+            ("function", index_of(f).into()),
+        ]),
+        Value::Native(native) => {
+            Json::object([("type", "native".into()), ("name", native.name.into())])
+        }
+    };
+
+    let globals = main.global_names();
+    let listing = functions.iter().map(|fun| {
+        let chunk = fun.chunk();
+        let code = chunk.instructions().map(|(offset, ins)| {
+            let mut fields = vec![
+                ("offset", offset.into()),
+                ("line", chunk.get_line(offset).into()),
+                ("op", ins.name().into()),
+            ];
+            match ins {
+                OpCode::Constant(index) => fields.push(("constant", (index as usize).into())),
+                OpCode::DefineGlobal(slot) | OpCode::GetGlobal(slot) | OpCode::SetGlobal(slot) => {
+                    fields.push(("slot", (slot as usize).into()));
+                    let name = globals.get(slot as usize).map(String::as_str);
+                    fields.push(("name", name.into()));
+                }
+                OpCode::GetLocal(slot) | OpCode::SetLocal(slot) => {
+                    fields.push(("slot", (slot as usize).into()))
+                }
+                OpCode::Jump(_) | OpCode::JumpIfFalse(_) | OpCode::Loop(_) => {
+                    fields.push(("target", ins.jump_target(offset).into()))
+                }
+                OpCode::Call(arg_count) => fields.push(("args", (arg_count as usize).into())),
+                _ => {}
+            }
+            Json::object(fields)
+        });
+        Json::object([
+            ("name", fun.name().into()),
+            ("arity", (fun.arity() as usize).into()),
+            ("size", chunk.len().into()),
+            (
+                "constants",
+                Json::Array(chunk.constants().iter().map(constant).collect()),
+            ),
+            ("code", Json::Array(code.collect())),
+        ])
+    });
+
+    Json::object([
+        (
+            "globals",
+            Json::Array(globals.iter().map(|name| name.as_str().into()).collect()),
+        ),
+        ("functions", Json::Array(listing.collect())),
+    ])
+}
@@ -0,0 +1,359 @@
+//! Formatting scripts in the canonical style, behind `rlox fmt`.
+//!
+//! Statements go on their own lines, indented by four spaces per block, with braces on the line
+//! of the statement they belong to and spaces around binary operators. Blank lines between
+//! statements are kept, runs of them are collapsed into one. Comments are kept where they are:
+//! on their own line or after the code of a line. A comment before the body of an `if`, `else`,
+//! `while` or `for` moves the body to the next line. The few inside an expression are moved
+//! after their statement, since expressions are always printed on one line.
+
+use crate::{
+    ast::{Expr, ExprKind, FunDecl, Literal, Program, Span, Stmt, StmtKind},
+    parser::Parser,
+    Result,
+};
+
+const INDENT: &str = "    ";
+
+/// Formats `source`, which has to parse without errors.
+pub fn format(source: &str) -> Result<String> {
+    let (program, comments) = Parser::with_source(source).parse_with_comments()?;
+    let mut formatter = Formatter {
+        source,
+        comments,
+        next_comment: 0,
+        out: String::with_capacity(source.len()),
+        indent: 0,
+        last_end: None,
+    };
+    formatter.program(&program);
+    Ok(formatter.out)
+}
+
+struct Formatter<'a> {
+    source: &'a str,
+    comments: Vec<Span>,
+    // the first comment that isn't printed yet
+    next_comment: usize,
+    out: String,
+    // the current number of indents
+    indent: usize,
+    // where the last statement or comment printed on its own line ended, None at the start of
+    // a block since blocks never start with a blank line
+    last_end: Option<usize>,
+}
+
+impl Formatter<'_> {
+    fn program(&mut self, program: &Program) {
+        self.stmts(&program.stmts, self.source.len());
+    }
+
+    // A statement per line, followed by the comments left before `end`.
+    fn stmts(&mut self, stmts: &[Stmt], end: usize) {
+        for stmt in stmts {
+            self.comments_before(stmt.span.start);
+            self.start_line(stmt.span.start);
+            self.stmt(stmt);
+            self.trailing_comment(stmt.span.end);
+            self.out.push('\n');
+            self.last_end = Some(stmt.span.end);
+            // the comments inside the statement's expressions
+            self.comments_before(stmt.span.end);
+        }
+        self.comments_before(end);
+    }
+
+    // Starts a line for something at `start`, after a blank line if the source had one.
+    fn start_line(&mut self, start: usize) {
+        // comments moved out of a statement come before its end
+        if let Some(end) = self.last_end.filter(|&end| end < start) {
+            if self.source[end..start].matches('\n').count() > 1 {
+                self.out.push('\n');
+            }
+        }
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+
+    fn next_comment(&self) -> Option<Span> {
+        self.comments.get(self.next_comment).copied()
+    }
+
+    // Prints the comments that start before `offset` on their own lines.
+    fn comments_before(&mut self, offset: usize) {
+        while let Some(comment) = self.next_comment().filter(|c| c.start < offset) {
+            self.start_line(comment.start);
+            self.comment(comment);
+            self.out.push('\n');
+            self.last_end = Some(comment.end);
+        }
+    }
+
+    // Prints the comment right after `offset` on the same line, with no code in between, if
+    // there is one. Returns whether there was.
+    fn trailing_comment(&mut self, offset: usize) -> bool {
+        let Some(comment) = self.next_comment() else {
+            return false;
+        };
+        let trailing = comment.start >= offset
+            && self.source[offset..comment.start]
+                .bytes()
+                .all(|b| b == b' ' || b == b'\t' || b == b'\r');
+        if trailing {
+            self.out.push(' ');
+            self.comment(comment);
+        }
+        trailing
+    }
+
+    fn comment(&mut self, comment: Span) {
+        self.next_comment += 1;
+        self.out
+            .push_str(self.source[comment.start..comment.end].trim_end());
+    }
+
+    // Where the next token at or after `offset` starts, skipping whitespace and comments.
+    fn next_token(&self, mut offset: usize) -> usize {
+        let bytes = self.source.as_bytes();
+        while offset < bytes.len() {
+            match self.comments.iter().find(|c| c.start == offset) {
+                Some(comment) => offset = comment.end,
+                None if bytes[offset].is_ascii_whitespace() => offset += 1,
+                None => break,
+            }
+        }
+        offset
+    }
+
+    // Where the next `{` at or after `offset` is, skipping comments.
+    fn open_brace(&self, mut offset: usize) -> usize {
+        let bytes = self.source.as_bytes();
+        while bytes[offset] != b'{' {
+            match self.comments.iter().find(|c| c.start == offset) {
+                Some(comment) => offset = comment.end,
+                None => offset += 1,
+            }
+        }
+        offset
+    }
+
+    // `{`, the statements and `}`, where `span` covers the braces.
+    fn block(&mut self, stmts: &[Stmt], span: Span) {
+        let close = span.end - 1;
+        self.out.push('{');
+        let empty = stmts.is_empty() && self.next_comment().is_none_or(|c| c.start > close);
+        if !empty {
+            self.trailing_comment(span.start + 1);
+            self.out.push('\n');
+            self.indent += 1;
+            self.last_end = None;
+            self.stmts(stmts, close);
+            self.indent -= 1;
+            for _ in 0..self.indent {
+                self.out.push_str(INDENT);
+            }
+        }
+        self.out.push('}');
+    }
+
+    // The body of `if`, `else`, `while` and `for`: blocks start on the same line, other
+    // statements go there whole. After comments they start on the next line, statements
+    // indented. Returns whether the line ends with a comment.
+    fn body(&mut self, body: &Stmt) -> bool {
+        if self
+            .next_comment()
+            .is_none_or(|c| c.start > body.span.start)
+        {
+            self.out.push(' ');
+            self.stmt(body);
+            return self.trailing_comment(body.span.end);
+        }
+        // the first comment stays on the line of the keyword unless it was on its own line
+        let comment = self.next_comment().filter(|c| {
+            let line_start = self.source[..c.start].rfind('\n').map_or(0, |i| i + 1);
+            !self.source[line_start..c.start].trim().is_empty()
+        });
+        if let Some(comment) = comment {
+            self.out.push(' ');
+            self.comment(comment);
+        }
+        self.out.push('\n');
+        // a block keeps its braces at the indent of the keyword
+        let indent = !matches!(body.kind, StmtKind::Block(_)) as usize;
+        self.indent += indent;
+        self.last_end = None;
+        self.comments_before(body.span.start);
+        self.start_line(body.span.start);
+        self.stmt(body);
+        self.indent -= indent;
+        self.trailing_comment(body.span.end)
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Expression(expr) => {
+                self.expr(expr);
+                self.out.push(';');
+            }
+            StmtKind::Print(expr) => {
+                self.out.push_str("print ");
+                self.expr(expr);
+                self.out.push(';');
+            }
+            StmtKind::Var { name, init } => {
+                self.out.push_str("var ");
+                self.out.push_str(name.name);
+                if let Some(init) = init {
+                    self.out.push_str(" = ");
+                    self.expr(init);
+                }
+                self.out.push(';');
+            }
+            StmtKind::Fun(decl) => self.fun(decl, stmt.span),
+            StmtKind::Block(stmts) => self.block(stmts, stmt.span),
+            StmtKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                self.out.push_str("if (");
+                self.expr(cond);
+                self.out.push(')');
+                let commented = self.body(then_branch);
+                if let Some(else_branch) = else_branch {
+                    let at_else = self.next_token(then_branch.span.end);
+                    let comments = self.next_comment().is_some_and(|c| c.start < at_else);
+                    if matches!(then_branch.kind, StmtKind::Block(_)) && !commented && !comments {
+                        self.out.push(' ');
+                    } else {
+                        self.out.push('\n');
+                        // the comments between the two branches stay before `else`
+                        self.last_end = None;
+                        self.comments_before(at_else);
+                        for _ in 0..self.indent {
+                            self.out.push_str(INDENT);
+                        }
+                    }
+                    self.out.push_str("else");
+                    self.body(else_branch);
+                }
+            }
+            StmtKind::While { cond, body } => {
+                self.out.push_str("while (");
+                self.expr(cond);
+                self.out.push(')');
+                self.body(body);
+            }
+            StmtKind::For {
+                init,
+                cond,
+                increment,
+                body,
+            } => {
+                self.out.push_str("for (");
+                match init {
+                    Some(init) => self.stmt(init),
+                    None => self.out.push(';'),
+                }
+                if let Some(cond) = cond {
+                    self.out.push(' ');
+                    self.expr(cond);
+                }
+                self.out.push(';');
+                if let Some(increment) = increment {
+                    self.out.push(' ');
+                    self.expr(increment);
+                }
+                self.out.push(')');
+                self.body(body);
+            }
+            StmtKind::Test { name, body } => {
+                self.out.push_str("test ");
+                self.out
+                    .push_str(&self.source[name.span.start..name.span.end]);
+                self.out.push(' ');
+                let body_span = Span {
+                    start: self.open_brace(name.span.end),
+                    ..stmt.span
+                };
+                self.block(body, body_span);
+            }
+            StmtKind::Return(value) => {
+                self.out.push_str("return");
+                if let Some(value) = value {
+                    self.out.push(' ');
+                    self.expr(value);
+                }
+                self.out.push(';');
+            }
+        }
+    }
+
+    fn fun(&mut self, decl: &FunDecl, span: Span) {
+        self.out.push_str("fun ");
+        self.out.push_str(decl.name.name);
+        self.out.push('(');
+        for (i, param) in decl.params.iter().enumerate() {
+            if i > 0 {
+                self.out.push_str(", ");
+            }
+            self.out.push_str(param.name);
+        }
+        self.out.push_str(") ");
+        let after_params = decl.params.last().unwrap_or(&decl.name).span.end;
+        let body = Span {
+            start: self.open_brace(after_params),
+            ..span
+        };
+        self.block(&decl.body, body);
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            // numbers keep how they were written, `1.50` stays `1.50`
+            ExprKind::Literal(Literal::Number(_) | Literal::String(_)) => self
+                .out
+                .push_str(&self.source[expr.span.start..expr.span.end]),
+            ExprKind::Literal(Literal::True) => self.out.push_str("true"),
+            ExprKind::Literal(Literal::False) => self.out.push_str("false"),
+            ExprKind::Literal(Literal::Nil) => self.out.push_str("nil"),
+            ExprKind::Variable(name) => self.out.push_str(name.name),
+            ExprKind::Assign { name, value } => {
+                self.out.push_str(name.name);
+                self.out.push_str(" = ");
+                self.expr(value);
+            }
+            ExprKind::Unary { op, operand } => {
+                self.out.push_str(&op.to_string());
+                self.expr(operand);
+            }
+            ExprKind::Binary { op, left, right } => {
+                self.expr(left);
+                self.out.push_str(&format!(" {} ", op));
+                self.expr(right);
+            }
+            ExprKind::Logical { op, left, right } => {
+                self.expr(left);
+                self.out.push_str(&format!(" {} ", op));
+                self.expr(right);
+            }
+            ExprKind::Grouping(inner) => {
+                self.out.push('(');
+                self.expr(inner);
+                self.out.push(')');
+            }
+            ExprKind::Call { callee, args } => {
+                self.expr(callee);
+                self.out.push('(');
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push_str(", ");
+                    }
+                    self.expr(arg);
+                }
+                self.out.push(')');
+            }
+        }
+    }
+}
@@ -0,0 +1,317 @@
+//! Fuzzing the scanner, the compiler and the VM for panics, behind `rlox fuzz`.
+//!
+//! The fuzzer needs no external tooling: it mutates the scripts of a corpus, the golden tests by
+//! default, with byte edits and Lox tokens, and feeds them to a target. Errors are expected,
+//! most inputs are not valid Lox, but every panic is a bug. An input that panics is saved to the
+//! regression directory of its target, which is replayed before fuzzing so that fixed crashes
+//! stay fixed.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    bytecode::FunctionObj,
+    compiler::Compiler,
+    golden,
+    parser::Parser,
+    resolver,
+    scanner::Scanner,
+    token::TokenKind,
+    vm::{VmConfig, VM},
+    CheckFailed, OptLevel, Result,
+};
+
+/// Where the inputs that made a target panic are kept, one directory per target.
+pub const REGRESSIONS: &str = "fuzz/regressions";
+
+// longer inputs are truncated, they are slower without finding more
+const MAX_INPUT: usize = 4096;
+// instructions a program may run before the VM stops it
+const BUDGET: u64 = 100_000;
+const MEMORY: usize = 1 << 24;
+
+// Inserted by the mutator, so that edits make new programs rather than only new errors.
+const TOKENS: [&str; 38] = [
+    "(",
+    ")",
+    "{",
+    "}",
+    ";",
+    ",",
+    ".",
+    "+",
+    "-",
+    "*",
+    "/",
+    "!",
+    "=",
+    "==",
+    "!=",
+    "<",
+    ">=",
+    "\"",
+    "//",
+    "\n",
+    " ",
+    "and",
+    "or",
+    "if",
+    "else",
+    "while",
+    "for",
+    "fun",
+    "return",
+    "var",
+    "print",
+    "test",
+    "nil",
+    "true",
+    "0",
+    "1.5",
+    "x",
+    "assert_eq",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// Arbitrary bytes, as text, to `Scanner::scan_token`.
+    Scanner,
+    /// Arbitrary source to the parser, the resolver and `Compiler::compile`.
+    Compiler,
+    /// Programs that compile to `VM::run`, under an instruction and memory budget.
+    Vm,
+}
+
+impl Target {
+    pub const ALL: [Target; 3] = [Target::Scanner, Target::Compiler, Target::Vm];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Target::Scanner => "scanner",
+            Target::Compiler => "compiler",
+            Target::Vm => "vm",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|target| target.name() == name)
+    }
+
+    /// Feeds `input` to the target. Whether it is valid doesn't matter, only panics do.
+    pub fn run(self, input: &[u8]) {
+        let source = String::from_utf8_lossy(input);
+        match self {
+            Target::Scanner => scan(&source),
+            Target::Compiler => {
+                compile(&source, OptLevel::Fold);
+            }
+            Target::Vm => {
+                for level in [OptLevel::None, OptLevel::Fold] {
+                    if let Some(code) = compile(&source, level) {
+                        execute(code);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn scan(source: &str) {
+    let mut scanner = Scanner::new(source);
+    // every token and every error consumes at least a byte
+    for _ in 0..=source.len() {
+        if let Ok(token) = scanner.scan_token() {
+            if token.kind() == TokenKind::Eof {
+                return;
+            }
+        }
+    }
+    panic!("the scanner made no progress");
+}
+
+fn compile(source: &str, level: OptLevel) -> Option<FunctionObj> {
+    let (program, errors) = Parser::with_source(source).parse_recovering();
+    resolver::resolve(source, &program);
+    if !errors.is_empty() || !Compiler::main_compiler().check(&program).is_empty() {
+        return None;
+    }
+    Compiler::main_compiler()
+        .with_opt_level(level)
+        .compile(&program)
+        .ok()
+}
+
+fn execute(code: FunctionObj) {
+    let config = VmConfig {
+        max_instructions: Some(BUDGET),
+        max_memory: Some(MEMORY),
+        ..VmConfig::default()
+    };
+    let mut vm = VM::with_config(code, config);
+    vm.set_output(Box::new(io::sink()));
+    // runtime errors are fine
+    let _ = vm.run();
+}
+
+// Runs the target and returns the panic message if it panicked.
+fn panics(target: Target, input: &[u8]) -> Option<String> {
+    let payload = panic::catch_unwind(AssertUnwindSafe(|| target.run(input))).err()?;
+    Some(match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "panicked".to_string(),
+        },
+    })
+}
+
+// A xorshift generator, random enough to pick mutations.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    // A number in `0..n`, `n` has to be positive.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+// `input` with a few random edits, some of them taking bytes from other `corpus` entries.
+fn mutate(input: &[u8], corpus: &[Vec<u8>], rng: &mut Rng) -> Vec<u8> {
+    let mut out = input.to_vec();
+    for _ in 0..=rng.below(4) {
+        let at = rng.below(out.len() + 1);
+        let len = 1 + rng.below(16);
+        match rng.below(5) {
+            0 if at < out.len() => out[at] = rng.next() as u8,
+            1 => {
+                let token = TOKENS[rng.below(TOKENS.len())];
+                out.splice(at..at, token.bytes());
+            }
+            2 => {
+                out.drain(at..(at + len).min(out.len()));
+            }
+            3 if !out.is_empty() => {
+                let from = rng.below(out.len());
+                let copy = out[from..(from + len).min(out.len())].to_vec();
+                out.splice(at..at, copy);
+            }
+            _ => {
+                let other = &corpus[rng.below(corpus.len())];
+                let from = rng.below(other.len() + 1);
+                let copy = &other[from..(from + len * 4).min(other.len())];
+                out.splice(at..at, copy.iter().copied());
+            }
+        }
+    }
+    out.truncate(MAX_INPUT);
+    out
+}
+
+// The files in `dir`, sorted, none if it doesn't exist.
+fn regressions(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut files = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    files.sort();
+    Ok(files)
+}
+
+// Fuzzes `target`, returning whether it panicked.
+fn fuzz(target: Target, corpus: &[Vec<u8>], iterations: usize, rng: &mut Rng) -> Result<bool> {
+    let dir = Path::new(REGRESSIONS).join(target.name());
+    let mut failed = false;
+    for file in regressions(&dir)? {
+        if let Some(message) = panics(target, &fs::read(&file)?) {
+            println!(
+                "{}: {} still panics: {}",
+                target.name(),
+                file.display(),
+                message
+            );
+            failed = true;
+        }
+    }
+
+    for _ in 0..iterations {
+        let input = mutate(&corpus[rng.below(corpus.len())], corpus, rng);
+        let Some(message) = panics(target, &input) else {
+            continue;
+        };
+        let mut hasher = DefaultHasher::new();
+        input.hash(&mut hasher);
+        fs::create_dir_all(&dir)?;
+        let file = dir.join(format!("crash-{:016x}.lox", hasher.finish()));
+        fs::write(&file, &input)?;
+        println!("{}: panicked: {}", target.name(), message);
+        println!("{}: input saved to {}", target.name(), file.display());
+        // the same bug would be found again and again
+        return Ok(true);
+    }
+    println!("{}: {} inputs, no panics", target.name(), iterations);
+    Ok(failed)
+}
+
+/// Fuzzes `targets` with `iterations` mutations of the scripts at `corpus` each. The same
+/// `seed` makes the same inputs. It is an error for any target to panic.
+pub fn run(targets: &[Target], iterations: usize, seed: Option<u64>, corpus: &str) -> Result<()> {
+    let mut scripts = Vec::new();
+    golden::scripts(Path::new(corpus), &mut scripts)?;
+    let inputs = scripts
+        .iter()
+        .map(fs::read)
+        .collect::<io::Result<Vec<_>>>()?;
+    if inputs.is_empty() {
+        return Err(format!("No scripts to fuzz with in {}", corpus).into());
+    }
+    let seed = seed.unwrap_or_else(|| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        now.as_nanos() as u64
+    });
+    println!("seed {}", seed);
+
+    let targets = targets.to_vec();
+    // panics are reported with the input that caused them, not by the default hook
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    // on the default stack of a thread, no larger than the main thread's, so that an input
+    // nesting past what the parser's limit protects overflows here as it would when run
+    let fuzzer = thread::Builder::new().spawn(move || -> std::result::Result<usize, String> {
+        // xorshift never leaves zero
+        let mut rng = Rng(seed | 1);
+        let mut failed = 0;
+        for target in targets {
+            if fuzz(target, &inputs, iterations, &mut rng).map_err(|e| e.to_string())? {
+                failed += 1;
+            }
+        }
+        Ok(failed)
+    })?;
+    let failed = fuzzer.join();
+    panic::set_hook(hook);
+    match failed {
+        Ok(Ok(0)) => Ok(()),
+        Ok(Ok(failed)) => Err(CheckFailed(format!("{} fuzz targets panicked", failed)).into()),
+        Ok(Err(error)) => Err(error.into()),
+        Err(_) => Err("The fuzzer itself panicked".into()),
+    }
+}
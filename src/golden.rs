@@ -0,0 +1,299 @@
+//! Running annotated scripts as tests, behind `rlox test`.
+//!
+//! A script states what it should do in comments, following the craftinginterpreters test
+//! suite:
+//!
+//! - `// expect: <text>` is a line it prints, in order,
+//! - `// expect runtime error: <message>` is the runtime error it stops with, on that line,
+//! - `// [line N] Error<...>: <message>` is a compile error on line `N`, `// Error...` one on
+//!   the line of the comment. The message only has to start the actual one, which goes on
+//!   with where the error is.
+
+use std::{fs, path::Path};
+
+use crate::{
+    bytecode::FunctionObj,
+    compiler::Compiler,
+    diagnostic::Diagnostic,
+    parser::Parser,
+    reference::Interpreter,
+    vm::{RuntimeError, VM},
+    CheckFailed, OptLevel, Options, Result,
+};
+
+/// What a script is expected to do.
+#[derive(Debug, Default)]
+struct Expectations {
+    output: Vec<(usize, String)>,
+    // (line, the start of the message)
+    compile_errors: Vec<(usize, String)>,
+    runtime_error: Option<(usize, String)>,
+}
+
+fn expectations(source: &str) -> Expectations {
+    let mut expected = Expectations::default();
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        if let Some((_, output)) = text.split_once("// expect: ") {
+            expected.output.push((line, output.to_string()));
+        } else if let Some((_, message)) = text.split_once("// expect runtime error: ") {
+            expected.runtime_error = Some((line, message.to_string()));
+        } else if let Some((_, error)) = text.split_once("// [line ") {
+            let Some((n, error)) = error.split_once(']') else {
+                continue;
+            };
+            if let (Ok(n), Some(message)) = (n.parse(), error_message(error)) {
+                expected.compile_errors.push((n, message));
+            }
+        } else if let Some((_, error)) = text.split_once("// ") {
+            if let Some(message) = error_message(error) {
+                expected.compile_errors.push((line, message));
+            }
+        }
+    }
+    expected
+}
+
+// The message of ` Error at 'x': message`, empty when it doesn't give one.
+fn error_message(error: &str) -> Option<String> {
+    let error = error.trim_start().strip_prefix("Error")?;
+    Some(match error.split_once(": ") {
+        Some((_, message)) => message.trim_end().to_string(),
+        None => String::new(),
+    })
+}
+
+// Compiles without printing the errors, they are compared with the expected ones instead.
+fn compile(source: &str, options: &Options) -> std::result::Result<FunctionObj, Vec<Diagnostic>> {
+    let (program, errors) = Parser::with_source(source).parse_recovering();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let compiler = || Compiler::main_compiler().with_max_locals(options.vm.max_locals);
+    let errors = compiler().check(&program);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    compiler()
+        .with_opt_level(options.opt_level)
+        .compile(&program)
+        .map_err(|error| vec![Diagnostic::at_line(error.to_string(), 0)])
+}
+
+/// Runs the script at `path` and returns how it failed its expectations, empty if it passed.
+pub fn check_file(path: &Path, options: &Options) -> Result<Vec<String>> {
+    let source = fs::read_to_string(path)?;
+    let expected = expectations(&source);
+    let mut failures = Vec::new();
+
+    let code = match compile(&source, options) {
+        Ok(code) => Some(code),
+        Err(errors) => {
+            let mut unmatched: Vec<_> = errors.iter().collect();
+            for (line, message) in expected.compile_errors.iter() {
+                let found = unmatched.iter().position(|error| {
+                    error.line() == *line && error.message().starts_with(message.as_str())
+                });
+                match found {
+                    Some(i) => {
+                        unmatched.remove(i);
+                    }
+                    None => failures.push(format!(
+                        "missing compile error: [line {}] Error: {}",
+                        line, message
+                    )),
+                }
+            }
+            for error in unmatched {
+                failures.push(format!(
+                    "unexpected compile error: [line {}] Error: {}",
+                    error.line(),
+                    error.message()
+                ));
+            }
+            None
+        }
+    };
+    let Some(code) = code else {
+        return Ok(failures);
+    };
+    for (line, message) in expected.compile_errors.iter() {
+        failures.push(format!(
+            "missing compile error: [line {}] Error: {}",
+            line, message
+        ));
+    }
+
+    let mut printed = Vec::new();
+    let result = {
+        let mut vm = VM::with_config(code, options.vm);
+        vm.set_output(Box::new(&mut printed));
+        vm.run()
+    };
+
+    let printed = String::from_utf8_lossy(&printed);
+    let mut printed = printed.lines();
+    for (line, output) in expected.output.iter() {
+        match printed.next() {
+            Some(actual) if actual == output => {}
+            Some(actual) => failures.push(format!(
+                "expected output '{}' (line {}), got '{}'",
+                output, line, actual
+            )),
+            None => failures.push(format!("missing output '{}' (line {})", output, line)),
+        }
+    }
+    for actual in printed {
+        failures.push(format!("unexpected output '{}'", actual));
+    }
+
+    let actual = match &result {
+        Ok(()) => None,
+        Err(error) => Some(match error.downcast_ref::<RuntimeError>() {
+            Some(error) => {
+                let line = error.trace().first().map_or(0, |(line, _)| *line);
+                (line, error.message().to_string())
+            }
+            None => (0, error.to_string()),
+        }),
+    };
+    match (&expected.runtime_error, actual) {
+        (None, None) => {}
+        (Some(expected), Some(actual)) if *expected == actual => {}
+        (Some((line, message)), Some((actual_line, actual))) => failures.push(format!(
+            "expected runtime error '{}' (line {}), got '{}' on line {}",
+            message, line, actual, actual_line
+        )),
+        (Some((line, message)), None) => failures.push(format!(
+            "missing runtime error '{}' (line {})",
+            message, line
+        )),
+        (None, Some((line, message))) => failures.push(format!(
+            "unexpected runtime error '{}' on line {}",
+            message, line
+        )),
+    }
+    Ok(failures)
+}
+
+// The `.lox` files at `path`, searched recursively when it is a directory, sorted.
+pub(crate) fn scripts(path: &Path, found: &mut Vec<std::path::PathBuf>) -> Result<()> {
+    if !path.is_dir() {
+        found.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = fs::read_dir(path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    for entry in entries {
+        if entry.is_dir() {
+            scripts(&entry, found)?;
+        } else if entry.extension().is_some_and(|ext| ext == "lox") {
+            found.push(entry);
+        }
+    }
+    Ok(())
+}
+
+// What a run printed and the error it stopped with, if any.
+fn outcome(printed: &[u8], result: Result<()>) -> (String, Option<String>) {
+    let error = result
+        .err()
+        .map(|error| match error.downcast_ref::<RuntimeError>() {
+            Some(error) => format!("{:?}: {}", error.kind(), error.message()),
+            None => error.to_string(),
+        });
+    (String::from_utf8_lossy(printed).into_owned(), error)
+}
+
+/// Runs the script at `path` on the VM, at every optimization level, and on the reference
+/// interpreter, and returns where they disagree: in what they print or in how they fail.
+/// Scripts that don't compile have nothing to compare, both share the parser.
+pub fn compare_file(path: &Path, options: &Options) -> Result<Vec<String>> {
+    let source = fs::read_to_string(path)?;
+    let (program, errors) = Parser::with_source(&source).parse_recovering();
+    let compiler = || Compiler::main_compiler().with_max_locals(options.vm.max_locals);
+    if !errors.is_empty() || !compiler().check(&program).is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut printed = Vec::new();
+    let result = Interpreter::new(Box::new(&mut printed), options.vm).run(&program);
+    let (expected_output, expected_error) = outcome(&printed, result);
+
+    let mut failures = Vec::new();
+    for level in 0..=2 {
+        let opt_level = OptLevel::from_level(level).expect("a valid level");
+        let code = compiler().with_opt_level(opt_level).compile(&program)?;
+        let mut printed = Vec::new();
+        let result = {
+            let mut vm = VM::with_config(code, options.vm);
+            vm.set_output(Box::new(&mut printed));
+            vm.run()
+        };
+        let (output, error) = outcome(&printed, result);
+
+        let mut lines = output.lines();
+        let mut expected_lines = expected_output.lines();
+        for n in 1.. {
+            match (lines.next(), expected_lines.next()) {
+                (None, None) => break,
+                (actual, expected) if actual == expected => continue,
+                (actual, expected) => {
+                    failures.push(format!(
+                        "-O{}: output line {} is '{}', the reference printed '{}'",
+                        level,
+                        n,
+                        actual.unwrap_or("<none>"),
+                        expected.unwrap_or("<none>")
+                    ));
+                    break;
+                }
+            }
+        }
+        if error != expected_error {
+            let show = |error: Option<String>| error.unwrap_or_else(|| "no error".to_string());
+            failures.push(format!(
+                "-O{}: the VM ended with {}, the reference with {}",
+                level,
+                show(error),
+                show(expected_error.clone())
+            ));
+        }
+    }
+    Ok(failures)
+}
+
+/// Checks every script at `path`, a file or a directory, and prints the failures along with a
+/// summary. It is an error for any script to fail. With `differential` the scripts are
+/// compared with the reference interpreter instead of their annotations, see [`compare_file`].
+pub fn run(path: &str, differential: bool, options: &Options) -> Result<()> {
+    let mut found = Vec::new();
+    scripts(Path::new(path), &mut found)?;
+
+    let mut failed = 0;
+    for script in found.iter() {
+        let failures = match differential {
+            true => compare_file(script, options),
+            false => check_file(script, options),
+        };
+        let failures = failures.unwrap_or_else(|error| vec![format!("could not run: {}", error)]);
+        if failures.is_empty() {
+            println!("PASS {}", script.display());
+            continue;
+        }
+        failed += 1;
+        println!("FAIL {}", script.display());
+        for failure in failures {
+            println!("    {}", failure);
+        }
+    }
+
+    println!();
+    println!("{} passed, {} failed", found.len() - failed, failed);
+    if failed > 0 {
+        return Err(CheckFailed(format!("{} of {} tests failed", failed, found.len())).into());
+    }
+    Ok(())
+}
@@ -1,46 +1,416 @@
 use std::{
-    cell::RefCell,
-    fs,
-    io::{self, Write},
+    fmt, fs,
+    io::{self, Read, Write},
+    rc::Rc,
 };
 
+pub mod ast;
+pub mod bench;
 pub mod bytecode;
 pub mod compiler;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod diagnostic;
+pub mod disasm;
+pub mod formatter;
+pub mod fuzz;
+pub mod golden;
+pub mod json;
+pub mod loxc;
+pub mod lsp;
+pub mod natives;
+pub mod optimizer;
+pub mod parser;
+pub mod profiler;
+pub mod reference;
+pub mod resolver;
 pub mod scanner;
 pub mod token;
+pub mod verifier;
 pub mod vm;
 
+pub use optimizer::OptLevel;
+pub use vm::{InterruptHandle, RuntimeErrorKind, VmConfig};
+
 pub type Error = Box<dyn std::error::Error>;
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub fn run_repl() -> Result<()> {
+/// A check that was asked for didn't pass, like a failing test or an unformatted file, rather
+/// than the script being wrong. See [`exit_code`].
+#[derive(Debug)]
+pub struct CheckFailed(pub String);
+
+impl fmt::Display for CheckFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CheckFailed {}
+
+/// The exit code of the command line for `error`:
+///
+/// - 1: a check failed, see [`CheckFailed`],
+/// - 65: the script doesn't compile, or isn't valid bytecode,
+/// - 70: the script raised a runtime error,
+/// - 71: the script ran into a limit of the VM: frames, stack, memory or instructions,
+/// - 74: a file couldn't be read or written,
+/// - 130: the script was interrupted.
+///
+/// Usage errors exit with 64 before anything runs.
+pub fn exit_code(error: &Error) -> i32 {
+    if error.is::<CheckFailed>() {
+        return 1;
+    }
+    if error.is::<io::Error>() {
+        return 74;
+    }
+    match error
+        .downcast_ref::<vm::RuntimeError>()
+        .map(|error| error.kind())
+    {
+        Some(RuntimeErrorKind::Script) => 70,
+        Some(
+            RuntimeErrorKind::StackOverflow
+            | RuntimeErrorKind::OutOfMemory
+            | RuntimeErrorKind::BudgetExhausted,
+        ) => 71,
+        Some(RuntimeErrorKind::Interrupted) => 130,
+        // everything else comes from the compiler, the verifier or the bytecode loader
+        None => 65,
+    }
+}
+
+/// Settings for compiling and running a script.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+    pub vm: VmConfig,
+    pub opt_level: OptLevel,
+    /// Print the stack and every instruction to stderr as the script runs.
+    pub trace: bool,
+    /// Profile the script as it runs, see [`profiler`].
+    pub profile: bool,
+    /// Record the lines and branches the script runs, see [`coverage`].
+    pub coverage: bool,
+    /// Print the resolver's warnings to stderr as scripts compile, see [`resolver`].
+    pub warnings: bool,
+}
+
+pub fn run_repl(options: &Options) -> Result<()> {
+    let interrupt = InterruptHandle::new();
+    sigint::install(&interrupt);
+
     loop {
         print!("> ");
         io::stdout().flush()?;
         let mut line = String::new();
-        io::stdin().read_line(&mut line)?;
+        if io::stdin().read_line(&mut line)? == 0 {
+            // EOF
+            break;
+        }
         if matches!(line.trim(), "quit" | "q!") {
             break;
         }
 
-        if let Err(error) = interpret(line) {
+        interrupt.reset();
+        sigint::set_running(true);
+        let result = compile(&line, options).and_then(|code| {
+            let mut vm = new_vm(code, options);
+            vm.set_interrupt_handle(interrupt.clone());
+            vm.run()
+        });
+        sigint::set_running(false);
+
+        if let Err(error) = result {
             eprintln!("error: {}", error);
         }
     }
     Ok(())
 }
 
-pub fn run_file(path: String) -> Result<()> {
-    fs::read_to_string(path)
-        .map_err(|e| e.into())
-        .and_then(interpret)
+/// Runs a script, either source code or a precompiled `.loxc` file.
+pub fn run_file(path: String, options: &Options) -> Result<()> {
+    if options.profile || options.coverage {
+        return run_instrumented(&path, options);
+    }
+    let code = load_file(&path, options)?;
+    let mut vm = new_vm(code, options);
+    vm.run()
+}
+
+/// Runs the script at `path` under the profiler and coverage its options ask for, then writes
+/// what they found next to it. The profile report goes to stderr, the stacks for flamegraph
+/// tools to `<path>.folded` and the coverage to `<path>.lcov`.
+pub fn run_instrumented(path: &str, options: &Options) -> Result<()> {
+    let code = load_file(path, options)?;
+    let mut profiler = options.profile.then(profiler::Profiler::new);
+    let mut coverage = None;
+    let mut vm = vm::VM::with_config(code, options.vm);
+    if options.coverage {
+        // the functions as the VM holds them, coverage finds them by address
+        coverage = Some(coverage::Coverage::new(vm.call_stack()[0].function));
+    }
+    vm.set_tracer(Some(Box::new(|trace| {
+        if options.trace {
+            eprintln!("{}", trace);
+        }
+        if let Some(profiler) = profiler.as_mut() {
+            profiler.record(trace);
+        }
+        if let Some(coverage) = coverage.as_mut() {
+            coverage.record(trace);
+        }
+    })));
+    let result = vm.run();
+    drop(vm);
+
+    // a failed script still has a profile and coverage up to the failure
+    if let Some(mut profiler) = profiler {
+        profiler.finish();
+        let folded = format!("{}.folded", path);
+        fs::write(&folded, profiler.folded())?;
+        eprint!("{}", profiler.report());
+        eprintln!("\nFolded stacks written to {}", folded);
+    }
+    if let Some(coverage) = coverage {
+        let lcov = format!("{}.lcov", path);
+        fs::write(&lcov, coverage.to_lcov(path))?;
+        eprintln!("Coverage written to {}", lcov);
+    }
+    result
+}
+
+/// Runs the script at `path`, then each of its `test` blocks, and reports how they went. It is
+/// an error for any test to fail.
+pub fn test_file(path: &str, options: &Options) -> Result<()> {
+    let code = load_file(path, options)?;
+    let tests: Vec<_> = code
+        .chunk()
+        .constants()
+        .iter()
+        .filter_map(|constant| match constant {
+            bytecode::Value::Function(fun) if fun.test_name().is_some() => Some(Rc::clone(fun)),
+            _ => None,
+        })
+        .collect();
+
+    // the script itself defines what the tests use
+    let mut vm = new_vm(code, options);
+    vm.run()?;
+
+    let mut failed = 0;
+    for test in tests.iter() {
+        let name = test.test_name().unwrap_or_default();
+        let Err(error) = vm.run_function(Rc::clone(test)) else {
+            println!("test {} ... ok", name);
+            continue;
+        };
+        failed += 1;
+        println!("test {} ... FAILED", name);
+        match error.downcast_ref::<vm::RuntimeError>() {
+            Some(error) => {
+                let line = error.trace().first().map_or(0, |(line, _)| *line);
+                println!("    [line {}] {}", line, error.message());
+            }
+            None => println!("    {}", error),
+        }
+    }
+
+    println!();
+    println!("{} passed, {} failed", tests.len() - failed, failed);
+    if failed > 0 {
+        return Err(CheckFailed(format!("{} of {} tests failed", failed, tests.len())).into());
+    }
+    Ok(())
+}
+
+/// Runs the script at `path` under the interactive debugger.
+pub fn debug_file(path: &str, options: &Options) -> Result<()> {
+    let code = load_file(path, options)?;
+    // precompiled files have no source to show
+    let source = fs::read_to_string(path)
+        .ok()
+        .filter(|source| !loxc::is_precompiled(source.as_bytes()));
+    debugger::run_cli(code, source.as_deref(), options)
+}
+
+/// The path that stands for stdin, see [`read_script`].
+pub const STDIN: &str = "-";
+
+/// Reads the script at `path`, or stdin if `path` is `-`.
+pub fn read_script(path: &str) -> Result<Vec<u8>> {
+    if path == STDIN {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes)?;
+        return Ok(bytes);
+    }
+    Ok(fs::read(path)?)
+}
+
+/// Compiles the script at `path`, or reads it back if it is a precompiled `.loxc` file.
+pub fn load_file(path: &str, options: &Options) -> Result<bytecode::FunctionObj> {
+    load(path, read_script(path)?, options)
+}
+
+// Compiles `bytes` read from `path`, or decodes them if they are precompiled.
+fn load(path: &str, bytes: Vec<u8>, options: &Options) -> Result<bytecode::FunctionObj> {
+    if loxc::is_precompiled(&bytes) || path.ends_with(&format!(".{}", loxc::EXTENSION)) {
+        return loxc::decode(&bytes);
+    }
+    let source = String::from_utf8(bytes).map_err(|_| format!("{} is not valid UTF-8", path))?;
+    compile(&source, options)
+}
+
+/// Compiles the script at `path` into the precompiled file `output`.
+pub fn compile_file(path: &str, output: &str, options: &Options) -> Result<()> {
+    let source = fs::read_to_string(path)?;
+    let code = compile(&source, options)?;
+    fs::write(output, loxc::encode(&code))?;
+    Ok(())
+}
+
+/// Formats the script at `path` in place. With `check` the file is left alone, and it is an
+/// error for it not to be formatted already.
+pub fn format_file(path: &str, check: bool) -> Result<()> {
+    let source = fs::read_to_string(path)?;
+    let formatted = formatter::format(&source)?;
+    if formatted == source {
+        return Ok(());
+    }
+    if check {
+        return Err(CheckFailed(format!(
+            "{} is not formatted, run 'rlox fmt {}'",
+            path, path
+        ))
+        .into());
+    }
+    fs::write(path, formatted)?;
+    Ok(())
+}
+
+pub fn compile(source: &str, options: &Options) -> Result<bytecode::FunctionObj> {
+    let program = parser::Parser::with_source(source).parse()?;
+    if options.warnings {
+        for warning in resolver::resolve(source, &program) {
+            eprintln!("{}", warning);
+        }
+    }
+    let compiler = compiler::Compiler::main_compiler()
+        .with_max_locals(options.vm.max_locals)
+        .with_opt_level(options.opt_level);
+
+    compiler.compile(&program)
+}
+
+/// What `--dump-tokens` and `--dump-bytecode` print in place of running a script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dump {
+    Tokens,
+    Bytecode,
+}
+
+/// Prints the tokens or the bytecode of the script `path`, whose contents are `bytes`.
+pub fn dump(path: &str, bytes: Vec<u8>, dump: Dump, options: &Options) -> Result<()> {
+    match dump {
+        Dump::Tokens => {
+            let source =
+                String::from_utf8(bytes).map_err(|_| format!("{} is not valid UTF-8", path))?;
+            dump_tokens(&source)
+        }
+        Dump::Bytecode => {
+            print!("{}", disasm::to_text(&load(path, bytes, options)?));
+            Ok(())
+        }
+    }
 }
 
-fn interpret(source: String) -> Result<()> {
-    let parser = RefCell::new(compiler::Parser::with_source(&source));
-    let compiler = compiler::Compiler::main_compiler(&parser);
+/// Prints the tokens of `source`, one per line with the line they are on. Scanning goes on
+/// after an error, it is only an error once all of them were printed.
+pub fn dump_tokens(source: &str) -> Result<()> {
+    let mut scanner = scanner::Scanner::new(source);
+    let mut errors = 0;
+    loop {
+        match scanner.scan_token() {
+            Ok(token) => {
+                println!("{:>4} {:?}", token.line(), token.kind());
+                if token.kind() == token::TokenKind::Eof {
+                    break;
+                }
+            }
+            Err(error) => {
+                eprintln!("{}", error);
+                errors += 1;
+            }
+        }
+    }
+    if errors > 0 {
+        return Err(format!("{} errors while scanning", errors).into());
+    }
+    Ok(())
+}
 
-    let code = compiler.compile()?;
-    let mut vm = vm::VM::with_code(code);
+pub fn interpret(source: String, options: &Options) -> Result<()> {
+    let code = compile(&source, options)?;
+    let mut vm = new_vm(code, options);
     vm.run()
 }
+
+fn new_vm(code: bytecode::FunctionObj, options: &Options) -> vm::VM<'static> {
+    let mut vm = vm::VM::with_config(code, options.vm);
+    if options.trace {
+        vm.set_tracer(Some(Box::new(|trace| eprintln!("{}", trace))));
+    }
+    vm
+}
+
+// Ctrl-C in the REPL interrupts the running snippet instead of killing the session.
+// While waiting for input it still exits, like it would without a handler.
+#[cfg(unix)]
+mod sigint {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    };
+
+    use crate::InterruptHandle;
+
+    const SIGINT: i32 = 2;
+
+    static HANDLE: OnceLock<InterruptHandle> = OnceLock::new();
+    static RUNNING: AtomicBool = AtomicBool::new(false);
+
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+        fn _exit(status: i32) -> !;
+    }
+
+    extern "C" fn on_sigint(signum: i32) {
+        match HANDLE.get() {
+            Some(handle) if RUNNING.load(Ordering::Relaxed) => handle.interrupt(),
+            // only async-signal-safe functions may be called here
+            _ => unsafe { _exit(128 + signum) },
+        }
+    }
+
+    pub fn install(handle: &InterruptHandle) {
+        if HANDLE.set(handle.clone()).is_ok() {
+            unsafe {
+                signal(SIGINT, on_sigint);
+            }
+        }
+    }
+
+    pub fn set_running(running: bool) {
+        RUNNING.store(running, Ordering::Relaxed);
+    }
+}
+
+#[cfg(not(unix))]
+mod sigint {
+    use crate::InterruptHandle;
+
+    pub fn install(_handle: &InterruptHandle) {}
+
+    pub fn set_running(_running: bool) {}
+}
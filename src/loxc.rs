@@ -0,0 +1,306 @@
+//! Precompiled `.loxc` files.
+//!
+//! A file is the header followed by the main function:
+//!
+//! ```text
+//! magic    "LOXC"
+//! version  u16 little-endian, see `FORMAT_VERSION`
+//! checksum u32 little-endian, CRC-32 of the payload
+//! payload  function
+//! ```
+//!
+//! A function is its name, arity, declaration line, global names, code, constants, line runs
+//! and local names with their slot and code range. Lengths and counts are unsigned LEB128
+//! varints, strings are a length followed by UTF-8 bytes. Each constant starts with a tag byte,
+//! nested functions are stored inline after theirs.
+
+use std::rc::Rc;
+
+use crate::{
+    bytecode::{Chunk, FunctionObj, LineTable, LocalVar, Value},
+    Error, Result,
+};
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+/// Bumped whenever the layout or the instruction encoding changes.
+pub const FORMAT_VERSION: u16 = 3;
+pub const EXTENSION: &str = "loxc";
+
+const HEADER_SIZE: usize = MAGIC.len() + 2 + 4;
+// deeper nesting than any real script has, but keeps a crafted file from overflowing the stack
+const MAX_NESTING: usize = 255;
+
+mod constant {
+    pub const NUMBER: u8 = 0;
+    pub const STRING: u8 = 1;
+    pub const FUNCTION: u8 = 2;
+    pub const TRUE: u8 = 3;
+    pub const FALSE: u8 = 4;
+    pub const NIL: u8 = 5;
+}
+
+/// Whether `bytes` start like a precompiled file.
+pub fn is_precompiled(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Serializes `fun` and the functions nested in it.
+pub fn encode(fun: &FunctionObj) -> Vec<u8> {
+    let mut payload = Vec::new();
+    write_function(&mut payload, fun);
+
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&crc32(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+/// Reads back a function written by [`encode`], rejecting files that are truncated, corrupt
+/// or from another format version.
+pub fn decode(bytes: &[u8]) -> Result<FunctionObj> {
+    if !is_precompiled(bytes) {
+        return Err(Error::from("Not a precompiled rlox file"));
+    }
+    if bytes.len() < HEADER_SIZE {
+        return Err(corrupt("truncated header"));
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != FORMAT_VERSION {
+        return Err(Error::from(format!(
+            "Unsupported .loxc format version {}, this rlox reads version {}",
+            version, FORMAT_VERSION
+        )));
+    }
+    let checksum = u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+    let payload = &bytes[HEADER_SIZE..];
+    if crc32(payload) != checksum {
+        return Err(corrupt("checksum mismatch"));
+    }
+
+    let mut reader = Reader {
+        bytes: payload,
+        pos: 0,
+    };
+    let fun = reader.function(0)?;
+    if reader.pos != payload.len() {
+        return Err(corrupt("trailing bytes after the main function"));
+    }
+    Ok(fun)
+}
+
+fn corrupt(what: &str) -> Error {
+    Error::from(format!("Corrupt .loxc file: {}", what))
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_varint(out, s.len() as u64);
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_function(out: &mut Vec<u8>, fun: &FunctionObj) {
+    write_str(out, fun.name());
+    out.push(fun.arity());
+    write_varint(out, fun.line() as u64);
+
+    write_varint(out, fun.global_names().len() as u64);
+    for name in fun.global_names() {
+        write_str(out, name);
+    }
+
+    let chunk = fun.chunk();
+    write_varint(out, chunk.len() as u64);
+    out.extend_from_slice(chunk.code());
+
+    write_varint(out, chunk.constants().len() as u64);
+    for value in chunk.constants() {
+        match value {
+            Value::Number(n) => {
+                out.push(constant::NUMBER);
+                out.extend_from_slice(&n.to_bits().to_le_bytes());
+            }
+            Value::String(s) => {
+                out.push(constant::STRING);
+                write_str(out, s);
+            }
+            Value::Function(f) => {
+                out.push(constant::FUNCTION);
+                write_function(out, f);
+            }
+            Value::Boolean(true) => out.push(constant::TRUE),
+            Value::Boolean(false) => out.push(constant::FALSE),
+            Value::Nil => out.push(constant::NIL),
+            Value::Native(_) => unreachable!("natives are bound when the script runs"),
+        }
+    }
+
+    let runs: Vec<_> = chunk.lines().runs().collect();
+    write_varint(out, runs.len() as u64);
+    for (start, line) in runs {
+        write_varint(out, start as u64);
+        write_varint(out, line as u64);
+    }
+
+    write_varint(out, fun.locals().len() as u64);
+    for local in fun.locals() {
+        write_str(out, &local.name);
+        write_varint(out, local.slot as u64);
+        write_varint(out, local.start as u64);
+        write_varint(out, local.end as u64);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.bytes.get(self.pos..end))
+            .ok_or_else(|| corrupt("unexpected end of file"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(corrupt("varint too long"))
+    }
+
+    // A length or count, which can't be larger than the bytes left since every item takes one.
+    fn len(&mut self) -> Result<usize> {
+        let len = self.varint()?;
+        if len > (self.bytes.len() - self.pos) as u64 {
+            return Err(corrupt("length past the end of file"));
+        }
+        Ok(len as usize)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        u32::try_from(self.varint()?).map_err(|_| corrupt("value out of range"))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.len()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| corrupt("invalid UTF-8 in string"))
+    }
+
+    fn function(&mut self, depth: usize) -> Result<FunctionObj> {
+        if depth > MAX_NESTING {
+            return Err(corrupt("functions nested too deeply"));
+        }
+        let name = self.string()?;
+        let arity = self.byte()?;
+        let line = self.u32()? as usize;
+
+        let global_count = self.len()?;
+        let globals = (0..global_count)
+            .map(|_| self.string())
+            .collect::<Result<Vec<_>>>()?;
+
+        let code_len = self.len()?;
+        let code = self.take(code_len)?.to_vec();
+
+        let const_count = self.len()?;
+        let mut constants = Vec::with_capacity(const_count);
+        for _ in 0..const_count {
+            constants.push(self.constant(depth)?);
+        }
+
+        let run_count = self.len()?;
+        let mut lines = LineTable::new();
+        let mut next_start = 0;
+        for i in 0..run_count {
+            let start = self.u32()? as usize;
+            let line = self.u32()? as usize;
+            // runs must cover the code from its first byte, in increasing order
+            if (i == 0 && start != 0) || start < next_start || start >= code.len() {
+                return Err(corrupt("invalid line table"));
+            }
+            lines.push(start, line);
+            next_start = start + 1;
+        }
+        if run_count == 0 && !code.is_empty() {
+            return Err(corrupt("missing line table"));
+        }
+
+        let local_count = self.len()?;
+        let mut locals = Vec::with_capacity(local_count);
+        for _ in 0..local_count {
+            let name = self.string()?;
+            let slot =
+                u16::try_from(self.varint()?).map_err(|_| corrupt("local slot out of range"))?;
+            let start = self.u32()? as usize;
+            let end = self.u32()? as usize;
+            if start > end || end > code.len() {
+                return Err(corrupt("invalid local range"));
+            }
+            locals.push(LocalVar {
+                name,
+                slot,
+                start,
+                end,
+            });
+        }
+
+        let mut fun =
+            FunctionObj::with_chunk(name, arity, Chunk::from_parts(code, constants, lines));
+        fun.set_line(line);
+        fun.set_global_names(globals);
+        *fun.locals_mut() = locals;
+        Ok(fun)
+    }
+
+    fn constant(&mut self, depth: usize) -> Result<Value> {
+        Ok(match self.byte()? {
+            constant::NUMBER => {
+                let bytes = self.take(8)?.try_into().unwrap();
+                Value::Number(f64::from_bits(u64::from_le_bytes(bytes)))
+            }
+            constant::STRING => Value::String(Rc::new(self.string()?)),
+            constant::FUNCTION => Value::Function(Rc::new(self.function(depth + 1)?)),
+            constant::TRUE => Value::Boolean(true),
+            constant::FALSE => Value::Boolean(false),
+            constant::NIL => Value::Nil,
+            tag => return Err(corrupt(&format!("unknown constant tag {}", tag))),
+        })
+    }
+}
+
+// CRC-32 (IEEE), computed bitwise since files are small and read once.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
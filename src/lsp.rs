@@ -0,0 +1,426 @@
+//! A Language Server Protocol server, behind `rlox lsp`.
+//!
+//! Documents are synced in full on every change. Each change re-parses the document to publish
+//! its diagnostics, and requests re-parse it too, which is cheap for scripts of any real size.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+};
+
+use crate::{
+    ast::Span,
+    compiler::Compiler,
+    diagnostic::Diagnostic,
+    json::{self, Json},
+    parser::Parser,
+    resolver::{self, Symbol, SymbolKind, Symbols},
+    Options, Result,
+};
+
+const KEYWORDS: [&str; 16] = [
+    "and", "class", "else", "false", "for", "fun", "if", "nil", "or", "print", "return", "super",
+    "this", "true", "var", "while",
+];
+
+// error codes of JSON-RPC
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+
+// LSP enumerations
+mod kind {
+    pub const SEVERITY_ERROR: usize = 1;
+    pub const SEVERITY_WARNING: usize = 2;
+    pub const SYMBOL_FUNCTION: usize = 12;
+    pub const SYMBOL_VARIABLE: usize = 13;
+    pub const COMPLETION_FUNCTION: usize = 3;
+    pub const COMPLETION_VARIABLE: usize = 6;
+    pub const COMPLETION_KEYWORD: usize = 14;
+    pub const SYNC_FULL: usize = 1;
+}
+
+// Converts byte offsets to LSP positions and back, positions count UTF-16 code units.
+struct LineIndex<'a> {
+    text: &'a str,
+    // byte offset where each line starts
+    starts: Vec<usize>,
+}
+
+impl<'a> LineIndex<'a> {
+    fn new(text: &'a str) -> Self {
+        let newlines = text.match_indices('\n').map(|(i, _)| i + 1);
+        Self {
+            text,
+            starts: std::iter::once(0).chain(newlines).collect(),
+        }
+    }
+
+    fn position(&self, offset: usize) -> Json {
+        let line = self.starts.partition_point(|&start| start <= offset) - 1;
+        let start = self.starts[line];
+        let character: usize = self.text[start..offset.min(self.text.len())]
+            .chars()
+            .map(char::len_utf16)
+            .sum();
+        Json::object([("line", line.into()), ("character", character.into())])
+    }
+
+    fn range(&self, start: usize, end: usize) -> Json {
+        Json::object([("start", self.position(start)), ("end", self.position(end))])
+    }
+
+    fn span(&self, span: Span) -> Json {
+        self.range(span.start, span.end)
+    }
+
+    // The whole of a 1-based `line`, for diagnostics that don't know more.
+    fn line(&self, line: usize) -> Json {
+        let line = line.saturating_sub(1).min(self.starts.len() - 1);
+        let end = self
+            .starts
+            .get(line + 1)
+            .map_or(self.text.len(), |next| next - 1);
+        self.range(self.starts[line], end)
+    }
+
+    fn offset(&self, position: &Json) -> Option<usize> {
+        let line = position.get("line")?.as_usize()?;
+        let character = position.get("character")?.as_usize()?;
+        let start = *self.starts.get(line)?;
+        let end = self
+            .starts
+            .get(line + 1)
+            .copied()
+            .unwrap_or(self.text.len());
+        let mut units = 0;
+        for (i, ch) in self.text[start..end].char_indices() {
+            if units >= character {
+                return Some(start + i);
+            }
+            units += ch.len_utf16();
+        }
+        Some(end)
+    }
+}
+
+struct Server<W: Write> {
+    output: W,
+    options: Options,
+    // open documents by URI
+    documents: HashMap<String, String>,
+    shutdown: bool,
+}
+
+/// Serves an editor until it sends `exit` or closes `input`.
+pub fn serve(mut input: impl BufRead, output: impl Write, options: &Options) -> Result<()> {
+    let mut server = Server {
+        output,
+        options: *options,
+        documents: HashMap::new(),
+        shutdown: false,
+    };
+    while let Some(message) = json::read_message(&mut input)? {
+        let Some(method) = message.get("method").and_then(Json::as_str) else {
+            // responses to requests we never send
+            continue;
+        };
+        if method == "exit" {
+            break;
+        }
+        let null = Json::Null;
+        let params = message.get("params").unwrap_or(&null);
+        match message.get("id") {
+            Some(id) => {
+                let response = match server.request(method, params) {
+                    Ok(result) => ("result", result),
+                    Err((code, message)) => {
+                        let error = Json::object([
+                            ("code", (code as f64).into()),
+                            ("message", message.into()),
+                        ]);
+                        ("error", error)
+                    }
+                };
+                server.send(vec![("id", id.clone()), response])?;
+            }
+            None => server.notification(method, params)?,
+        }
+    }
+    if !server.shutdown {
+        return Err("The client exited without shutting the server down".into());
+    }
+    Ok(())
+}
+
+// Where a request points in a document.
+fn text_position(params: &Json) -> Option<(&str, &Json)> {
+    let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+    Some((uri, params.get("position")?))
+}
+
+fn describe(symbol: &Symbol, symbols: &Symbols) -> String {
+    let parent = |id: Option<usize>| match id {
+        Some(id) => format!("in {}()", symbols.symbols[id].name.name),
+        None => "global".to_string(),
+    };
+    match symbol.kind {
+        SymbolKind::Function => {
+            let arity = symbol.params.len();
+            format!(
+                "```lox\nfun {}({})\n```\nTakes {} argument{}.",
+                symbol.name.name,
+                symbol.params.join(", "),
+                arity,
+                if arity == 1 { "" } else { "s" }
+            )
+        }
+        SymbolKind::Variable => format!(
+            "```lox\nvar {}\n```\n{} variable",
+            symbol.name.name,
+            parent(symbol.parent)
+        ),
+        SymbolKind::Parameter => format!(
+            "```lox\n{}\n```\nparameter {}",
+            symbol.name.name,
+            parent(symbol.parent)
+        ),
+    }
+}
+
+impl<W: Write> Server<W> {
+    fn send(&mut self, mut fields: Vec<(&str, Json)>) -> Result<()> {
+        fields.insert(0, ("jsonrpc", "2.0".into()));
+        json::write_message(&mut self.output, &Json::object(fields))?;
+        Ok(())
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> Result<()> {
+        let document = params.get("textDocument");
+        let Some(uri) = document
+            .and_then(|doc| doc.get("uri"))
+            .and_then(Json::as_str)
+        else {
+            return Ok(());
+        };
+        match method {
+            "textDocument/didOpen" => {
+                let text = document
+                    .and_then(|doc| doc.get("text"))
+                    .and_then(Json::as_str);
+                self.documents
+                    .insert(uri.to_string(), text.unwrap_or_default().to_string());
+            }
+            "textDocument/didChange" => {
+                // with full sync the last change holds the whole text
+                let text = params
+                    .get("contentChanges")
+                    .and_then(Json::as_array)
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change.get("text"))
+                    .and_then(Json::as_str);
+                if let (Some(text), Some(document)) = (text, self.documents.get_mut(uri)) {
+                    *document = text.to_string();
+                }
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                return self.publish(uri, Vec::new());
+            }
+            _ => return Ok(()),
+        }
+        let Some(text) = self.documents.get(uri) else {
+            // a change to a document that was never opened
+            return Ok(());
+        };
+        let diagnostics = self.diagnostics(text);
+        self.publish(uri, diagnostics)
+    }
+
+    fn publish(&mut self, uri: &str, diagnostics: Vec<Json>) -> Result<()> {
+        let params = Json::object([("uri", uri.into()), ("diagnostics", diagnostics.into())]);
+        self.send(vec![
+            ("method", "textDocument/publishDiagnostics".into()),
+            ("params", params),
+        ])
+    }
+
+    fn diagnostics(&self, text: &str) -> Vec<Json> {
+        let index = LineIndex::new(text);
+        let diagnostic = |range: Json, severity: usize, code: Option<&str>, message: &str| {
+            Json::object([
+                ("range", range),
+                ("severity", severity.into()),
+                ("code", code.into()),
+                ("source", "rlox".into()),
+                ("message", message.into()),
+            ])
+        };
+        let error = |error: &Diagnostic| {
+            let range = match error.span() {
+                Some(span) => index.span(span),
+                None => index.line(error.line()),
+            };
+            diagnostic(range, kind::SEVERITY_ERROR, None, error.message())
+        };
+
+        let (program, errors) = Parser::with_source(text).parse_recovering();
+        if !errors.is_empty() {
+            // the statements that failed to parse are missing, checking the rest would only
+            // report errors about them
+            return errors.iter().map(error).collect();
+        }
+        let errors = Compiler::main_compiler()
+            .with_max_locals(self.options.vm.max_locals)
+            .check(&program);
+        let warnings = resolver::resolve(text, &program);
+        errors
+            .iter()
+            .map(error)
+            .chain(warnings.iter().map(|warning| {
+                diagnostic(
+                    index.span(warning.span),
+                    kind::SEVERITY_WARNING,
+                    Some(warning.lint.name()),
+                    &warning.message,
+                )
+            }))
+            .collect()
+    }
+
+    fn request(&mut self, method: &str, params: &Json) -> std::result::Result<Json, (i32, String)> {
+        let invalid = || (INVALID_PARAMS, format!("Invalid parameters for {}", method));
+        match method {
+            "initialize" => {
+                let capabilities = Json::object([
+                    ("textDocumentSync", kind::SYNC_FULL.into()),
+                    ("definitionProvider", true.into()),
+                    ("hoverProvider", true.into()),
+                    ("documentSymbolProvider", true.into()),
+                    ("completionProvider", Json::object::<&str>([])),
+                ]);
+                let info = Json::object([
+                    ("name", "rlox".into()),
+                    ("version", env!("CARGO_PKG_VERSION").into()),
+                ]);
+                Ok(Json::object([
+                    ("capabilities", capabilities),
+                    ("serverInfo", info),
+                ]))
+            }
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Json::Null)
+            }
+            "textDocument/definition" | "textDocument/hover" => {
+                let (uri, position) = text_position(params).ok_or_else(invalid)?;
+                let Some(text) = self.documents.get(uri) else {
+                    return Ok(Json::Null);
+                };
+                let index = LineIndex::new(text);
+                let offset = index.offset(position).ok_or_else(invalid)?;
+                let (program, _) = Parser::with_source(text).parse_recovering();
+                let symbols = resolver::symbols(&program);
+                let Some(symbol) = symbols.at(offset) else {
+                    return Ok(Json::Null);
+                };
+                let range = index.span(symbol.name.span);
+                Ok(match method {
+                    "textDocument/definition" => {
+                        Json::object([("uri", uri.into()), ("range", range)])
+                    }
+                    _ => {
+                        let contents = Json::object([
+                            ("kind", "markdown".into()),
+                            ("value", describe(symbol, &symbols).into()),
+                        ]);
+                        Json::object([("contents", contents)])
+                    }
+                })
+            }
+            "textDocument/documentSymbol" => {
+                let uri = params
+                    .get("textDocument")
+                    .and_then(|doc| doc.get("uri"))
+                    .and_then(Json::as_str)
+                    .ok_or_else(invalid)?;
+                let Some(text) = self.documents.get(uri) else {
+                    return Ok(Json::Null);
+                };
+                let index = LineIndex::new(text);
+                let (program, _) = Parser::with_source(text).parse_recovering();
+                let symbols = resolver::symbols(&program);
+                Ok(document_symbols(&symbols, None, &index).into())
+            }
+            "textDocument/completion" => {
+                let uri = params
+                    .get("textDocument")
+                    .and_then(|doc| doc.get("uri"))
+                    .and_then(Json::as_str)
+                    .ok_or_else(invalid)?;
+                let text = self.documents.get(uri).map_or("", String::as_str);
+                let (program, _) = Parser::with_source(text).parse_recovering();
+                let symbols = resolver::symbols(&program);
+
+                let mut items: Vec<Json> = KEYWORDS
+                    .iter()
+                    .map(|keyword| {
+                        Json::object([
+                            ("label", (*keyword).into()),
+                            ("kind", kind::COMPLETION_KEYWORD.into()),
+                        ])
+                    })
+                    .collect();
+                let mut seen = Vec::new();
+                for symbol in symbols.symbols.iter() {
+                    if seen.contains(&symbol.name.name) {
+                        continue;
+                    }
+                    seen.push(symbol.name.name);
+                    let (kind, detail) = match symbol.kind {
+                        SymbolKind::Function => (
+                            kind::COMPLETION_FUNCTION,
+                            format!("fun {}({})", symbol.name.name, symbol.params.join(", ")),
+                        ),
+                        _ => (
+                            kind::COMPLETION_VARIABLE,
+                            format!("var {}", symbol.name.name),
+                        ),
+                    };
+                    items.push(Json::object([
+                        ("label", symbol.name.name.into()),
+                        ("kind", kind.into()),
+                        ("detail", detail.into()),
+                    ]));
+                }
+                Ok(items.into())
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("Unsupported method '{}'", method))),
+        }
+    }
+}
+
+// The functions and variables declared in `parent`, with what is declared in them nested.
+fn document_symbols(symbols: &Symbols, parent: Option<usize>, index: &LineIndex) -> Vec<Json> {
+    symbols
+        .symbols
+        .iter()
+        .enumerate()
+        .filter(|(_, symbol)| symbol.parent == parent && symbol.kind != SymbolKind::Parameter)
+        .map(|(id, symbol)| {
+            let (kind, children) = match symbol.kind {
+                SymbolKind::Function => (
+                    kind::SYMBOL_FUNCTION,
+                    document_symbols(symbols, Some(id), index),
+                ),
+                _ => (kind::SYMBOL_VARIABLE, Vec::new()),
+            };
+            Json::object([
+                ("name", symbol.name.name.into()),
+                ("kind", kind.into()),
+                ("range", index.span(symbol.span)),
+                ("selectionRange", index.span(symbol.name.span)),
+                ("children", children.into()),
+            ])
+        })
+        .collect()
+}
@@ -1,18 +1,258 @@
-use std::env;
+use std::{env, io};
+
+use rlox::{Dump, OptLevel, Options};
+
+const USAGE: &str = "Usage: rlox [run] [options] [--profile] [--coverage] [--] [script [args...]]
+       rlox repl [options]
+       rlox eval [options] -e <code> [args...]
+       rlox --test [options] <script>
+       rlox compile [options] <script> [-o <output>]
+       rlox disasm [options] <script> [--json]
+       rlox debug [options] <script>
+       rlox dap [options]
+       rlox lsp [options]
+       rlox fmt <script> [--check]
+       rlox test [options] <path> [--differential]
+       rlox bench [options] [path] [-n <runs>] [--baseline <file>] [--save <file>]
+       rlox fuzz [scanner|compiler|vm] [-n <iterations>] [--seed <n>] [--corpus <path>]
+
+Options: [-O0|-O1|-O2] [--max-frames <n>] [--max-stack <n>] [--max-locals <n>]
+         [--max-instructions <n>] [--max-memory <bytes>] [--trace]
+         [--dump-tokens|--dump-bytecode] to print them instead of running, with run and eval
+         [-h|--help] [--version]
+
+Options go before the script, '--' ends them. The script '-' is read from stdin. Everything
+after the script, or after -e <code>, is passed to it as is, and read with argc() and arg(n).
+
+Exit codes: 0 success, 1 a check failed (test, fmt --check, fuzz), 64 usage error,
+            65 compile error, 70 runtime error, 71 the script hit a VM limit,
+            74 I/O error, 130 interrupted";
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("{}\n{}", msg, USAGE);
+    std::process::exit(64);
+}
+
+fn parse_limit<T: std::str::FromStr + Default + PartialOrd>(
+    flag: &str,
+    value: Option<String>,
+) -> T {
+    match value.map(|v| v.parse::<T>()) {
+        Some(Ok(n)) if n > T::default() => n,
+        _ => usage_error(&format!("{} expects a positive integer", flag)),
+    }
+}
+
+#[derive(PartialEq)]
+enum Command {
+    Run,
+    Repl,
+    Eval,
+    Compile,
+    Disasm,
+    Debug,
+    Dap,
+    Lsp,
+    Fmt,
+    Test,
+    Bench,
+    Fuzz,
+}
 
 fn main() {
-    let mut args = env::args();
-    let result = if args.len() == 1 {
-        rlox::run_repl()
-    } else if args.len() == 2 {
-        rlox::run_file(args.nth(1).unwrap())
-    } else {
-        eprintln!("Usage: rlox [script]");
-        std::process::exit(64);
+    let mut options = Options::default();
+    let config = &mut options.vm;
+    let mut script = None;
+    let mut output = None;
+    let mut json = false;
+    let mut check = false;
+    let mut test = false;
+    let mut differential = false;
+    let mut count = None;
+    let mut baseline = None;
+    let mut save = None;
+    let mut seed = None;
+    let mut corpus = None;
+    let mut code = None;
+    let mut dump = None;
+    let mut script_args = Vec::new();
+    // set by `--`, the arguments left aren't options
+    let mut positional = false;
+
+    let mut args = env::args().skip(1).peekable();
+    let command = match args.peek().map(String::as_str) {
+        Some("compile") => Command::Compile,
+        Some("disasm") => Command::Disasm,
+        Some("debug") => Command::Debug,
+        Some("dap") => Command::Dap,
+        Some("lsp") => Command::Lsp,
+        Some("fmt") => Command::Fmt,
+        Some("test") => Command::Test,
+        Some("bench") => Command::Bench,
+        Some("fuzz") => Command::Fuzz,
+        Some("repl") => Command::Repl,
+        Some("eval") => Command::Eval,
+        Some("run") => {
+            args.next();
+            Command::Run
+        }
+        _ => Command::Run,
+    };
+    if command != Command::Run {
+        args.next();
+    }
+    let takes_args = matches!(command, Command::Run | Command::Eval);
+    while let Some(arg) = args.next() {
+        // run and eval pass everything after the script or the code on to it
+        if takes_args && (script.is_some() || code.is_some()) {
+            script_args.push(arg);
+            continue;
+        }
+        match arg.as_str() {
+            _ if positional && script.is_none() => script = Some(arg),
+            _ if positional => usage_error("Too many arguments"),
+            "--" => positional = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            "--version" => {
+                println!("rlox {}", env!("CARGO_PKG_VERSION"));
+                return;
+            }
+            "-e" if command == Command::Eval => {
+                code = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("-e expects code")),
+                )
+            }
+            "--dump-tokens" if takes_args => dump = Some(Dump::Tokens),
+            "--dump-bytecode" if takes_args => dump = Some(Dump::Bytecode),
+            "--trace" => options.trace = true,
+            "--profile" if command == Command::Run => options.profile = true,
+            "--coverage" if command == Command::Run => options.coverage = true,
+            "--json" if command == Command::Disasm => json = true,
+            "--check" if command == Command::Fmt => check = true,
+            "--test" if command == Command::Run => test = true,
+            "--differential" if command == Command::Test => differential = true,
+            "-o" if command == Command::Compile => {
+                output = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("-o expects a path")),
+                )
+            }
+            "-n" if matches!(command, Command::Bench | Command::Fuzz) => {
+                count = Some(parse_limit(&arg, args.next()))
+            }
+            "--seed" if command == Command::Fuzz => seed = Some(parse_limit(&arg, args.next())),
+            "--corpus" if command == Command::Fuzz => {
+                corpus = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("--corpus expects a path")),
+                )
+            }
+            "--baseline" if command == Command::Bench => {
+                baseline = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("--baseline expects a path")),
+                )
+            }
+            "--save" if command == Command::Bench => {
+                save = Some(
+                    args.next()
+                        .unwrap_or_else(|| usage_error("--save expects a path")),
+                )
+            }
+            "--max-frames" => config.max_frames = parse_limit(&arg, args.next()),
+            "--max-stack" => config.max_stack = parse_limit(&arg, args.next()),
+            "--max-locals" => config.max_locals = parse_limit(&arg, args.next()),
+            "--max-instructions" => config.max_instructions = Some(parse_limit(&arg, args.next())),
+            "--max-memory" => config.max_memory = Some(parse_limit(&arg, args.next())),
+            _ if arg.starts_with("-O") => {
+                options.opt_level = arg[2..]
+                    .parse()
+                    .ok()
+                    .and_then(OptLevel::from_level)
+                    .unwrap_or_else(|| {
+                        usage_error(&format!("Unknown optimization level '{}'", arg))
+                    })
+            }
+            _ if arg.starts_with('-') && arg != rlox::STDIN => {
+                usage_error(&format!("Unknown option '{}'", arg))
+            }
+            _ if command == Command::Eval => {
+                usage_error("eval takes its arguments after -e <code>")
+            }
+            _ if script.is_none() => script = Some(arg),
+            _ => usage_error("Too many arguments"),
+        }
+    }
+
+    rlox::natives::set_args(script_args);
+    // only for scripts in files, not for the REPL, -e or the scripts of tests and benchmarks
+    options.warnings =
+        script.is_some() && matches!(command, Command::Run | Command::Compile | Command::Debug);
+    let result = match (command, script) {
+        (Command::Dap, None) => rlox::dap::serve(io::stdin().lock(), io::stdout(), &options),
+        (Command::Dap, Some(_)) => usage_error("dap takes the script from the launch request"),
+        (Command::Lsp, None) => rlox::lsp::serve(io::stdin().lock(), io::stdout(), &options),
+        (Command::Lsp, Some(_)) => usage_error("lsp takes the scripts from the editor"),
+        (Command::Run, None) if test => usage_error("--test expects a script"),
+        (Command::Run | Command::Repl, None) => rlox::run_repl(&options),
+        (Command::Repl, Some(_)) => usage_error("repl takes no script"),
+        (Command::Eval, _) => {
+            let code = code.unwrap_or_else(|| usage_error("eval expects -e <code>"));
+            match dump {
+                Some(dump) => rlox::dump("-e", code.into_bytes(), dump, &options),
+                None => rlox::interpret(code, &options),
+            }
+        }
+        (Command::Run, Some(path)) if test => rlox::test_file(&path, &options),
+        (Command::Run, Some(path)) if dump.is_some() => rlox::read_script(&path)
+            .and_then(|bytes| rlox::dump(&path, bytes, dump.unwrap(), &options)),
+        (Command::Run, Some(path)) => rlox::run_file(path, &options),
+        (Command::Compile, Some(path)) => {
+            let output = output.unwrap_or_else(|| {
+                let stem = path.strip_suffix(".lox").unwrap_or(&path);
+                format!("{}.{}", stem, rlox::loxc::EXTENSION)
+            });
+            rlox::compile_file(&path, &output, &options)
+        }
+        (Command::Disasm, Some(path)) => rlox::load_file(&path, &options).map(|code| {
+            if json {
+                println!("{}", rlox::disasm::to_json(&code));
+            } else {
+                print!("{}", rlox::disasm::to_text(&code));
+            }
+        }),
+        (Command::Debug, Some(path)) => rlox::debug_file(&path, &options),
+        (Command::Fmt, Some(path)) => rlox::format_file(&path, check),
+        (Command::Test, Some(path)) => rlox::golden::run(&path, differential, &options),
+        (Command::Fuzz, target) => {
+            let targets = match target {
+                Some(name) => vec![rlox::fuzz::Target::from_name(&name)
+                    .unwrap_or_else(|| usage_error(&format!("Unknown fuzz target '{}'", name)))],
+                None => rlox::fuzz::Target::ALL.to_vec(),
+            };
+            rlox::fuzz::run(
+                &targets,
+                count.unwrap_or(10_000),
+                seed,
+                corpus.as_deref().unwrap_or("tests/lox"),
+            )
+        }
+        (Command::Bench, path) => rlox::bench::run(
+            path.as_deref().unwrap_or("benches"),
+            count.unwrap_or(10),
+            baseline.as_deref(),
+            save.as_deref(),
+            &options,
+        ),
+        (_, None) => usage_error("Expected a script"),
     };
 
     if let Err(e) = result {
         eprintln!("{}", e);
-        std::process::exit(1);
+        std::process::exit(rlox::exit_code(&e));
     }
 }
@@ -0,0 +1,110 @@
+//! Functions built into the VM. A script gets them through globals of the same name, unless it
+//! defines those globals itself.
+
+use std::{rc::Rc, sync::OnceLock};
+
+use crate::bytecode::{NativeFn, Value};
+
+pub const NATIVES: [NativeFn; 4] = [
+    NativeFn {
+        name: "assert",
+        arity: 2,
+        fun: assert,
+    },
+    NativeFn {
+        name: "assert_eq",
+        arity: 2,
+        fun: assert_eq,
+    },
+    NativeFn {
+        name: "argc",
+        arity: 0,
+        fun: argc,
+    },
+    NativeFn {
+        name: "arg",
+        arity: 1,
+        fun: arg,
+    },
+];
+
+// the arguments given to the script on the command line
+static ARGS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Sets the arguments `argc()` and `arg(n)` give scripts, there are none until then. Only the
+/// first call has an effect.
+pub fn set_args(args: Vec<String>) {
+    // later calls can't change what a running script already saw
+    let _ = ARGS.set(args);
+}
+
+fn args() -> &'static [String] {
+    ARGS.get().map_or(&[], Vec::as_slice)
+}
+
+/// The native called `name`.
+pub fn lookup(name: &str) -> Option<NativeFn> {
+    NATIVES.iter().find(|native| native.name == name).copied()
+}
+
+// Values in assertion messages, strings are quoted so `"1"` and `1` can be told apart.
+fn show(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("{:?}", s),
+        value => value.to_string(),
+    }
+}
+
+// assert(cond, message) fails unless `cond` is truthy.
+fn assert(args: &[Value]) -> Result<Value, String> {
+    let [cond, message] = args else {
+        unreachable!("called with its arity");
+    };
+    if cond.is_truthy() {
+        return Ok(Value::Nil);
+    }
+    Err(format!(
+        "Assertion failed: {} (got {})",
+        message,
+        show(cond)
+    ))
+}
+
+// assert_eq(actual, expected) fails unless both are equal.
+fn assert_eq(args: &[Value]) -> Result<Value, String> {
+    let [actual, expected] = args else {
+        unreachable!("called with its arity");
+    };
+    if actual == expected {
+        return Ok(Value::Nil);
+    }
+    Err(format!(
+        "Assertion failed: expected {}, got {}",
+        show(expected),
+        show(actual)
+    ))
+}
+
+// argc() is the number of arguments given to the script.
+fn argc(_: &[Value]) -> Result<Value, String> {
+    Ok(Value::Number(args().len() as f64))
+}
+
+// arg(n) is the argument at index `n`, a string.
+fn arg(args: &[Value]) -> Result<Value, String> {
+    let [n] = args else {
+        unreachable!("called with its arity");
+    };
+    let given = self::args();
+    match n {
+        Value::Number(n) if n.fract() == 0.0 && *n >= 0.0 && *n < given.len() as f64 => {
+            Ok(Value::String(Rc::new(given[*n as usize].clone())))
+        }
+        Value::Number(_) => Err(format!(
+            "Argument index {} out of range, the script has {} arguments",
+            show(n),
+            given.len()
+        )),
+        n => Err(format!("Argument index must be a number, got {}", show(n))),
+    }
+}
@@ -0,0 +1,331 @@
+use std::{cmp::Ordering, rc::Rc};
+
+use crate::bytecode::{Chunk, FunctionObj, OpCode, Value};
+
+/// How much work the optimizer does on every compiled chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum OptLevel {
+    /// Keep the code exactly as the compiler emitted it.
+    #[default]
+    None,
+    /// Fuse comparison pairs and drop values that are pushed only to be popped.
+    Peephole,
+    /// Also fold arithmetic, comparisons and `!` on constant operands.
+    Fold,
+}
+
+impl OptLevel {
+    /// Maps the number of an `-O<n>` flag to a level.
+    pub fn from_level(level: u8) -> Option<Self> {
+        match level {
+            0 => Some(OptLevel::None),
+            1 => Some(OptLevel::Peephole),
+            2 => Some(OptLevel::Fold),
+            _ => None,
+        }
+    }
+}
+
+// A decoded instruction. Jumps refer to their destination by the index of the original
+// instruction, so the code can shrink freely before it is encoded again.
+struct Ins {
+    op: OpCode,
+    line: usize,
+    target: Option<usize>,
+    // indices of the original instructions that now start at this one
+    labels: Vec<usize>,
+    // whether some jump lands on this instruction, if so it cannot be merged into the previous one
+    targeted: bool,
+}
+
+struct Optimizer<'a> {
+    level: OptLevel,
+    chunk: &'a mut Chunk,
+    out: Vec<Ins>,
+    // labels of removed instructions, they move to the next instruction
+    pending: Vec<usize>,
+    is_target: Vec<bool>,
+}
+
+/// Optimizes the chunk of `fun` in place.
+/// Nested functions in the constant pool are left alone, the compiler optimizes each of them
+/// when it finishes compiling it.
+pub fn optimize(fun: &mut FunctionObj, level: OptLevel) {
+    if level == OptLevel::None || fun.chunk().is_empty() {
+        return;
+    }
+
+    let old = fun.chunk();
+    let (code, is_target) = decode(old);
+    let lines = code.iter().map(|(offset, _)| old.get_line(*offset));
+
+    let mut chunk = Chunk::new();
+    for constant in old.constants() {
+        chunk.add_const(constant.clone());
+    }
+    let ins: Vec<_> = code
+        .iter()
+        .zip(lines)
+        .enumerate()
+        .map(|(i, ((_, (op, target)), line))| Ins {
+            op: *op,
+            line,
+            target: *target,
+            labels: vec![i],
+            targeted: is_target[i],
+        })
+        .collect();
+
+    let mut optimizer = Optimizer {
+        level,
+        chunk: &mut chunk,
+        out: Vec::with_capacity(ins.len()),
+        pending: Vec::new(),
+        is_target,
+    };
+    for ins in ins {
+        optimizer.push(ins);
+    }
+    let new_offsets = optimizer.encode();
+
+    // local ranges start and end on instruction boundaries, or at the end of the code
+    let new_offset = |offset: usize| {
+        let index = code
+            .binary_search_by_key(&offset, |(o, _)| *o)
+            .unwrap_or(code.len());
+        new_offsets[index]
+    };
+    for local in fun.locals_mut() {
+        local.start = new_offset(local.start);
+        local.end = new_offset(local.end);
+    }
+
+    *fun.chunk_mut() = chunk;
+}
+
+type Decoded = Vec<(usize, (OpCode, Option<usize>))>;
+
+// Decodes the chunk into instructions with their byte offsets, resolving jumps to instruction
+// indices. The index one past the last instruction stands for the end of the chunk.
+fn decode(chunk: &Chunk) -> (Decoded, Vec<bool>) {
+    let instructions: Vec<_> = chunk.instructions().collect();
+    let index_of = |offset: usize| {
+        instructions
+            .binary_search_by_key(&offset, |(o, _)| *o)
+            .unwrap_or(instructions.len())
+    };
+
+    let mut is_target = vec![false; instructions.len() + 1];
+    let code = instructions
+        .iter()
+        .map(|&(offset, op)| {
+            let end = offset + OpCode::JUMP_SIZE;
+            let target = match op {
+                OpCode::Jump(Some(jump)) | OpCode::JumpIfFalse(Some(jump)) => {
+                    Some(index_of(end + jump as usize))
+                }
+                OpCode::Loop(jump) => Some(index_of(end - jump as usize)),
+                _ => None,
+            };
+            if let Some(target) = target {
+                is_target[target] = true;
+            }
+            (offset, (op, target))
+        })
+        .collect();
+    (code, is_target)
+}
+
+fn is_pure_push(op: OpCode) -> bool {
+    matches!(
+        op,
+        OpCode::Constant(_) | OpCode::True | OpCode::False | OpCode::Nil | OpCode::GetLocal(_)
+    )
+}
+
+impl<'a> Optimizer<'a> {
+    fn push(&mut self, mut ins: Ins) {
+        if !self.pending.is_empty() {
+            ins.targeted |= self.pending.iter().any(|&label| self.is_target[label]);
+            ins.labels.append(&mut self.pending);
+        }
+        self.out.push(ins);
+        while self.reduce() {}
+    }
+
+    // Tries to rewrite the instructions at the end of `out`, returns whether it did.
+    fn reduce(&mut self) -> bool {
+        let len = self.out.len();
+        if len >= 3 && !self.out[len - 2].targeted && !self.out[len - 1].targeted {
+            let (a, b, c) = (
+                self.out[len - 3].op,
+                self.out[len - 2].op,
+                self.out[len - 1].op,
+            );
+            if let Some(op) = self.fold_binary(a, b, c) {
+                self.replace(3, op, len - 1);
+                return true;
+            }
+        }
+        if len >= 2 && !self.out[len - 1].targeted {
+            let (a, b) = (self.out[len - 2].op, self.out[len - 1].op);
+            if b == OpCode::Pop && is_pure_push(a) {
+                self.out.pop();
+                let removed = self.out.pop().unwrap();
+                self.pending.extend(removed.labels);
+                return true;
+            }
+            if let Some(op) = self.fuse(a, b).or_else(|| self.fold_unary(a, b)) {
+                self.replace(2, op, len - 2);
+                return true;
+            }
+        }
+        false
+    }
+
+    // Replaces the last `count` instructions with `op`, taking the line of `out[line_from]`.
+    fn replace(&mut self, count: usize, op: OpCode, line_from: usize) {
+        let line = self.out[line_from].line;
+        let first = self.out.len() - count;
+        self.out.truncate(first + 1);
+        let ins = &mut self.out[first];
+        ins.op = op;
+        ins.line = line;
+        ins.target = None;
+    }
+
+    fn fuse(&self, a: OpCode, b: OpCode) -> Option<OpCode> {
+        if self.level < OptLevel::Peephole || b != OpCode::Not {
+            return None;
+        }
+        match a {
+            OpCode::Less => Some(OpCode::GreaterEqual),
+            OpCode::Greater => Some(OpCode::LessEqual),
+            OpCode::Equal => Some(OpCode::NotEqual),
+            _ => None,
+        }
+    }
+
+    fn literal(&self, op: OpCode) -> Option<Value> {
+        match op {
+            OpCode::Constant(index) => match self.chunk.get_const(index) {
+                value @ (Value::Number(_) | Value::String(_)) => Some(value.clone()),
+                _ => None,
+            },
+            OpCode::True => Some(Value::Boolean(true)),
+            OpCode::False => Some(Value::Boolean(false)),
+            OpCode::Nil => Some(Value::Nil),
+            _ => None,
+        }
+    }
+
+    fn fold_unary(&mut self, a: OpCode, b: OpCode) -> Option<OpCode> {
+        if self.level < OptLevel::Fold {
+            return None;
+        }
+        // only folds what cannot fail at run time, errors are left for the VM to report
+        let value = match (self.literal(a)?, b) {
+            (Value::Number(n), OpCode::Negate) => Value::Number(-n),
+            (Value::Boolean(b), OpCode::Not) => Value::Boolean(!b),
+            (Value::Nil, OpCode::Not) => Value::Boolean(true),
+            _ => return None,
+        };
+        self.push_value(value)
+    }
+
+    fn fold_binary(&mut self, a: OpCode, b: OpCode, op: OpCode) -> Option<OpCode> {
+        if self.level < OptLevel::Fold {
+            return None;
+        }
+        let (a, b) = (self.literal(a)?, self.literal(b)?);
+        let value = match (&a, &b, op) {
+            (_, _, OpCode::Equal) => Value::Boolean(a == b),
+            (_, _, OpCode::NotEqual) => Value::Boolean(a != b),
+            (&Value::Number(a), &Value::Number(b), op) => match op {
+                OpCode::Add => Value::Number(a + b),
+                OpCode::Subtract => Value::Number(a - b),
+                OpCode::Multiply => Value::Number(a * b),
+                OpCode::Divide => Value::Number(a / b),
+                OpCode::Less => Value::Boolean(a < b),
+                OpCode::Greater => Value::Boolean(a > b),
+                // same NaN handling as `VM::comparison`
+                OpCode::LessEqual => Value::Boolean(a.partial_cmp(&b) != Some(Ordering::Greater)),
+                OpCode::GreaterEqual => Value::Boolean(a.partial_cmp(&b) != Some(Ordering::Less)),
+                _ => return None,
+            },
+            (Value::String(a), Value::String(b), OpCode::Add) => {
+                Value::String(Rc::new(format!("{a}{b}")))
+            }
+            _ => return None,
+        };
+        self.push_value(value)
+    }
+
+    // The instruction that pushes `value`, reusing an identical constant if there is one.
+    fn push_value(&mut self, value: Value) -> Option<OpCode> {
+        let same = |constant: &Value| match (constant, &value) {
+            // -0 and 0 compare equal but print differently
+            (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+            (Value::String(a), Value::String(b)) => a == b,
+            _ => false,
+        };
+        match value {
+            Value::Boolean(true) => return Some(OpCode::True),
+            Value::Boolean(false) => return Some(OpCode::False),
+            Value::Nil => return Some(OpCode::Nil),
+            _ => {}
+        }
+        let constants = self.chunk.constants();
+        if let Some(index) = constants.iter().position(same) {
+            return Some(OpCode::Constant(index as u16));
+        }
+        if constants.len() > u16::MAX as usize {
+            return None;
+        }
+        Some(OpCode::Constant(self.chunk.add_const(value)))
+    }
+
+    // Writes `out` to the chunk and returns the new offset of every original instruction,
+    // followed by the end of the code.
+    fn encode(mut self) -> Vec<usize> {
+        let mut scratch = Vec::new();
+        let mut offsets = Vec::with_capacity(self.out.len() + 1);
+        let mut new_index = vec![0; self.is_target.len()];
+
+        let mut offset = 0;
+        for (i, ins) in self.out.iter().enumerate() {
+            for &label in ins.labels.iter() {
+                new_index[label] = i;
+            }
+            offsets.push(offset);
+            scratch.clear();
+            ins.op.encode(&mut scratch);
+            offset += scratch.len();
+        }
+        // jumps past the last instruction
+        offsets.push(offset);
+        self.pending.push(self.is_target.len() - 1);
+        for &label in self.pending.iter() {
+            new_index[label] = self.out.len();
+        }
+
+        for (i, ins) in self.out.iter().enumerate() {
+            let end = offsets[i] + OpCode::JUMP_SIZE;
+            let op = match (ins.op, ins.target) {
+                (OpCode::Jump(_), Some(target)) => {
+                    OpCode::Jump(Some((offsets[new_index[target]] - end) as u16))
+                }
+                (OpCode::JumpIfFalse(_), Some(target)) => {
+                    OpCode::JumpIfFalse(Some((offsets[new_index[target]] - end) as u16))
+                }
+                (OpCode::Loop(_), Some(target)) => {
+                    OpCode::Loop((end - offsets[new_index[target]]) as u16)
+                }
+                (op, _) => op,
+            };
+            self.chunk.write_ins(op, ins.line);
+        }
+
+        new_index.iter().map(|&i| offsets[i]).collect()
+    }
+}
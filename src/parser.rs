@@ -0,0 +1,624 @@
+use std::mem;
+
+use crate::{
+    ast::{
+        BinaryOp, Expr, ExprKind, FunDecl, Ident, Literal, LogicalOp, Program, Span, Stmt,
+        StmtKind, UnaryOp,
+    },
+    bytecode::Precedence,
+    diagnostic::Diagnostic,
+    scanner::Scanner,
+    token::{Token, TokenKind},
+    Error, Result,
+};
+
+/*
+statement      → exprStmt
+               | forStmt
+               | ifStmt
+               | printStmt
+               | returnStmt
+               | whileStmt
+               | block ;
+
+declaration    → classDecl
+               | funDecl
+               | varDecl
+               | statement ;
+
+block          → "{" declaration* "}" ;
+
+*/
+
+pub struct Parser<'a> {
+    scanner: Scanner<'a>,
+    current: Token<'a>,
+    previous: Token<'a>,
+    diagnostics: Vec<Diagnostic>,
+    // how many function bodies we are in, returning a value is only allowed inside one
+    fun_depth: usize,
+    // how deeply nested the code being parsed is, see `nested`
+    depth: usize,
+    // set once `MAX_DEPTH` is passed, which ends parsing
+    too_deep: bool,
+}
+
+impl<'a> Parser<'a> {
+    // nesting of statements and expressions past which a script is rejected, which keeps the
+    // parser and the passes walking the tree after it from overflowing the stack
+    pub const MAX_DEPTH: usize = 256;
+
+    pub fn with_source(source: &'a str) -> Self {
+        Self {
+            scanner: Scanner::new(source),
+            current: Token::none(),
+            previous: Token::none(),
+            diagnostics: Vec::new(),
+            fun_depth: 0,
+            depth: 0,
+            too_deep: false,
+        }
+    }
+
+    /// Parses the whole source, reporting every syntax error it can recover from.
+    pub fn parse(self) -> Result<Program<'a>> {
+        self.parse_with_comments().map(|(program, _)| program)
+    }
+
+    /// Like [`Parser::parse`], also returning the spans of the `//` comments in the source, for
+    /// tools that have to keep them.
+    pub fn parse_with_comments(mut self) -> Result<(Program<'a>, Vec<Span>)> {
+        let program = self.program();
+        for diagnostic in self.diagnostics.iter() {
+            eprintln!("Parsing error: {}", diagnostic);
+        }
+        if !self.diagnostics.is_empty() {
+            return Err(Error::from(format!(
+                "\nAborting compilation due to {} errors",
+                self.diagnostics.len()
+            )));
+        }
+        Ok((program, self.scanner.comments().to_vec()))
+    }
+
+    /// Parses the whole source, returning the statements that parsed along with the syntax
+    /// errors, for tools that work on code being edited.
+    pub fn parse_recovering(mut self) -> (Program<'a>, Vec<Diagnostic>) {
+        let program = self.program();
+        (program, self.diagnostics)
+    }
+
+    fn program(&mut self) -> Program<'a> {
+        while let Err(error) = self.advance() {
+            self.report_error(error);
+        }
+        let mut stmts = Vec::new();
+        while !self.is_at_end() {
+            // tests can only be declared at the top level
+            let stmt = match self.at_test() {
+                true => self.test_decl(),
+                false => self.declaration(),
+            };
+            if self.too_deep {
+                // the rest of the script would only bring errors about the brackets left open
+                if let Err(error) = stmt {
+                    self.report_error(error);
+                }
+                break;
+            }
+            stmts.extend(self.recover(stmt));
+        }
+        Program { stmts }
+    }
+
+    // Skips the rest of a statement that failed to parse.
+    fn recover(&mut self, result: Result<Stmt<'a>>) -> Option<Stmt<'a>> {
+        match result {
+            Ok(stmt) => Some(stmt),
+            Err(error) => {
+                self.synchronize();
+                self.report_error(error);
+                None
+            }
+        }
+    }
+
+    // `test` is only a keyword when a string follows, elsewhere it is a plain identifier.
+    fn at_test(&self) -> bool {
+        if self.current.kind() != TokenKind::Identifier("test") {
+            return false;
+        }
+        let next = self.scanner.clone().scan_token();
+        matches!(next.map(|token| token.kind()), Ok(TokenKind::String(_)))
+    }
+
+    fn test_decl(&mut self) -> Result<Stmt<'a>> {
+        self.advance()?;
+        let start = self.previous_span();
+        self.advance()?;
+        let TokenKind::String(name) = self.previous.kind() else {
+            unreachable!("checked by at_test");
+        };
+        let name = Ident {
+            name,
+            span: self.previous_span(),
+        };
+        if !self.check_curr(TokenKind::LeftBrace) {
+            return Err(self.error_at_current("Expect '{' before test body."));
+        }
+        let body = self.block()?;
+        Ok(self.finish_stmt(start, StmtKind::Test { name, body }))
+    }
+
+    fn synchronize(&mut self) {
+        while !self.is_at_end() {
+            if self.previous.kind() == TokenKind::Semicolon {
+                return;
+            }
+            match self.current.kind() {
+                TokenKind::Class
+                | TokenKind::Fun
+                | TokenKind::Var
+                | TokenKind::For
+                | TokenKind::If
+                | TokenKind::While
+                | TokenKind::Print
+                | TokenKind::Return => return,
+                _ => {}
+            }
+            if let Err(error) = self.advance() {
+                self.report_error(error);
+            }
+        }
+    }
+
+    fn declaration(&mut self) -> Result<Stmt<'a>> {
+        match self.current.kind() {
+            TokenKind::Var => self.var_decl(),
+            TokenKind::Fun => self.fun_decl(),
+            _ if self.at_test() => {
+                let error = self.error_at_current("Tests can only be declared at the top level.");
+                self.report_error(error);
+                // parsed whole, so that parsing goes on after its body rather than inside it
+                self.test_decl()
+            }
+            _ => self.statement(),
+        }
+    }
+
+    fn fun_decl(&mut self) -> Result<Stmt<'a>> {
+        self.advance()?;
+        let start = self.previous_span();
+        let name = self.consume_ident("Expect function name.")?;
+
+        self.consume(TokenKind::LeftParen, "Expect '(' after function name.")?;
+        let mut params = Vec::new();
+        if !self.check_curr(TokenKind::RightParen) {
+            loop {
+                if params.len() == u8::MAX as usize {
+                    let error = self.error_at_current("Cannot have more than 255 parameters.");
+                    self.report_error(error);
+                }
+                params.push(self.consume_ident("Expect variable name.")?);
+                if !self.match_curr(TokenKind::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expect ')' after parameters")?;
+        if !self.check_curr(TokenKind::LeftBrace) {
+            return Err(self.error_at_current("Expect '{' before function body."));
+        }
+
+        self.fun_depth += 1;
+        let body = self.nested(Self::block);
+        self.fun_depth -= 1;
+
+        let decl = FunDecl {
+            name,
+            params,
+            body: body?,
+        };
+        Ok(self.finish_stmt(start, StmtKind::Fun(decl)))
+    }
+
+    fn var_decl(&mut self) -> Result<Stmt<'a>> {
+        self.advance()?;
+        let start = self.previous_span();
+        let name = self.consume_ident("Expect variable name.")?;
+
+        let init = if self.match_curr(TokenKind::Equal)? {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+
+        self.consume(
+            TokenKind::Semicolon,
+            "Expect ';' after variable declaration.",
+        )?;
+        Ok(self.finish_stmt(start, StmtKind::Var { name, init }))
+    }
+
+    fn statement(&mut self) -> Result<Stmt<'a>> {
+        self.nested(|parser| match parser.current.kind() {
+            TokenKind::Print => parser.print_stmt(),
+            TokenKind::LeftBrace => {
+                let start = parser.current_span();
+                let stmts = parser.block()?;
+                Ok(parser.finish_stmt(start, StmtKind::Block(stmts)))
+            }
+            TokenKind::If => parser.if_stmt(),
+            TokenKind::While => parser.while_stmt(),
+            TokenKind::For => parser.for_stmt(),
+            TokenKind::Return => parser.return_stmt(),
+            _ => parser.expression_stmt(),
+        })
+    }
+
+    fn return_stmt(&mut self) -> Result<Stmt<'a>> {
+        self.advance()?;
+        let start = self.previous_span();
+        let value = if self.check_curr(TokenKind::Semicolon) {
+            self.consume(TokenKind::Semicolon, "Expect ';' after return.")?;
+            None
+        } else {
+            if self.fun_depth == 0 {
+                return Err(self.error_at_current("Cannot return value from top-level code."));
+            }
+            let value = self.expression()?;
+            self.consume(TokenKind::Semicolon, "Expect ';' after return value.")?;
+            Some(value)
+        };
+        Ok(self.finish_stmt(start, StmtKind::Return(value)))
+    }
+
+    fn for_stmt(&mut self) -> Result<Stmt<'a>> {
+        self.advance()?;
+        let start = self.previous_span();
+
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'for'.")?;
+        let init = match self.current.kind() {
+            TokenKind::Semicolon => {
+                self.advance()?;
+                None
+            }
+            TokenKind::Var => Some(Box::new(self.var_decl()?)),
+            _ => Some(Box::new(self.expression_stmt()?)),
+        };
+
+        let mut cond = None;
+        if !self.match_curr(TokenKind::Semicolon)? {
+            cond = Some(self.expression()?);
+            self.consume(TokenKind::Semicolon, "Expect ';' after loop condition.")?;
+        }
+
+        let mut increment = None;
+        if !self.match_curr(TokenKind::RightParen)? {
+            increment = Some(self.expression()?);
+            self.consume(TokenKind::RightParen, "Expect ')' after for clauses.")?;
+        }
+
+        let body = Box::new(self.statement()?);
+        Ok(self.finish_stmt(
+            start,
+            StmtKind::For {
+                init,
+                cond,
+                increment,
+                body,
+            },
+        ))
+    }
+
+    fn while_stmt(&mut self) -> Result<Stmt<'a>> {
+        self.advance()?;
+        let start = self.previous_span();
+
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'while'.")?;
+        let cond = self.expression()?;
+        self.consume(TokenKind::RightParen, "Expect ')' after condition.")?;
+
+        let body = Box::new(self.statement()?);
+        Ok(self.finish_stmt(start, StmtKind::While { cond, body }))
+    }
+
+    fn if_stmt(&mut self) -> Result<Stmt<'a>> {
+        self.advance()?;
+        let start = self.previous_span();
+
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'if'.")?;
+        let cond = self.expression()?;
+        self.consume(TokenKind::RightParen, "Expect ')' after condition.")?;
+
+        let then_branch = Box::new(self.statement()?);
+        let else_branch = if self.match_curr(TokenKind::Else)? {
+            Some(Box::new(self.statement()?))
+        } else {
+            None
+        };
+        Ok(self.finish_stmt(
+            start,
+            StmtKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            },
+        ))
+    }
+
+    // a `{ ... }` block, the current token is the opening brace
+    fn block(&mut self) -> Result<Vec<Stmt<'a>>> {
+        self.advance()?;
+
+        let mut stmts = Vec::new();
+        while !self.check_curr(TokenKind::RightBrace) && !self.is_at_end() {
+            let stmt = match self.declaration() {
+                // recovering here would go on just as deep, it is left to `program`
+                Err(error) if self.too_deep => return Err(error),
+                stmt => stmt,
+            };
+            stmts.extend(self.recover(stmt));
+        }
+
+        self.consume(TokenKind::RightBrace, "Expect '}' after block.")?;
+        Ok(stmts)
+    }
+
+    fn print_stmt(&mut self) -> Result<Stmt<'a>> {
+        self.advance()?;
+        let start = self.previous_span();
+        let value = self.expression()?;
+        self.consume(TokenKind::Semicolon, "Expect ';' after value.")?;
+        Ok(self.finish_stmt(start, StmtKind::Print(value)))
+    }
+
+    fn expression_stmt(&mut self) -> Result<Stmt<'a>> {
+        let expr = self.expression()?;
+        self.consume(TokenKind::Semicolon, "Expect ';' after expression.")?;
+        Ok(self.finish_stmt(expr.span, StmtKind::Expression(expr)))
+    }
+
+    fn expression(&mut self) -> Result<Expr<'a>> {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    // parse any expression at given precendece level or higher
+    fn parse_precedence(&mut self, precedence: Precedence) -> Result<Expr<'a>> {
+        self.nested(|parser| {
+            parser.advance()?;
+            let can_assign = precedence <= Precedence::Assignment;
+            let mut expr = parser.prefix(can_assign)?;
+
+            while precedence <= parser.current.kind().precedence() {
+                // the tree gets one level deeper with every operator, though the parser doesn't
+                parser.deeper()?;
+                parser.advance()?;
+                expr = parser.infix(expr)?;
+            }
+            if can_assign && parser.match_curr(TokenKind::Equal)? {
+                return Err(parser.error_at_previous("Invalid assignment target."));
+            }
+            Ok(expr)
+        })
+    }
+
+    // Runs `parse` one level deeper, and back at this level after it, whether it fails or not.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let depth = self.depth;
+        let result = self.deeper().and_then(|()| parse(self));
+        self.depth = depth;
+        result
+    }
+
+    fn deeper(&mut self) -> Result<()> {
+        self.depth += 1;
+        if self.depth > Self::MAX_DEPTH {
+            self.too_deep = true;
+            return Err(self.error_at_current("Code is nested too deeply."));
+        }
+        Ok(())
+    }
+
+    fn prefix(&mut self, can_assign: bool) -> Result<Expr<'a>> {
+        let start = self.previous_span();
+        let kind = match self.previous.kind() {
+            TokenKind::LeftParen => {
+                let inner = self.expression()?;
+                self.consume(TokenKind::RightParen, "Expect ')' after expression")?;
+                ExprKind::Grouping(Box::new(inner))
+            }
+            TokenKind::Number(val) => ExprKind::Literal(Literal::Number(val)),
+            TokenKind::String(s) => ExprKind::Literal(Literal::String(s)),
+            TokenKind::True => ExprKind::Literal(Literal::True),
+            TokenKind::False => ExprKind::Literal(Literal::False),
+            TokenKind::Nil => ExprKind::Literal(Literal::Nil),
+            TokenKind::Minus | TokenKind::Bang => {
+                let op = match self.previous.kind() {
+                    TokenKind::Minus => UnaryOp::Negate,
+                    _ => UnaryOp::Not,
+                };
+                let operand = self.parse_precedence(Precedence::Unary)?;
+                ExprKind::Unary {
+                    op,
+                    operand: Box::new(operand),
+                }
+            }
+            TokenKind::Identifier(name) => {
+                let name = Ident { name, span: start };
+                if can_assign && self.match_curr(TokenKind::Equal)? {
+                    let value = self.expression()?;
+                    ExprKind::Assign {
+                        name,
+                        value: Box::new(value),
+                    }
+                } else {
+                    ExprKind::Variable(name)
+                }
+            }
+
+            kind => return Err(self.error_at_previous(&format!("Unexpected token '{:?}'", kind))),
+        };
+        Ok(self.finish_expr(start, kind))
+    }
+
+    fn infix(&mut self, left: Expr<'a>) -> Result<Expr<'a>> {
+        let operator = self.previous.kind();
+        let start = left.span;
+        let kind = match operator {
+            TokenKind::LeftParen => ExprKind::Call {
+                callee: Box::new(left),
+                args: self.argument_list()?,
+            },
+            TokenKind::And | TokenKind::Or => {
+                let (op, precedence) = match operator {
+                    TokenKind::And => (LogicalOp::And, Precedence::And),
+                    _ => (LogicalOp::Or, Precedence::Or),
+                };
+                let right = self.parse_precedence(precedence)?;
+                ExprKind::Logical {
+                    op,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                }
+            }
+            _ => {
+                let op = match operator {
+                    TokenKind::Plus => BinaryOp::Add,
+                    TokenKind::Minus => BinaryOp::Subtract,
+                    TokenKind::Star => BinaryOp::Multiply,
+                    TokenKind::Slash => BinaryOp::Divide,
+                    TokenKind::EqualEqual => BinaryOp::Equal,
+                    TokenKind::BangEqual => BinaryOp::NotEqual,
+                    TokenKind::Less => BinaryOp::Less,
+                    TokenKind::LessEqual => BinaryOp::LessEqual,
+                    TokenKind::Greater => BinaryOp::Greater,
+                    TokenKind::GreaterEqual => BinaryOp::GreaterEqual,
+                    // only tokens with an infix precedence get here
+                    _ => unreachable!(),
+                };
+                let right = self.parse_precedence(operator.precedence().higher())?;
+                ExprKind::Binary {
+                    op,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                }
+            }
+        };
+        Ok(self.finish_expr(start, kind))
+    }
+
+    fn argument_list(&mut self) -> Result<Vec<Expr<'a>>> {
+        let mut args = Vec::new();
+        if !self.check_curr(TokenKind::RightParen) {
+            loop {
+                args.push(self.expression()?);
+                if args.len() > u8::MAX as usize {
+                    return Err(self.error_at_previous("Cannot have more than 255 arguments."));
+                }
+                if !self.match_curr(TokenKind::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expect ')' after arguments.")?;
+        Ok(args)
+    }
+
+    fn span_of(token: &Token<'a>) -> Span {
+        Span {
+            start: token.start(),
+            end: token.end(),
+            line: token.line(),
+            end_line: token.line(),
+        }
+    }
+
+    fn current_span(&self) -> Span {
+        Self::span_of(&self.current)
+    }
+
+    fn previous_span(&self) -> Span {
+        Self::span_of(&self.previous)
+    }
+
+    // a node that starts at `start` and ends with the previous token
+    fn finish_stmt(&self, start: Span, kind: StmtKind<'a>) -> Stmt<'a> {
+        let span = start.to(self.previous_span());
+        Stmt { kind, span }
+    }
+
+    fn finish_expr(&self, start: Span, kind: ExprKind<'a>) -> Expr<'a> {
+        let span = start.to(self.previous_span());
+        Expr { kind, span }
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.check_curr(TokenKind::Eof)
+    }
+
+    fn match_curr(&mut self, kind: TokenKind) -> Result<bool> {
+        if self.check_curr(kind) {
+            self.advance()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn check_curr(&self, kind: TokenKind) -> bool {
+        self.current.kind() == kind
+    }
+
+    fn advance(&mut self) -> Result<()> {
+        let token = self.scanner.scan_token()?;
+        self.previous = mem::replace(&mut self.current, token);
+        Ok(())
+    }
+
+    fn consume(&mut self, expected: TokenKind, msg: &str) -> Result<()> {
+        if self.check_curr(expected) {
+            self.advance()
+        } else {
+            Err(self.error_at_current(msg))
+        }
+    }
+
+    fn consume_ident(&mut self, msg: &str) -> Result<Ident<'a>> {
+        if let TokenKind::Identifier(name) = self.current.kind() {
+            self.advance()?;
+            Ok(Ident {
+                name,
+                span: self.previous_span(),
+            })
+        } else {
+            Err(self.error_at_current(msg))
+        }
+    }
+
+    fn report_error(&mut self, error: Error) {
+        let diagnostic = match error.downcast::<Diagnostic>() {
+            Ok(diagnostic) => *diagnostic,
+            Err(error) => Diagnostic::at_line(error.to_string(), self.current.line()),
+        };
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn error_at(&self, token: &Token<'a>, msg: &str) -> Error {
+        let message = format!(
+            "{} at line {}, at token '{}'",
+            msg,
+            token.line(),
+            token.kind()
+        );
+        Error::from(Diagnostic::new(message, Self::span_of(token)))
+    }
+
+    fn error_at_previous(&self, msg: &str) -> Error {
+        self.error_at(&self.previous, msg)
+    }
+
+    fn error_at_current(&self, msg: &str) -> Error {
+        self.error_at(&self.current, msg)
+    }
+}
@@ -0,0 +1,213 @@
+//! Counting where a script spends its instructions and time, behind `--profile`.
+//!
+//! The profiler is a tracer: it sees every instruction, so its counts are exact rather than
+//! sampled. Calls are timed when the depth of the call stack changes, which is the only time it
+//! reads the clock. A function that calls itself only adds its outermost call to its inclusive
+//! time, the nested calls are already part of it.
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    time::{Duration, Instant},
+};
+
+use crate::vm::Trace;
+
+#[derive(Debug, Default, Clone)]
+pub struct FunctionStats {
+    pub calls: u64,
+    pub instructions: u64,
+    /// Time in the function and the functions it called.
+    pub inclusive: Duration,
+    /// Time in the function itself.
+    pub exclusive: Duration,
+}
+
+// A call that is still running.
+struct Active {
+    name: String,
+    start: Instant,
+    // time spent in the calls it made
+    children: Duration,
+    instructions: u64,
+    // length of `Profiler::path` before this call was added
+    path_len: usize,
+}
+
+#[derive(Default)]
+pub struct Profiler {
+    active: Vec<Active>,
+    // the names of the active calls, outermost first, separated by `;`
+    path: String,
+    total: u64,
+    ops: HashMap<&'static str, u64>,
+    // indexed by line
+    lines: Vec<u64>,
+    functions: HashMap<String, FunctionStats>,
+    // instructions executed by each stack of calls
+    stacks: HashMap<String, u64>,
+    started: Option<Instant>,
+    elapsed: Duration,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accounts for the instruction about to run, to be called from a tracer.
+    pub fn record(&mut self, trace: &Trace) {
+        if trace.depth != self.active.len() {
+            let now = Instant::now();
+            self.started.get_or_insert(now);
+            // a call adds one frame and a return removes one
+            while self.active.len() > trace.depth {
+                self.leave(now);
+            }
+            if self.active.len() < trace.depth {
+                self.enter(trace.function.name(), now);
+            }
+        }
+
+        self.total += 1;
+        *self.ops.entry(trace.ins.name()).or_default() += 1;
+        let line = trace.line();
+        if line >= self.lines.len() {
+            self.lines.resize(line + 1, 0);
+        }
+        self.lines[line] += 1;
+        if let Some(call) = self.active.last_mut() {
+            call.instructions += 1;
+        }
+    }
+
+    fn enter(&mut self, name: &str, now: Instant) {
+        let path_len = self.path.len();
+        if !self.path.is_empty() {
+            self.path.push(';');
+        }
+        self.path.push_str(name);
+        self.active.push(Active {
+            name: name.to_string(),
+            start: now,
+            children: Duration::ZERO,
+            instructions: 0,
+            path_len,
+        });
+    }
+
+    fn leave(&mut self, now: Instant) {
+        let Some(call) = self.active.pop() else {
+            return;
+        };
+        let inclusive = now - call.start;
+        let recursive = self.active.iter().any(|outer| outer.name == call.name);
+        if let Some(caller) = self.active.last_mut() {
+            caller.children += inclusive;
+        }
+
+        match self.stacks.get_mut(&self.path) {
+            Some(count) => *count += call.instructions,
+            None => {
+                self.stacks.insert(self.path.clone(), call.instructions);
+            }
+        }
+        self.path.truncate(call.path_len);
+
+        let stats = self.functions.entry(call.name).or_default();
+        stats.calls += 1;
+        stats.instructions += call.instructions;
+        stats.exclusive += inclusive.saturating_sub(call.children);
+        if !recursive {
+            stats.inclusive += inclusive;
+        }
+    }
+
+    /// Ends the calls still running, once the script finished or failed.
+    pub fn finish(&mut self) {
+        let now = Instant::now();
+        while !self.active.is_empty() {
+            self.leave(now);
+        }
+        if let Some(started) = self.started {
+            self.elapsed = now - started;
+        }
+    }
+
+    pub fn functions(&self) -> &HashMap<String, FunctionStats> {
+        &self.functions
+    }
+
+    /// The stacks of calls in the folded format of flamegraph tools: the function names
+    /// separated by `;`, outermost first, then the instructions run with that stack.
+    pub fn folded(&self) -> String {
+        let mut stacks: Vec<_> = self.stacks.iter().filter(|(_, &n)| n > 0).collect();
+        stacks.sort();
+        let mut out = String::new();
+        for (stack, count) in stacks {
+            // flamegraph tools split frames on `;` and the count on the last space
+            writeln!(out, "{} {}", stack.replace(' ', "_"), count).unwrap();
+        }
+        out
+    }
+
+    /// A summary for people: time and instructions per function, per instruction kind and for
+    /// the busiest lines.
+    pub fn report(&self) -> String {
+        const TOP_LINES: usize = 10;
+        let share = |n: u64| 100.0 * n as f64 / self.total.max(1) as f64;
+        let mut out = String::new();
+        writeln!(out, "{} instructions in {:.3?}", self.total, self.elapsed).unwrap();
+
+        let mut functions: Vec<_> = self.functions.iter().collect();
+        functions.sort_by(|a, b| b.1.exclusive.cmp(&a.1.exclusive).then(a.0.cmp(b.0)));
+        writeln!(
+            out,
+            "\n{:<24} {:>10} {:>14} {:>12} {:>12}",
+            "function", "calls", "instructions", "inclusive", "exclusive"
+        )
+        .unwrap();
+        for (name, stats) in functions {
+            writeln!(
+                out,
+                "{:<24} {:>10} {:>14} {:>12} {:>12}",
+                name,
+                stats.calls,
+                stats.instructions,
+                format!("{:.3?}", stats.inclusive),
+                format!("{:.3?}", stats.exclusive)
+            )
+            .unwrap();
+        }
+
+        let mut ops: Vec<_> = self.ops.iter().collect();
+        ops.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        writeln!(
+            out,
+            "\n{:<24} {:>14} {:>7}",
+            "instruction", "count", "share"
+        )
+        .unwrap();
+        for (name, &count) in ops {
+            writeln!(out, "{:<24} {:>14} {:>6.1}%", name, count, share(count)).unwrap();
+        }
+
+        let mut lines: Vec<_> = self
+            .lines
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .collect();
+        lines.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(&b.0)));
+        writeln!(
+            out,
+            "\n{:<24} {:>14} {:>7}",
+            "line", "instructions", "share"
+        )
+        .unwrap();
+        for (line, &count) in lines.into_iter().take(TOP_LINES) {
+            writeln!(out, "{:<24} {:>14} {:>6.1}%", line, count, share(count)).unwrap();
+        }
+        out
+    }
+}
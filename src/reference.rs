@@ -0,0 +1,392 @@
+//! A tree-walking interpreter, the reference the bytecode compiler and VM are checked against.
+//!
+//! It evaluates the syntax tree directly and shares nothing with the compiler but the parser:
+//! no slots, no jumps, variables are looked up by name in the scopes of the running call.
+//! Functions don't capture anything, like in the VM a name that isn't a local of the running
+//! function is a global. Values are the VM's, so both print them the same way, and functions
+//! are represented by a `FunctionObj` without code.
+//!
+//! Of the VM's limits only `VmConfig::max_frames` is enforced.
+
+use std::{cmp::Ordering, collections::HashMap, io::Write, rc::Rc};
+
+use crate::{
+    ast::{
+        BinaryOp, Expr, ExprKind, FunDecl, Ident, Literal, LogicalOp, Program, Stmt, StmtKind,
+        UnaryOp,
+    },
+    bytecode::{FunctionObj, Value},
+    natives,
+    vm::{RuntimeError, RuntimeErrorKind, VmConfig},
+    Error, Result,
+};
+
+// How a statement finished.
+enum Flow {
+    Next,
+    Return(Value),
+}
+
+// A call being evaluated.
+struct Call<'p> {
+    name: String,
+    // line of the call it is making, for the stack trace
+    line: usize,
+    // innermost last
+    scopes: Vec<Vec<(&'p str, Value)>>,
+}
+
+pub struct Interpreter<'p, 'w> {
+    out: Box<dyn Write + 'w>,
+    config: VmConfig,
+    globals: HashMap<&'p str, Value>,
+    // outermost first, the main function included
+    calls: Vec<Call<'p>>,
+    // every function declaration gets a single value, like the VM's constants
+    values: HashMap<*const FunDecl<'p>, Rc<FunctionObj>>,
+    decls: HashMap<*const FunctionObj, &'p FunDecl<'p>>,
+}
+
+impl<'p, 'w> Interpreter<'p, 'w> {
+    /// An interpreter printing to `out`.
+    pub fn new(out: Box<dyn Write + 'w>, config: VmConfig) -> Self {
+        Self {
+            out,
+            config,
+            globals: HashMap::new(),
+            calls: Vec::new(),
+            values: HashMap::new(),
+            decls: HashMap::new(),
+        }
+    }
+
+    /// Runs the main function of `program`, skipping its tests like `VM::run` does.
+    pub fn run(&mut self, program: &'p Program<'p>) -> Result<()> {
+        self.calls = vec![Call {
+            name: FunctionObj::new_main().name().to_string(),
+            line: 0,
+            scopes: Vec::new(),
+        }];
+        for stmt in program.stmts.iter() {
+            // the compiler rejects `return` outside of functions
+            self.stmt(stmt)?;
+        }
+        Ok(())
+    }
+
+    fn stmts(&mut self, stmts: &'p [Stmt<'p>]) -> Result<Flow> {
+        for stmt in stmts {
+            if let Flow::Return(value) = self.stmt(stmt)? {
+                return Ok(Flow::Return(value));
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    // Runs `stmts` in a new scope.
+    fn block(&mut self, stmts: &'p [Stmt<'p>]) -> Result<Flow> {
+        self.call().scopes.push(Vec::new());
+        let flow = self.stmts(stmts);
+        self.call().scopes.pop();
+        flow
+    }
+
+    fn stmt(&mut self, stmt: &'p Stmt<'p>) -> Result<Flow> {
+        match &stmt.kind {
+            StmtKind::Expression(expr) => {
+                self.expr(expr)?;
+            }
+            StmtKind::Print(expr) => {
+                let value = self.expr(expr)?;
+                writeln!(self.out, "{}", value)?;
+            }
+            StmtKind::Var { name, init } => {
+                let value = match init {
+                    Some(init) => self.expr(init)?,
+                    None => Value::Nil,
+                };
+                self.define(name, value);
+            }
+            StmtKind::Fun(decl) => {
+                let value = self.function(decl);
+                self.define(&decl.name, value);
+            }
+            StmtKind::Block(stmts) => return self.block(stmts),
+            StmtKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                if self.expr(cond)?.is_truthy() {
+                    return self.stmt(then_branch);
+                } else if let Some(else_branch) = else_branch {
+                    return self.stmt(else_branch);
+                }
+            }
+            StmtKind::While { cond, body } => {
+                while self.expr(cond)?.is_truthy() {
+                    if let Flow::Return(value) = self.stmt(body)? {
+                        return Ok(Flow::Return(value));
+                    }
+                }
+            }
+            StmtKind::For {
+                init,
+                cond,
+                increment,
+                body,
+            } => {
+                self.call().scopes.push(Vec::new());
+                let flow = self.for_stmt(init.as_deref(), cond.as_ref(), increment.as_ref(), body);
+                self.call().scopes.pop();
+                return flow;
+            }
+            StmtKind::Return(value) => {
+                let value = match value {
+                    Some(value) => self.expr(value)?,
+                    None => Value::Nil,
+                };
+                return Ok(Flow::Return(value));
+            }
+            // only run by `--test`
+            StmtKind::Test { .. } => {}
+        }
+        Ok(Flow::Next)
+    }
+
+    fn for_stmt(
+        &mut self,
+        init: Option<&'p Stmt<'p>>,
+        cond: Option<&'p Expr<'p>>,
+        increment: Option<&'p Expr<'p>>,
+        body: &'p Stmt<'p>,
+    ) -> Result<Flow> {
+        if let Some(init) = init {
+            self.stmt(init)?;
+        }
+        loop {
+            if let Some(cond) = cond {
+                if !self.expr(cond)?.is_truthy() {
+                    return Ok(Flow::Next);
+                }
+            }
+            if let Flow::Return(value) = self.stmt(body)? {
+                return Ok(Flow::Return(value));
+            }
+            if let Some(increment) = increment {
+                self.expr(increment)?;
+            }
+        }
+    }
+
+    // The value of a function declaration, the same every time it is declared.
+    fn function(&mut self, decl: &'p FunDecl<'p>) -> Value {
+        let function = self.values.entry(decl).or_insert_with(|| {
+            let arity = decl.params.len().min(u8::MAX as usize) as u8;
+            Rc::new(FunctionObj::new(decl.name.name.to_string(), arity))
+        });
+        self.decls.insert(Rc::as_ptr(function), decl);
+        Value::Function(Rc::clone(function))
+    }
+
+    fn call(&mut self) -> &mut Call<'p> {
+        self.calls
+            .last_mut()
+            .expect("the main function is always running")
+    }
+
+    // Declares a variable in the innermost scope, a global outside of any.
+    fn define(&mut self, name: &Ident<'p>, value: Value) {
+        match self.call().scopes.last_mut() {
+            Some(scope) => scope.push((name.name, value)),
+            None => {
+                self.globals.insert(name.name, value);
+            }
+        }
+    }
+
+    // The variable `name` as the running function sees it: its innermost local of that name,
+    // or else the global. Natives are globals that are always defined.
+    fn variable(&mut self, name: &Ident<'p>) -> Result<&mut Value> {
+        let scopes = &self
+            .calls
+            .last()
+            .expect("the main function is always running")
+            .scopes;
+        let local = scopes.iter().enumerate().rev().find_map(|(i, scope)| {
+            let j = scope.iter().rposition(|(local, _)| *local == name.name)?;
+            Some((i, j))
+        });
+        if let Some((i, j)) = local {
+            return Ok(&mut self.call().scopes[i][j].1);
+        }
+        if !self.globals.contains_key(name.name) {
+            let native = natives::lookup(name.name).ok_or_else(|| {
+                self.error(
+                    name.span.end_line,
+                    &format!("Undefined global variable '{}'", name.name),
+                )
+            })?;
+            self.globals.insert(name.name, Value::Native(native));
+        }
+        Ok(self.globals.get_mut(name.name).expect("inserted above"))
+    }
+
+    fn expr(&mut self, expr: &'p Expr<'p>) -> Result<Value> {
+        let line = expr.span.end_line;
+        Ok(match &expr.kind {
+            ExprKind::Literal(literal) => match *literal {
+                Literal::Number(n) => Value::Number(n),
+                Literal::String(s) => Value::String(Rc::new(s.to_string())),
+                Literal::True => Value::Boolean(true),
+                Literal::False => Value::Boolean(false),
+                Literal::Nil => Value::Nil,
+            },
+            ExprKind::Variable(name) => self.variable(name)?.clone(),
+            ExprKind::Assign { name, value } => {
+                let value = self.expr(value)?;
+                *self.variable(name)? = value.clone();
+                value
+            }
+            ExprKind::Unary { op, operand } => {
+                let operand = self.expr(operand)?;
+                match (op, operand) {
+                    (UnaryOp::Negate, Value::Number(n)) => Value::Number(-n),
+                    (UnaryOp::Negate, v) => {
+                        return Err(self.error(line, &format!("Cannot negate {v}")))
+                    }
+                    (UnaryOp::Not, Value::Boolean(b)) => Value::Boolean(!b),
+                    (UnaryOp::Not, Value::Nil) => Value::Boolean(true),
+                    (UnaryOp::Not, v) => {
+                        return Err(
+                            self.error(line, &format!("Cannot perform '!' operation on {v}"))
+                        )
+                    }
+                }
+            }
+            ExprKind::Binary { op, left, right } => {
+                let left = self.expr(left)?;
+                let right = self.expr(right)?;
+                self.binary(*op, left, right, line)?
+            }
+            ExprKind::Logical { op, left, right } => {
+                let left = self.expr(left)?;
+                match (op, left.is_truthy()) {
+                    (LogicalOp::And, false) | (LogicalOp::Or, true) => left,
+                    _ => self.expr(right)?,
+                }
+            }
+            ExprKind::Grouping(inner) => self.expr(inner)?,
+            ExprKind::Call { callee, args } => {
+                let callee = self.expr(callee)?;
+                let args = args
+                    .iter()
+                    .map(|arg| self.expr(arg))
+                    .collect::<Result<Vec<_>>>()?;
+                self.call_value(callee, args, line)?
+            }
+        })
+    }
+
+    fn binary(&self, op: BinaryOp, left: Value, right: Value, line: usize) -> Result<Value> {
+        let numbers = || match (&left, &right) {
+            (Value::Number(a), Value::Number(b)) => Ok((*a, *b)),
+            // the right operand is checked first, it is on top of the VM's stack
+            (_, Value::Number(_)) => {
+                Err(self.error(line, &format!("Expected a number but got {}", left)))
+            }
+            _ => Err(self.error(line, &format!("Expected a number but got {}", right))),
+        };
+        Ok(match op {
+            BinaryOp::Add => match (&left, &right) {
+                (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+                (Value::String(a), Value::String(b)) => {
+                    Value::String(Rc::new(format!("{}{}", a, b)))
+                }
+                (a, b) => return Err(self.error(line, &format!("Cannot add {a} and {b}"))),
+            },
+            BinaryOp::Subtract => numbers().map(|(a, b)| Value::Number(a - b))?,
+            BinaryOp::Multiply => numbers().map(|(a, b)| Value::Number(a * b))?,
+            BinaryOp::Divide => numbers().map(|(a, b)| Value::Number(a / b))?,
+            BinaryOp::Equal => Value::Boolean(left == right),
+            BinaryOp::NotEqual => Value::Boolean(left != right),
+            BinaryOp::Less => numbers().map(|(a, b)| Value::Boolean(a < b))?,
+            BinaryOp::Greater => numbers().map(|(a, b)| Value::Boolean(a > b))?,
+            // not greater, which isn't `<=` when NaN is involved
+            BinaryOp::LessEqual => numbers()
+                .map(|(a, b)| Value::Boolean(a.partial_cmp(&b) != Some(Ordering::Greater)))?,
+            BinaryOp::GreaterEqual => {
+                numbers().map(|(a, b)| Value::Boolean(a.partial_cmp(&b) != Some(Ordering::Less)))?
+            }
+        })
+    }
+
+    fn call_value(&mut self, callee: Value, args: Vec<Value>, line: usize) -> Result<Value> {
+        let arity_error = |this: &Self, arity: u8, name: &str| {
+            this.error(
+                line,
+                &format!(
+                    "Expected {} arguments but got {} in call to {}()",
+                    arity,
+                    args.len(),
+                    name
+                ),
+            )
+        };
+        match callee {
+            Value::Function(function) => {
+                if args.len() != function.arity() as usize {
+                    return Err(arity_error(self, function.arity(), function.name()));
+                }
+                if self.calls.len() >= self.config.max_frames {
+                    return Err(self.error_of_kind(
+                        RuntimeErrorKind::StackOverflow,
+                        line,
+                        "Stack overflow",
+                    ));
+                }
+                let decl = self.decls[&Rc::as_ptr(&function)];
+                let params = decl.params.iter().map(|param| param.name).zip(args);
+                self.call().line = line;
+                self.calls.push(Call {
+                    name: function.name().to_string(),
+                    line,
+                    // the parameters, then the body in a scope of its own
+                    scopes: vec![params.collect(), Vec::new()],
+                });
+                // a failed call stays on the stack, the error already has its trace
+                let flow = self.stmts(&decl.body)?;
+                self.calls.pop();
+                Ok(match flow {
+                    Flow::Return(value) => value,
+                    Flow::Next => Value::Nil,
+                })
+            }
+            Value::Native(native) => {
+                if args.len() != native.arity as usize {
+                    return Err(arity_error(self, native.arity, native.name));
+                }
+                (native.fun)(&args).map_err(|message| self.error(line, &message))
+            }
+            callee => Err(self.error(line, &format!("Can only call functions, not {}", callee))),
+        }
+    }
+
+    fn error(&self, line: usize, msg: &str) -> Error {
+        self.error_of_kind(RuntimeErrorKind::Script, line, msg)
+    }
+
+    // An error on `line` of the running call.
+    fn error_of_kind(&self, kind: RuntimeErrorKind, line: usize, msg: &str) -> Error {
+        let mut trace: Vec<_> = self
+            .calls
+            .iter()
+            .rev()
+            .map(|call| (call.line, call.name.clone()))
+            .collect();
+        if let Some(innermost) = trace.first_mut() {
+            innermost.0 = line;
+        }
+        Error::from(RuntimeError::new(kind, msg.to_string(), trace))
+    }
+}
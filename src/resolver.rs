@@ -0,0 +1,488 @@
+use std::{collections::HashMap, fmt};
+
+use crate::ast::{Expr, ExprKind, FunDecl, Ident, Program, Span, Stmt, StmtKind};
+
+/// The kinds of warning the resolver reports, each can be silenced for a whole file with a
+/// `// rlox: allow(<name>, ...)` comment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lint {
+    /// A local variable or parameter that is never read.
+    Unused,
+    /// Statements that follow a `return`.
+    Unreachable,
+    /// A local declared with the name of a local of an enclosing scope.
+    Shadow,
+    /// An assignment to a global that is declared nowhere in the file.
+    Undeclared,
+    /// A call to a declared function with the wrong number of arguments.
+    Arity,
+}
+
+impl Lint {
+    const ALL: [Lint; 5] = [
+        Lint::Unused,
+        Lint::Unreachable,
+        Lint::Shadow,
+        Lint::Undeclared,
+        Lint::Arity,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::Unused => "unused",
+            Lint::Unreachable => "unreachable",
+            Lint::Shadow => "shadow",
+            Lint::Undeclared => "undeclared",
+            Lint::Arity => "arity",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|lint| lint.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub lint: Lint,
+    pub message: String,
+    pub span: Span,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "warning[{}]: {} at line {}",
+            self.lint.name(),
+            self.message,
+            self.span.line
+        )
+    }
+}
+
+/// The lints silenced by `// rlox: allow(...)` comments in `source`.
+pub fn allowed_lints(source: &str) -> Vec<Lint> {
+    let mut allowed = Vec::new();
+    for line in source.lines() {
+        let directive = line
+            .trim()
+            .strip_prefix("//")
+            .and_then(|comment| comment.trim().strip_prefix("rlox:"))
+            .and_then(|rest| rest.trim().strip_prefix("allow("))
+            .and_then(|rest| rest.trim_end().strip_suffix(')'));
+        if let Some(names) = directive {
+            allowed.extend(
+                names
+                    .split(',')
+                    .filter_map(|name| Lint::from_name(name.trim())),
+            );
+        }
+    }
+    allowed
+}
+
+/// What a declared name is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Variable,
+    Parameter,
+    Function,
+}
+
+/// A declaration, see [`symbols`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol<'a> {
+    pub name: Ident<'a>,
+    pub kind: SymbolKind,
+    /// The whole declaration, including the body of a function.
+    pub span: Span,
+    /// The parameter names of a function.
+    pub params: Vec<&'a str>,
+    /// The function the symbol is declared in, `None` for the top level.
+    pub parent: Option<usize>,
+}
+
+/// The declarations of a program and the identifiers referring to them.
+#[derive(Debug, Clone, Default)]
+pub struct Symbols<'a> {
+    pub symbols: Vec<Symbol<'a>>,
+    /// Spans of identifiers that read or assign a symbol, with its index in `symbols`.
+    pub references: Vec<(Span, usize)>,
+}
+
+impl<'a> Symbols<'a> {
+    /// The symbol named by the identifier at byte `offset`, either its declaration or a
+    /// reference to it.
+    pub fn at(&self, offset: usize) -> Option<&Symbol<'a>> {
+        let contains = |span: &Span| span.start <= offset && offset <= span.end;
+        self.symbols
+            .iter()
+            .find(|symbol| contains(&symbol.name.span))
+            .or_else(|| {
+                let (_, id) = self.references.iter().find(|(span, _)| contains(span))?;
+                Some(&self.symbols[*id])
+            })
+    }
+}
+
+/// Resolves every name in `program` to its declaration, names that are declared nowhere are
+/// left out.
+pub fn symbols<'a>(program: &Program<'a>) -> Symbols<'a> {
+    let mut resolver = Resolver::default();
+    resolver.declare_globals(&program.stmts);
+    resolver.statements(&program.stmts);
+
+    let symbols = resolver
+        .decls
+        .into_iter()
+        .map(|decl| Symbol {
+            name: decl.name,
+            kind: decl.kind,
+            span: decl.span,
+            params: decl.params,
+            parent: decl.parent,
+        })
+        .collect();
+    Symbols {
+        symbols,
+        references: resolver.references,
+    }
+}
+
+/// Checks `program` for suspicious code, returning the warnings not allowed by `source`
+/// in source order.
+pub fn resolve(source: &str, program: &Program) -> Vec<Warning> {
+    let mut resolver = Resolver::default();
+    resolver.declare_globals(&program.stmts);
+    resolver.statements(&program.stmts);
+    resolver.check_calls();
+
+    let allowed = allowed_lints(source);
+    let mut warnings = resolver.warnings;
+    warnings.retain(|warning| !allowed.contains(&warning.lint));
+    warnings.sort_by_key(|warning| warning.span.start);
+    warnings
+}
+
+struct Decl<'a> {
+    name: Ident<'a>,
+    kind: SymbolKind,
+    span: Span,
+    params: Vec<&'a str>,
+    parent: Option<usize>,
+    // the number of parameters, as long as the name surely refers to that function
+    arity: Option<usize>,
+    used: bool,
+    reassigned: bool,
+}
+
+impl<'a> Decl<'a> {
+    fn set_params(&mut self, params: &[Ident<'a>]) {
+        self.params = params.iter().map(|param| param.name).collect();
+        self.arity = Some(params.len());
+    }
+}
+
+struct Call {
+    decl: usize,
+    arg_count: usize,
+    span: Span,
+}
+
+#[derive(Default)]
+struct Resolver<'a> {
+    decls: Vec<Decl<'a>>,
+    globals: HashMap<&'a str, usize>,
+    // the scopes of the function being resolved, innermost last
+    scopes: Vec<Vec<usize>>,
+    // calls are checked once every assignment has been seen
+    calls: Vec<Call>,
+    warnings: Vec<Warning>,
+    references: Vec<(Span, usize)>,
+    // the declaration of the function being resolved
+    function: Option<usize>,
+}
+
+impl<'a> Resolver<'a> {
+    fn warn(&mut self, lint: Lint, span: Span, message: String) {
+        self.warnings.push(Warning {
+            lint,
+            message,
+            span,
+        });
+    }
+
+    fn add_decl(&mut self, name: Ident<'a>, kind: SymbolKind, span: Span) -> usize {
+        self.decls.push(Decl {
+            name,
+            kind,
+            span,
+            params: Vec::new(),
+            parent: self.function,
+            arity: None,
+            used: false,
+            reassigned: false,
+        });
+        self.decls.len() - 1
+    }
+
+    // Globals can be used before their declaration, e.g. in a function declared earlier.
+    fn declare_globals(&mut self, stmts: &[Stmt<'a>]) {
+        for stmt in stmts {
+            let (name, kind, params) = match &stmt.kind {
+                StmtKind::Var { name, .. } => (*name, SymbolKind::Variable, None),
+                StmtKind::Fun(decl) => (decl.name, SymbolKind::Function, Some(&decl.params)),
+                _ => continue,
+            };
+            let id = self.add_decl(name, kind, stmt.span);
+            if let Some(params) = params {
+                self.decls[id].set_params(params);
+            }
+            match self.globals.get(name.name) {
+                // declared twice, a call may refer to either declaration
+                Some(&first) => self.decls[first].arity = None,
+                None => {
+                    self.globals.insert(name.name, id);
+                }
+            }
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    fn end_scope(&mut self) {
+        let scope = self.scopes.pop().unwrap_or_default();
+        for id in scope {
+            let decl = &self.decls[id];
+            if decl.used || decl.name.name.starts_with('_') {
+                continue;
+            }
+            let what = match decl.kind {
+                SymbolKind::Variable => "local variable",
+                SymbolKind::Parameter => "parameter",
+                SymbolKind::Function => "local function",
+            };
+            let message = format!("Unused {} '{}'.", what, decl.name.name);
+            self.warn(Lint::Unused, decl.name.span, message);
+        }
+    }
+
+    // Declares `name` in the current scope, at the top level it was declared up front.
+    fn declare(&mut self, name: Ident<'a>, kind: SymbolKind, span: Span) -> usize {
+        let Some(scope) = self.scopes.last() else {
+            return self
+                .decls
+                .iter()
+                .position(|decl| decl.name.span == name.span)
+                .expect("top level declarations are declared up front");
+        };
+        let already_declared = scope
+            .iter()
+            .any(|&id| self.decls[id].name.name == name.name);
+        let shadowed = self.scopes[..self.scopes.len() - 1]
+            .iter()
+            .flatten()
+            .any(|&id| self.decls[id].name.name == name.name);
+        // redeclaring in the same scope is a compile error, nothing to warn about
+        if shadowed && !already_declared {
+            let message = format!("Local '{}' shadows a local of an outer scope.", name.name);
+            self.warn(Lint::Shadow, name.span, message);
+        }
+
+        let id = self.add_decl(name, kind, span);
+        self.scopes.last_mut().unwrap().push(id);
+        id
+    }
+
+    fn reference(&mut self, name: &Ident<'a>) -> Option<usize> {
+        let id = self.lookup(name.name)?;
+        self.references.push((name.span, id));
+        Some(id)
+    }
+
+    fn lookup(&self, name: &str) -> Option<usize> {
+        let local = self
+            .scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|&&id| self.decls[id].name.name == name);
+        local.or_else(|| self.globals.get(name)).copied()
+    }
+
+    fn statements(&mut self, stmts: &[Stmt<'a>]) {
+        let mut returned = false;
+        for stmt in stmts {
+            if returned {
+                let message = "Unreachable code after 'return'.".to_string();
+                self.warn(Lint::Unreachable, stmt.span, message);
+                returned = false;
+            } else {
+                returned = always_returns(stmt);
+            }
+            self.statement(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt<'a>) {
+        match &stmt.kind {
+            StmtKind::Expression(expr) | StmtKind::Print(expr) => self.expression(expr),
+            StmtKind::Var { name, init } => {
+                if let Some(init) = init {
+                    self.expression(init);
+                }
+                self.declare(*name, SymbolKind::Variable, stmt.span);
+            }
+            StmtKind::Fun(decl) => {
+                let id = self.declare(decl.name, SymbolKind::Function, stmt.span);
+                // top level functions got theirs up front
+                if !self.scopes.is_empty() {
+                    self.decls[id].set_params(&decl.params);
+                }
+                self.function(decl, id);
+            }
+            StmtKind::Block(stmts) => {
+                self.begin_scope();
+                self.statements(stmts);
+                self.end_scope();
+            }
+            StmtKind::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(cond);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            StmtKind::While { cond, body } => {
+                self.expression(cond);
+                self.statement(body);
+            }
+            StmtKind::For {
+                init,
+                cond,
+                increment,
+                body,
+            } => {
+                self.begin_scope();
+                if let Some(init) = init {
+                    self.statement(init);
+                }
+                if let Some(cond) = cond {
+                    self.expression(cond);
+                }
+                if let Some(increment) = increment {
+                    self.expression(increment);
+                }
+                self.statement(body);
+                self.end_scope();
+            }
+            StmtKind::Return(value) => {
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+            StmtKind::Test { body, .. } => {
+                self.begin_scope();
+                self.statements(body);
+                self.end_scope();
+            }
+        }
+    }
+
+    fn function(&mut self, decl: &FunDecl<'a>, id: usize) {
+        // functions can't see the locals of the function they are declared in
+        let enclosing = std::mem::take(&mut self.scopes);
+        let enclosing_function = self.function.replace(id);
+
+        self.begin_scope();
+        for &param in decl.params.iter() {
+            self.declare(param, SymbolKind::Parameter, param.span);
+        }
+        self.begin_scope();
+        self.statements(&decl.body);
+        self.end_scope();
+        self.end_scope();
+
+        self.scopes = enclosing;
+        self.function = enclosing_function;
+    }
+
+    fn expression(&mut self, expr: &Expr<'a>) {
+        match &expr.kind {
+            ExprKind::Literal(_) => {}
+            ExprKind::Variable(name) => {
+                if let Some(id) = self.reference(name) {
+                    self.decls[id].used = true;
+                }
+            }
+            ExprKind::Assign { name, value } => {
+                self.expression(value);
+                match self.reference(name) {
+                    Some(id) => self.decls[id].reassigned = true,
+                    None => {
+                        let message = format!("Assignment to undeclared global '{}'.", name.name);
+                        self.warn(Lint::Undeclared, name.span, message);
+                    }
+                }
+            }
+            ExprKind::Unary { operand, .. } => self.expression(operand),
+            ExprKind::Binary { left, right, .. } | ExprKind::Logical { left, right, .. } => {
+                self.expression(left);
+                self.expression(right);
+            }
+            ExprKind::Grouping(inner) => self.expression(inner),
+            ExprKind::Call { callee, args } => {
+                self.expression(callee);
+                if let ExprKind::Variable(name) = &callee.kind {
+                    if let Some(decl) = self.lookup(name.name) {
+                        self.calls.push(Call {
+                            decl,
+                            arg_count: args.len(),
+                            span: expr.span,
+                        });
+                    }
+                }
+                for arg in args {
+                    self.expression(arg);
+                }
+            }
+        }
+    }
+
+    fn check_calls(&mut self) {
+        for call in std::mem::take(&mut self.calls) {
+            let decl = &self.decls[call.decl];
+            let Some(arity) = decl.arity.filter(|_| !decl.reassigned) else {
+                continue;
+            };
+            if arity != call.arg_count {
+                let message = format!(
+                    "'{}' expects {} arguments but is called with {}.",
+                    decl.name.name, arity, call.arg_count
+                );
+                self.warn(Lint::Arity, call.span, message);
+            }
+        }
+    }
+}
+
+// Whether control never gets past `stmt`.
+fn always_returns(stmt: &Stmt) -> bool {
+    match &stmt.kind {
+        StmtKind::Return(_) => true,
+        StmtKind::Block(stmts) => stmts.iter().any(always_returns),
+        StmtKind::If {
+            then_branch,
+            else_branch: Some(else_branch),
+            ..
+        } => always_returns(then_branch) && always_returns(else_branch),
+        _ => false,
+    }
+}
@@ -1,12 +1,15 @@
 use std::{iter::Peekable, slice::Iter};
 
-use crate::{token::Token, token::TokenKind, Error, Result};
+use crate::{ast::Span, diagnostic::Diagnostic, token::Token, token::TokenKind, Error, Result};
+#[derive(Clone)]
 pub struct Scanner<'a> {
     source: &'a str,
     byte_iter: Peekable<Iter<'a, u8>>,
     start: usize,
     current: usize,
     line: usize,
+    // the `//` comments skipped so far, they are trivia the parser never sees
+    comments: Vec<Span>,
 }
 
 impl<'a> Scanner<'a> {
@@ -17,9 +20,16 @@ impl<'a> Scanner<'a> {
             start: 0,
             current: 0,
             line: 1,
+            comments: Vec::new(),
         }
     }
 
+    /// The `//` comments scanned so far, in source order. Each span covers the comment up to
+    /// the end of its line.
+    pub fn comments(&self) -> &[Span] {
+        &self.comments
+    }
+
     fn advance(&mut self) -> Option<u8> {
         self.current += 1;
         self.byte_iter.next().copied()
@@ -29,6 +39,10 @@ impl<'a> Scanner<'a> {
         self.byte_iter.peek().copied().copied()
     }
 
+    fn peek_next(&self) -> Option<u8> {
+        self.source.as_bytes().get(self.current + 1).copied()
+    }
+
     fn match_next(&mut self, expected: u8) -> bool {
         if self.peek() == Some(expected) {
             self.advance();
@@ -39,7 +53,7 @@ impl<'a> Scanner<'a> {
     }
 
     fn make_token(&self, kind: TokenKind<'a>) -> Token<'a> {
-        Token::new(kind, self.line, self.start)
+        Token::new(kind, self.line, self.start, self.current)
     }
 
     fn skip_whitespace(&mut self) -> Result<()> {
@@ -54,15 +68,17 @@ impl<'a> Scanner<'a> {
                     self.advance();
                 }
 
-                Some(b'/') => {
-                    self.advance();
-                    if self.match_next(b'/') {
-                        while self.peek() != Some(b'\n') {
-                            self.advance();
-                        }
-                    } else {
-                        return Err(self.error("Unexpected character"));
+                Some(b'/') if self.peek_next() == Some(b'/') => {
+                    let start = self.current;
+                    while self.peek().is_some_and(|ch| ch != b'\n') {
+                        self.advance();
                     }
+                    self.comments.push(Span {
+                        start,
+                        end: self.current,
+                        line: self.line,
+                        end_line: self.line,
+                    });
                 }
                 _ => {
                     break;
@@ -87,10 +103,21 @@ impl<'a> Scanner<'a> {
     }
 
     fn error(&self, msg: &str) -> Error {
-        Error::from(format!(
+        let message = format!(
             "error: {} at line {}, column {}-{} ('{}')",
-            msg, self.line, self.start, self.current - 1, &self.source[self.start..self.current] 
-        ))
+            msg,
+            self.line,
+            self.start,
+            self.current - 1,
+            &self.source[self.start..self.current]
+        );
+        let span = Span {
+            start: self.start,
+            end: self.current,
+            line: self.line,
+            end_line: self.line,
+        };
+        Error::from(Diagnostic::new(message, span))
     }
 
     pub fn scan_token(&mut self) -> Result<Token<'a>> {
@@ -109,6 +136,7 @@ impl<'a> Scanner<'a> {
                 b'+' => self.make_token(TokenKind::Plus),
                 b';' => self.make_token(TokenKind::Semicolon),
                 b'*' => self.make_token(TokenKind::Star),
+                b'/' => self.make_token(TokenKind::Slash),
 
                 b'!' => self.make_token_match(b'=', TokenKind::Bang, TokenKind::BangEqual),
                 b'=' => self.make_token_match(b'=', TokenKind::Equal, TokenKind::EqualEqual),
@@ -121,7 +149,13 @@ impl<'a> Scanner<'a> {
 
                 b'a'..=b'z' | b'A'..=b'Z' | b'_' => self.make_identifier(),
 
-                _ => return Err(self.error("Unexpected character")),
+                _ => {
+                    // the rest of a multi-byte character, so the error can quote all of it
+                    while self.peek().is_some_and(|byte| byte & 0xC0 == 0x80) {
+                        self.advance();
+                    }
+                    return Err(self.error("Unexpected character"));
+                }
             })
         } else {
             Ok(self.make_token(TokenKind::Eof))
@@ -163,9 +197,12 @@ impl<'a> Scanner<'a> {
             }
         }
 
-        Ok(self.make_token(TokenKind::Number(
-            self.source[self.start..self.current].parse().unwrap(),
-        )))
+        // digits with an optional fraction always parse, but a malformed literal is the
+        // script's error rather than a panic
+        let number = self.source[self.start..self.current]
+            .parse()
+            .map_err(|_| self.error("Invalid number."))?;
+        Ok(self.make_token(TokenKind::Number(number)))
     }
 
     fn make_identifier(&mut self) -> Token<'a> {
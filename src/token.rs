@@ -60,8 +60,6 @@ impl<'a> TokenKind<'a> {
 
             TokenKind::Slash | TokenKind::Star => Precedence::Factor,
 
-            TokenKind::Bang => Precedence::Unary,
-
             TokenKind::EqualEqual | TokenKind::BangEqual => Precedence::Equality,
 
             TokenKind::Greater
@@ -71,8 +69,6 @@ impl<'a> TokenKind<'a> {
 
             TokenKind::Plus | TokenKind::Minus => Precedence::Term,
 
-            TokenKind::Equal => Precedence::Assignment,
-
             TokenKind::And => Precedence::And,
 
             TokenKind::Or => Precedence::Or,
@@ -137,19 +133,26 @@ impl<'a> fmt::Display for TokenKind<'a> {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
 pub struct Token<'a> {
     kind: TokenKind<'a>,
     line: usize,
     start: usize,
+    end: usize,
 }
 
 impl<'a> Token<'a> {
-    pub fn new(kind: TokenKind<'a>, line: usize, start: usize) -> Self {
-        Self { kind, start, line }
+    pub fn new(kind: TokenKind<'a>, line: usize, start: usize, end: usize) -> Self {
+        Self {
+            kind,
+            start,
+            end,
+            line,
+        }
     }
 
     pub fn none() -> Self {
-        Self::new(TokenKind::None, 0, 0)
+        Self::new(TokenKind::None, 0, 0, 0)
     }
     pub fn kind(&self) -> TokenKind<'a> {
         self.kind
@@ -160,4 +163,7 @@ impl<'a> Token<'a> {
     pub fn start(&self) -> usize {
         self.start
     }
+    pub fn end(&self) -> usize {
+        self.end
+    }
 }
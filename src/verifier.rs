@@ -0,0 +1,335 @@
+use std::fmt;
+
+use crate::bytecode::{Chunk, FunctionObj, OpCode, Value};
+
+/// What is wrong with an instruction, see [`VerifyError`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyErrorKind {
+    InvalidInstruction(u8),
+    TruncatedInstruction,
+    ConstantOutOfRange {
+        index: u16,
+        len: usize,
+    },
+    GlobalOutOfRange {
+        slot: u16,
+        len: usize,
+    },
+    LocalOutOfRange {
+        slot: u16,
+        depth: usize,
+    },
+    UnpatchedJump,
+    JumpOutOfRange {
+        target: isize,
+    },
+    StackUnderflow {
+        needed: usize,
+        depth: usize,
+    },
+    InconsistentStack {
+        expected: usize,
+        found: usize,
+    },
+    /// Execution can reach the end of a function's code without returning.
+    MissingReturn,
+}
+
+impl fmt::Display for VerifyErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidInstruction(byte) => write!(f, "invalid instruction {:#04x}", byte),
+            Self::TruncatedInstruction => write!(f, "truncated instruction"),
+            Self::ConstantOutOfRange { index, len } => {
+                write!(f, "constant #{} out of range, the chunk has {}", index, len)
+            }
+            Self::GlobalOutOfRange { slot, len } => {
+                write!(
+                    f,
+                    "global slot {} out of range, the program has {}",
+                    slot, len
+                )
+            }
+            Self::LocalOutOfRange { slot, depth } => {
+                write!(
+                    f,
+                    "local slot {} out of range, the frame holds {}",
+                    slot, depth
+                )
+            }
+            Self::UnpatchedJump => write!(f, "jump without a target"),
+            Self::JumpOutOfRange { target } => {
+                write!(f, "jump to {} is not the start of an instruction", target)
+            }
+            Self::StackUnderflow { needed, depth } => write!(
+                f,
+                "instruction needs {} values but the frame holds {}",
+                needed, depth
+            ),
+            Self::InconsistentStack { expected, found } => write!(
+                f,
+                "stack depth {} differs from {} on another path",
+                found, expected
+            ),
+            Self::MissingReturn => write!(f, "function can end without returning"),
+        }
+    }
+}
+
+/// An instruction that would make the VM misbehave, found by [`verify`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    function: String,
+    offset: usize,
+    kind: VerifyErrorKind,
+}
+
+impl VerifyError {
+    pub fn function(&self) -> &str {
+        &self.function
+    }
+
+    /// Byte offset of the instruction in the function's chunk.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn kind(&self) -> &VerifyErrorKind {
+        &self.kind
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid bytecode in {}() at offset {}: {}",
+            self.function, self.offset, self.kind
+        )
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Checks that `main` and every function nested in it can be executed safely:
+/// instructions decode, constant, global and local operands are in range, jumps land on
+/// instructions, and every reachable instruction sees the same stack depth on all paths
+/// with enough values for its operands.
+///
+/// Global slots index the names table of `main` rather than constants, so their range is
+/// checked against it.
+pub fn verify(main: &FunctionObj) -> Result<(), VerifyError> {
+    let globals = main.global_names().len();
+    verify_function(main, globals, true)
+}
+
+fn verify_function(fun: &FunctionObj, globals: usize, is_main: bool) -> Result<(), VerifyError> {
+    Verifier {
+        fun,
+        chunk: fun.chunk(),
+        globals,
+        is_main,
+    }
+    .run()?;
+
+    for constant in fun.chunk().constants() {
+        if let Value::Function(nested) = constant {
+            verify_function(nested, globals, false)?;
+        }
+    }
+    Ok(())
+}
+
+struct Verifier<'a> {
+    fun: &'a FunctionObj,
+    chunk: &'a Chunk,
+    globals: usize,
+    is_main: bool,
+}
+
+// How an instruction continues, besides falling through to the next one.
+enum Flow {
+    Next,
+    Branch(usize),
+    Jump(usize),
+    Return,
+}
+
+impl<'a> Verifier<'a> {
+    fn error(&self, offset: usize, kind: VerifyErrorKind) -> VerifyError {
+        VerifyError {
+            function: self.fun.name().to_string(),
+            offset,
+            kind,
+        }
+    }
+
+    fn run(&self) -> Result<(), VerifyError> {
+        let code = self.chunk.code();
+
+        // decode everything first, so jumps can be checked against instruction starts
+        let mut instructions = vec![None; code.len()];
+        let mut offset = 0;
+        while offset < code.len() {
+            let Some((ins, len)) = self.chunk.decode_ins(offset) else {
+                let byte = code[offset];
+                let kind = if is_known_tag(byte) {
+                    VerifyErrorKind::TruncatedInstruction
+                } else {
+                    VerifyErrorKind::InvalidInstruction(byte)
+                };
+                return Err(self.error(offset, kind));
+            };
+            self.check_operands(offset, ins)?;
+            instructions[offset] = Some((ins, len));
+            offset += len;
+        }
+
+        // stack depth at each instruction start, relative to the frame, which starts with the
+        // called function and its arguments
+        let mut depths: Vec<Option<usize>> = vec![None; code.len() + 1];
+        let entry = 1 + self.fun.arity() as usize;
+        let mut pending = vec![(0, entry)];
+
+        while let Some((offset, depth)) = pending.pop() {
+            match depths[offset] {
+                Some(expected) if expected != depth => {
+                    let found = depth;
+                    return Err(self.error(
+                        offset,
+                        VerifyErrorKind::InconsistentStack { expected, found },
+                    ));
+                }
+                Some(_) => continue,
+                None => depths[offset] = Some(depth),
+            }
+            if offset == code.len() {
+                // falling off the end only finishes the program, the VM doesn't return from a call there
+                if !self.is_main {
+                    return Err(self.error(offset, VerifyErrorKind::MissingReturn));
+                }
+                continue;
+            }
+
+            let (ins, len) = instructions[offset].expect("offsets come from decoded instructions");
+            let (needed, pushed) = stack_effect(ins);
+            if depth < needed {
+                return Err(self.error(offset, VerifyErrorKind::StackUnderflow { needed, depth }));
+            }
+            if let OpCode::GetLocal(slot) | OpCode::SetLocal(slot) = ins {
+                if slot as usize >= depth {
+                    return Err(
+                        self.error(offset, VerifyErrorKind::LocalOutOfRange { slot, depth })
+                    );
+                }
+            }
+            let after = depth - needed + pushed;
+
+            let next = offset + len;
+            match self.flow(offset, ins, &instructions)? {
+                Flow::Next => pending.push((next, after)),
+                Flow::Branch(target) => {
+                    pending.push((next, after));
+                    pending.push((target, after));
+                }
+                Flow::Jump(target) => pending.push((target, after)),
+                Flow::Return => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn check_operands(&self, offset: usize, ins: OpCode) -> Result<(), VerifyError> {
+        match ins {
+            OpCode::Constant(index) => {
+                let len = self.chunk.constants().len();
+                if index as usize >= len {
+                    return Err(
+                        self.error(offset, VerifyErrorKind::ConstantOutOfRange { index, len })
+                    );
+                }
+            }
+            OpCode::DefineGlobal(slot) | OpCode::GetGlobal(slot) | OpCode::SetGlobal(slot) => {
+                let len = self.globals;
+                if slot as usize >= len {
+                    return Err(self.error(offset, VerifyErrorKind::GlobalOutOfRange { slot, len }));
+                }
+            }
+            OpCode::Jump(None) | OpCode::JumpIfFalse(None) => {
+                return Err(self.error(offset, VerifyErrorKind::UnpatchedJump));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn flow(
+        &self,
+        offset: usize,
+        ins: OpCode,
+        instructions: &[Option<(OpCode, usize)>],
+    ) -> Result<Flow, VerifyError> {
+        // jumps are relative to the end of the jump instruction
+        let end = (offset + OpCode::JUMP_SIZE) as isize;
+        let target = match ins {
+            OpCode::Jump(Some(jump)) | OpCode::JumpIfFalse(Some(jump)) => end + jump as isize,
+            OpCode::Loop(jump) => end - jump as isize,
+            OpCode::Return => return Ok(Flow::Return),
+            _ => return Ok(Flow::Next),
+        };
+
+        let len = instructions.len() as isize;
+        let lands =
+            target == len || (0..len).contains(&target) && instructions[target as usize].is_some();
+        if !lands {
+            return Err(self.error(offset, VerifyErrorKind::JumpOutOfRange { target }));
+        }
+        let target = target as usize;
+        Ok(match ins {
+            OpCode::JumpIfFalse(_) => Flow::Branch(target),
+            _ => Flow::Jump(target),
+        })
+    }
+}
+
+// Whether `byte` starts an instruction, which then only fails to decode if its operand is cut off.
+fn is_known_tag(byte: u8) -> bool {
+    // every instruction decodes when followed by enough zero operand bytes
+    OpCode::decode(&[byte, 0, 0], 0).is_some()
+}
+
+// How many values an instruction needs on the stack, and how many it leaves in their place.
+fn stack_effect(ins: OpCode) -> (usize, usize) {
+    match ins {
+        OpCode::Constant(_)
+        | OpCode::True
+        | OpCode::False
+        | OpCode::Nil
+        | OpCode::GetLocal(_)
+        | OpCode::GetGlobal(_) => (0, 1),
+
+        OpCode::Pop | OpCode::Print | OpCode::DefineGlobal(_) => (1, 0),
+
+        OpCode::SetGlobal(_) | OpCode::SetLocal(_) | OpCode::JumpIfFalse(_) => (1, 1),
+        OpCode::Negate | OpCode::Not => (1, 1),
+
+        OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::Less
+        | OpCode::Greater
+        | OpCode::Equal
+        | OpCode::LessEqual
+        | OpCode::GreaterEqual
+        | OpCode::NotEqual => (2, 1),
+
+        // the callee and its arguments are replaced by the result
+        OpCode::Call(arg_count) => (arg_count as usize + 1, 1),
+
+        OpCode::Jump(_) | OpCode::Loop(_) => (0, 0),
+
+        // the returned value and the frame's own function slot
+        OpCode::Return => (2, 0),
+    }
+}
@@ -1,11 +1,14 @@
 use std::io;
 use std::io::Write;
-use std::{collections::HashMap, rc::Rc};
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::{collections::HashSet, fmt, rc::Rc};
 
 use crate::bytecode::FunctionObj;
 use crate::{
-    bytecode::{self, OpCode, Value},
-    Error, Result,
+    bytecode::{self, tag, OpCode, Value},
+    natives, verifier, Error, Result,
 };
 
 struct CallFrame {
@@ -24,37 +27,364 @@ impl CallFrame {
     }
 }
 
+/// Limits enforced while compiling and running a script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VmConfig {
+    /// Maximum depth of the call stack, including the implicit main function.
+    pub max_frames: usize,
+    /// Maximum number of values on the value stack, across all frames.
+    pub max_stack: usize,
+    /// Maximum number of local variable slots in a single function.
+    pub max_locals: usize,
+    /// Number of instructions a single `VM::run` may execute, unlimited if `None`.
+    pub max_instructions: Option<u64>,
+    /// Bytes of live heap objects (strings and functions) the script may hold, unlimited if `None`.
+    pub max_memory: Option<usize>,
+}
+
+impl VmConfig {
+    const DEFAULT_FRAMES: usize = 256;
+    const DEFAULT_LOCALS: usize = 256;
+    // locals are addressed with a u16 operand
+    pub const LOCALS_LIMIT: usize = u16::MAX as usize + 1;
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        Self {
+            max_frames: Self::DEFAULT_FRAMES,
+            max_stack: Self::DEFAULT_FRAMES * Self::DEFAULT_LOCALS,
+            max_locals: Self::DEFAULT_LOCALS,
+            max_instructions: None,
+            max_memory: None,
+        }
+    }
+}
+
+/// Lets a host stop a running VM, possibly from another thread.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests the VM to stop at its next call or backward jump.
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Clears a pending interrupt so the handle can be reused for another run.
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    /// An error raised by the executed code, like a type error or an undefined variable.
+    Script,
+    StackOverflow,
+    OutOfMemory,
+    BudgetExhausted,
+    Interrupted,
+}
+
+/// Error returned by `VM::run`, boxed into `crate::Error`.
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    kind: RuntimeErrorKind,
+    message: String,
+    // (line, function name), innermost frame first
+    trace: Vec<(usize, String)>,
+}
+
+impl RuntimeError {
+    pub(crate) fn new(
+        kind: RuntimeErrorKind,
+        message: String,
+        trace: Vec<(usize, String)>,
+    ) -> Self {
+        Self {
+            kind,
+            message,
+            trace,
+        }
+    }
+
+    pub fn kind(&self) -> RuntimeErrorKind {
+        self.kind
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn trace(&self) -> &[(usize, String)] {
+        &self.trace
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let header = match self.kind {
+            RuntimeErrorKind::Interrupted => "Interrupted",
+            _ => "Runtime error",
+        };
+        write!(f, "{}: {} \nstack trace:", header, self.message)?;
+        for (line, name) in self.trace.iter() {
+            write!(f, "\n[line {}] in {}()", line, name)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// The VM right before it executes an instruction, as seen by a tracer.
+pub struct Trace<'t> {
+    /// Function of the running frame.
+    pub function: &'t FunctionObj,
+    /// Number of frames on the call stack, 1 while in the main function.
+    pub depth: usize,
+    /// Byte offset of `ins` in the function's chunk.
+    pub offset: usize,
+    pub ins: OpCode,
+    /// The whole value stack, bottom first.
+    pub stack: &'t [Value],
+    /// Index in `stack` of the running frame's slot 0, the called function.
+    pub frame_start: usize,
+}
+
+impl Trace<'_> {
+    pub fn line(&self) -> usize {
+        self.function.chunk().get_line(self.offset)
+    }
+
+    /// The running frame's slots: the called function, its arguments, locals and temporaries.
+    pub fn frame_slots(&self) -> &[Value] {
+        &self.stack[self.frame_start..]
+    }
+}
+
+/// The stack with the running frame marked by `|`, then the instruction.
+impl fmt::Display for Trace<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "stack: [")?;
+        for (i, v) in self.stack.iter().enumerate() {
+            if i == self.frame_start {
+                write!(f, " | ")?;
+            }
+            write!(f, "{}, ", v)?;
+        }
+        writeln!(f, "]")?;
+        writeln!(
+            f,
+            "ins:   {}",
+            self.function.chunk().dissassemble_ins(self.offset)
+        )
+    }
+}
+
+/// A call frame of a paused VM, see `VM::call_stack`.
+pub struct Frame<'t> {
+    pub function: &'t FunctionObj,
+    /// Offset of the instruction the frame is at: the next one to run in the innermost frame,
+    /// the call in progress in the others.
+    pub offset: usize,
+    /// The frame's slots, starting with the called function.
+    pub slots: &'t [Value],
+}
+
+impl Frame<'_> {
+    pub fn line(&self) -> usize {
+        self.function.chunk().get_line(self.offset)
+    }
+
+    /// The named locals in scope at `offset` and their values, by slot.
+    pub fn locals(&self) -> Vec<(&str, &Value)> {
+        self.function
+            .locals_at(self.offset)
+            .into_iter()
+            .filter_map(|local| {
+                let value = self.slots.get(local.slot as usize)?;
+                Some((local.name.as_str(), value))
+            })
+            .collect()
+    }
+}
+
+/// Called with every instruction before it runs, see `VM::set_tracer`.
+pub type Tracer<'a> = Box<dyn FnMut(&Trace) + 'a>;
+
 pub struct VM<'a> {
+    // the running frame is kept out of `frames` to make instruction fetches cheap
+    frame: CallFrame,
+    // suspended callers, outermost first
     frames: Vec<CallFrame>,
-    lock: io::StdoutLock<'a>,
+    // where `print` writes, stdout unless the host redirects it
+    out: Box<dyn Write + 'a>,
     stack: Vec<bytecode::Value>,
-    globals: HashMap<Rc<String>, Value>,
+    // indexed by slot, `None` until the global is defined
+    globals: Vec<Option<Value>>,
+    global_names: Vec<String>,
+    config: VmConfig,
+    interrupt: InterruptHandle,
+    // upper bound of the live heap: the last measurement and the bytes allocated since,
+    // see `track_alloc`
+    bytes_allocated: usize,
+    // the live heap at the last measurement
+    bytes_measured: usize,
+    tracer: Option<Tracer<'a>>,
+    // the code is verified once, before the first instruction runs
+    verified: bool,
+    // set when the main function returns
+    finished: bool,
+    // instructions executed by `run` and `step` so far, see `count_instructions`
+    executed: u64,
+    counting: bool,
 }
 
 impl<'a> VM<'a> {
-    const FRAME_MAX: usize = 256;
-    const STACK_MAX: usize = 256;
+    // the stack starts small and grows on demand up to `VmConfig::max_stack`
+    const STACK_INITIAL: usize = 256;
+
     pub fn with_code(code: FunctionObj) -> Self {
-        let mut stack = Vec::with_capacity(Self::STACK_MAX);
+        Self::with_config(code, VmConfig::default())
+    }
+
+    pub fn with_config(code: FunctionObj, config: VmConfig) -> Self {
+        let mut stack = Vec::with_capacity(Self::STACK_INITIAL.min(config.max_stack));
+        let global_names = code.global_names().to_vec();
         let code = Rc::new(code);
         stack.push(Value::Function(Rc::clone(&code)));
 
         let frame = CallFrame::new(0, code);
-        Self {
-            frames: vec![frame],
-            lock: io::stdout().lock(),
+        let mut vm = Self {
+            frame,
+            frames: Vec::new(),
+            out: Box::new(io::stdout().lock()),
             stack,
-            globals: HashMap::new(),
-        }
+            globals: global_names
+                .iter()
+                .map(|name| natives::lookup(name).map(Value::Native))
+                .collect(),
+            global_names,
+            config,
+            interrupt: InterruptHandle::new(),
+            bytes_allocated: 0,
+            bytes_measured: 0,
+            tracer: None,
+            verified: false,
+            finished: false,
+            executed: 0,
+            counting: cfg!(feature = "bench"),
+        };
+        vm.bytes_allocated = vm.live_bytes();
+        vm.bytes_measured = vm.bytes_allocated;
+        vm
     }
 
-    fn frame_stack(&self) -> &[Value] {
-        &self.stack[self.curr_frame().stack_start..]
+    pub fn config(&self) -> &VmConfig {
+        &self.config
     }
 
-    fn frame_stack_mut(&mut self) -> &mut [Value] {
-        let start = self.curr_frame().stack_start;
-        &mut self.stack[start..]
+    /// Returns a handle that stops this VM when interrupted.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    /// Makes the VM listen to an existing handle, e.g. one shared with a signal handler.
+    pub fn set_interrupt_handle(&mut self, handle: InterruptHandle) {
+        self.interrupt = handle;
+    }
+
+    /// Sends the output of `print` statements to `out` instead of stdout.
+    pub fn set_output(&mut self, out: Box<dyn Write + 'a>) {
+        self.out = out;
+    }
+
+    /// Calls `tracer` before every instruction executed from now on, `None` stops tracing.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer<'a>>) {
+        self.tracer = tracer;
+    }
+
+    /// Makes `run` count the instructions it executes, which it otherwise only does with
+    /// `VmConfig::max_instructions` set or a tracer, as counting slows the loop down.
+    pub fn count_instructions(&mut self) {
+        self.counting = true;
+    }
+
+    /// Number of instructions executed so far, see `count_instructions`.
+    pub fn instructions_executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// Upper bound of the bytes held by heap objects, exact right after a measurement.
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    /// Sums the heap objects reachable from the stack, the globals and the call frames.
+    /// Objects shared through `Rc` are counted once.
+    fn live_bytes(&self) -> usize {
+        fn visit(value: &Value, seen: &mut HashSet<*const ()>) -> usize {
+            let ptr = match value {
+                Value::String(s) => Rc::as_ptr(s) as *const (),
+                Value::Function(f) => Rc::as_ptr(f) as *const (),
+                _ => return 0,
+            };
+            if !seen.insert(ptr) {
+                return 0;
+            }
+            let mut size = value.heap_size();
+            if let Value::Function(f) = value {
+                for constant in f.chunk().constants() {
+                    size += visit(constant, seen);
+                }
+            }
+            size
+        }
+
+        let mut seen = HashSet::new();
+        let frames = self
+            .frames()
+            .map(|frame| Value::Function(Rc::clone(&frame.function)));
+        let globals = self.globals.iter().flatten().cloned();
+
+        frames
+            .chain(globals)
+            .chain(self.stack.iter().cloned())
+            .map(|value| visit(&value, &mut seen))
+            .sum()
+    }
+
+    /// Accounts for a new heap object of `bytes` before it is allocated.
+    /// Once the count passes `VmConfig::max_memory` the live heap is measured,
+    /// so memory that was already freed does not count against the script.
+    /// Measuring walks the whole heap, so it is done at most once every eighth of the limit
+    /// allocated: a script whose live heap is past 7/8 of the limit may run out a bit early.
+    fn track_alloc(&mut self, bytes: usize) -> Result<()> {
+        self.bytes_allocated += bytes;
+        let Some(limit) = self.config.max_memory else {
+            return Ok(());
+        };
+        if self.bytes_allocated > limit {
+            if self.bytes_allocated - self.bytes_measured >= limit / 8 {
+                self.bytes_measured = self.live_bytes();
+                self.bytes_allocated = self.bytes_measured + bytes;
+            }
+            if self.bytes_allocated > limit {
+                self.bytes_allocated -= bytes;
+                return Err(self.error_of_kind(RuntimeErrorKind::OutOfMemory, "Out of memory"));
+            }
+        }
+        Ok(())
     }
 
     fn stack_get(&self, index: usize) -> &Value {
@@ -67,15 +397,20 @@ impl<'a> VM<'a> {
 
     fn curr_frame(&self) -> &CallFrame {
         // theres always a frame, because all the code is wrapped in implicit 1main function
-        self.frames.last().unwrap()
+        &self.frame
     }
 
     fn curr_frame_mut(&mut self) -> &mut CallFrame {
-        self.frames.last_mut().unwrap()
+        &mut self.frame
+    }
+
+    // innermost first
+    fn frames(&self) -> impl Iterator<Item = &CallFrame> {
+        std::iter::once(&self.frame).chain(self.frames.iter().rev())
     }
 
     fn chunk(&self) -> &bytecode::Chunk {
-        self.frames.last().unwrap().function.chunk()
+        self.frame.function.chunk()
     }
 
     fn ip(&self) -> usize {
@@ -86,117 +421,292 @@ impl<'a> VM<'a> {
         &mut self.curr_frame_mut().ip
     }
 
-    fn _trace(&mut self) {
-        // writeln!(self.lock, "stack: {:?}", self.stack).unwrap();
-        write!(self.lock, "stack: [").unwrap();
-        for (i, v) in self.stack.iter().enumerate() {
-            if i == self.curr_frame().stack_start {
-                write!(self.lock, " | ").unwrap();
-            }
-            write!(self.lock, "{}, ", v).unwrap();
-        }
-        writeln!(self.lock, "]").unwrap();
-        writeln!(
-            self.lock,
-            "ins:   {}\n",
-            self.chunk().dissassemble_ins(self.ip())
-        )
-        .unwrap();
+    // not inlined into the traced loop of `run`, tracing is slow anyway
+    #[inline(never)]
+    fn trace(&mut self) {
+        let Some(tracer) = self.tracer.as_mut() else {
+            return;
+        };
+        let frame = &self.frame;
+        let (ins, _) = frame
+            .function
+            .chunk()
+            .decode_ins(frame.ip)
+            .expect("verified code decodes");
+        tracer(&Trace {
+            function: &frame.function,
+            depth: self.frames.len() + 1,
+            offset: frame.ip,
+            ins,
+            stack: &self.stack,
+            frame_start: frame.stack_start,
+        });
+    }
+
+    /// The call frames, innermost first.
+    pub fn call_stack(&self) -> Vec<Frame<'_>> {
+        let mut end = self.stack.len();
+        self.frames()
+            .enumerate()
+            .map(|(i, frame)| {
+                let offset = match i {
+                    0 => frame.ip,
+                    // callers are suspended right after their `Call`
+                    _ => frame.ip - OpCode::CALL_SIZE,
+                };
+                let slots = &self.stack[frame.stack_start.min(end)..end];
+                end = frame.stack_start;
+                Frame {
+                    function: &frame.function,
+                    offset,
+                    slots,
+                }
+            })
+            .collect()
     }
 
-    pub fn run(&mut self) -> crate::Result<()> {
-        #[cfg(feature = "trace")]
-        writeln!(self.lock, "=== TRACE ===").unwrap();
+    /// The global variables by name, with their value once defined.
+    pub fn globals(&self) -> impl Iterator<Item = (&str, Option<&Value>)> {
+        self.global_names
+            .iter()
+            .map(String::as_str)
+            .zip(self.globals.iter().map(Option::as_ref))
+    }
 
-        #[cfg(feature = "bench")]
-        let start = std::time::Instant::now();
+    /// Whether the script has run to its end.
+    pub fn is_finished(&self) -> bool {
+        self.finished || self.is_at_end()
+    }
 
-        while !self.is_at_end() {
-            // #[cfg(feature = "trace")]
-            // self._trace();
+    // Checks the code before it first runs.
+    fn prepare(&mut self) -> Result<()> {
+        if self.verified {
+            return Ok(());
+        }
+        // loaded code is not trusted, every instruction below assumes a well-formed chunk
+        verifier::verify(&self.frame.function)?;
 
-            let _return = self.execute_ins()?;
-            if _return {
-                break;
-            }
+        // the compiled code itself may not fit
+        self.track_alloc(0)?;
+        self.verified = true;
+        Ok(())
+    }
+
+    fn check_interrupt(&self) -> Result<()> {
+        if self.interrupt.is_interrupted() {
+            return Err(self.error_of_kind(RuntimeErrorKind::Interrupted, "Execution interrupted"));
+        }
+        Ok(())
+    }
+
+    // The interrupt is only checked by calls and backward jumps, code without either runs to
+    // its end in at most as many instructions as it has. The instruction is put back, so that
+    // an interrupted script resumes with it.
+    #[inline]
+    fn check_interrupt_at_ins(&mut self) -> Result<()> {
+        if self.interrupt.is_interrupted() {
+            *self.ip_mut() -= 1;
+            return self.check_interrupt();
         }
+        Ok(())
+    }
+
+    /// Executes a single instruction and returns whether the script has finished, for hosts
+    /// that pause between instructions like the debugger.
+    /// Unlike `run`, steps don't count against `VmConfig::max_instructions`.
+    pub fn step(&mut self) -> Result<bool> {
+        self.prepare()?;
+        if self.is_finished() {
+            return Ok(true);
+        }
+        self.check_interrupt()?;
+        if self.tracer.is_some() {
+            self.trace();
+        }
+        self.executed += 1;
+        if self.execute_ins()? {
+            self.finished = true;
+        }
+        Ok(self.is_finished())
+    }
+
+    /// Runs `function` to its end in place of the main function, which has to be finished,
+    /// keeping the globals it defined. It is how the tests of a script run.
+    pub fn run_function(&mut self, function: Rc<FunctionObj>) -> Result<()> {
+        // a failed function leaves its frames behind
+        self.frames.clear();
+        self.stack.clear();
+        self.stack.push(Value::Function(Rc::clone(&function)));
+        self.frame = CallFrame::new(0, function);
+        self.finished = false;
+        self.run()
+    }
+
+    pub fn run(&mut self) -> crate::Result<()> {
+        #[cfg(feature = "bench")]
+        let start = std::time::Instant::now();
+        #[cfg(feature = "bench")]
+        let executed = self.executed;
+
+        self.prepare()?;
+        self.check_interrupt()?;
+
+        let budget = self.config.max_instructions;
+        // counted apart from `self.executed`, which the loop would otherwise store to every time
+        let mut count = 0;
+        let result = match (self.tracer.is_some(), self.counting || budget.is_some()) {
+            (true, _) => self.run_loop::<true, true>(budget.unwrap_or(u64::MAX), &mut count),
+            (false, true) => self.run_loop::<false, true>(budget.unwrap_or(u64::MAX), &mut count),
+            (false, false) => self.run_loop::<false, false>(u64::MAX, &mut count),
+        };
+        self.executed += count;
+        result?;
 
         if !self.stack.len() == 1 {
             eprintln!("WARNING: stack is not empty at the end of execution");
         }
 
         #[cfg(feature = "bench")]
-        writeln!(
-            self.lock,
-            "=== BENCH ===\nelapsed time:{:?}",
-            start.elapsed()
-        );
+        {
+            let elapsed = start.elapsed();
+            let executed = self.executed - executed;
+            writeln!(
+                self.out,
+                "=== BENCH ===\ninstructions: {} ({:.1}M/s)\nelapsed time:{:?}",
+                executed,
+                executed as f64 / elapsed.as_secs_f64() / 1e6,
+                elapsed
+            )?;
+        }
+
+        Ok(())
+    }
 
+    // The instruction loop of `run`, with the tracing and the counting compiled out of the loops
+    // that don't need them.
+    #[inline(always)]
+    fn run_loop<const TRACE: bool, const COUNT: bool>(
+        &mut self,
+        budget: u64,
+        executed: &mut u64,
+    ) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        while !self.is_at_end() {
+            if COUNT {
+                if *executed == budget {
+                    return Err(self.error_of_kind(
+                        RuntimeErrorKind::BudgetExhausted,
+                        "Instruction budget exhausted",
+                    ));
+                }
+                *executed += 1;
+            }
+            if TRACE {
+                self.trace();
+            }
+            if self.execute_ins()? {
+                self.finished = true;
+                break;
+            }
+        }
         Ok(())
     }
 
+    #[inline(always)]
     fn execute_ins(&mut self) -> Result<bool> {
         // return if we want to stop execution
-        match *self.advance() {
-            OpCode::Constant(index) => self.add_const(index),
-            OpCode::Print => self.print()?,
-            OpCode::Pop => {
+        match self.read_byte() {
+            tag::CONSTANT => {
+                let index = self.read_index();
+                self.add_const(index)?
+            }
+            tag::PRINT => self.print()?,
+            tag::POP => {
                 self.pop_stack();
             }
-            OpCode::DefineGlobal(index) => self.define_global(index),
-            OpCode::GetGlobal(index) => self.get_global(index)?,
-            OpCode::SetGlobal(index) => self.set_global(index)?,
+            tag::DEFINE_GLOBAL => {
+                let index = self.read_index();
+                self.define_global(index)
+            }
+            tag::GET_GLOBAL => {
+                let index = self.read_index();
+                self.get_global(index)?
+            }
+            tag::SET_GLOBAL => {
+                let index = self.read_index();
+                self.set_global(index)?
+            }
 
-            OpCode::GetLocal(offset) => self.push_stack(self.stack_get(offset as usize).clone()),
-            OpCode::SetLocal(offset) => {
+            tag::GET_LOCAL => {
+                let offset = self.read_index();
+                self.push_stack(self.stack_get(offset as usize).clone())?
+            }
+            tag::SET_LOCAL => {
+                let offset = self.read_index();
                 *self.stack_get_mut(offset as usize) = self.peek_stack_unwrapped(0).clone();
             }
 
-            OpCode::JumpIfFalse(offset) => {
-                let offset =
-                    offset.expect("Internal error: jump instruction has no offset") as usize;
+            tag::JUMP_IF_FALSE => {
+                let offset = self.read_jump() as usize;
                 if !self.peek_stack_unwrapped(0).is_truthy() {
                     *self.ip_mut() += offset;
                 }
             }
-            OpCode::Jump(offset) => {
-                let offset =
-                    offset.expect("Internal error: jump instruction has no offset") as usize;
+            tag::JUMP => {
+                let offset = self.read_jump() as usize;
                 *self.ip_mut() += offset;
             }
 
-            OpCode::Loop(offset) => {
-                *self.ip_mut() -= offset as usize;
+            tag::LOOP => {
+                self.check_interrupt_at_ins()?;
+                let offset = self.read_jump() as usize;
+                *self.ip_mut() -= offset;
             }
 
-            OpCode::Call(arg_count) => self.call(arg_count)?,
+            tag::CALL => {
+                self.check_interrupt_at_ins()?;
+                let arg_count = self.read_byte();
+                self.call(arg_count)?
+            }
 
-            OpCode::True => self.push_stack(Value::Boolean(true)),
-            OpCode::False => self.push_stack(Value::Boolean(false)),
-            OpCode::Nil => self.push_stack(Value::Nil),
+            tag::TRUE => self.push_stack(Value::Boolean(true))?,
+            tag::FALSE => self.push_stack(Value::Boolean(false))?,
+            tag::NIL => self.push_stack(Value::Nil)?,
 
-            OpCode::Negate => self.negate()?,
-            OpCode::Not => self.not()?,
+            tag::NEGATE => self.negate()?,
+            tag::NOT => self.not()?,
 
-            op @ (OpCode::Greater | OpCode::Less) => self.comparison(op)?,
-            OpCode::Equal => self.equality(),
+            tag::GREATER => self.comparison(OpCode::Greater)?,
+            tag::LESS => self.comparison(OpCode::Less)?,
+            tag::EQUAL => self.equality(false)?,
+            tag::LESS_EQUAL => self.comparison(OpCode::LessEqual)?,
+            tag::GREATER_EQUAL => self.comparison(OpCode::GreaterEqual)?,
+            tag::NOT_EQUAL => self.equality(true)?,
 
-            OpCode::Add => self.add()?,
-            op @ (OpCode::Subtract | OpCode::Multiply | OpCode::Divide) => self.binary(op)?,
+            tag::ADD => self.add()?,
+            tag::SUBTRACT => self.binary(OpCode::Subtract)?,
+            tag::MULTIPLY => self.binary(OpCode::Multiply)?,
+            tag::DIVIDE => self.binary(OpCode::Divide)?,
 
-            OpCode::Return => {
+            tag::RETURN => {
                 let ret = self.pop_stack();
-                let frame = self.frames.pop().unwrap();
-
-                let stack_start = frame.stack_start;
-
-                if self.frames.is_empty() {
+                let Some(caller) = self.frames.pop() else {
                     self.pop_stack();
                     return Ok(true);
-                }
-                self.stack.truncate(stack_start);
-                self.push_stack(ret);
+                };
+                let frame = mem::replace(&mut self.frame, caller);
+
+                self.stack.truncate(frame.stack_start);
+                self.push_stack(ret)?;
             }
+
+            byte => self.internal_error(&format!(
+                "invalid instruction {:#04x} at offset {}",
+                byte,
+                self.ip() - 1
+            )),
         }
         Ok(false)
     }
@@ -213,11 +723,26 @@ impl<'a> VM<'a> {
                         f.name()
                     )));
                 }
-                if self.frames.len() == Self::FRAME_MAX {
-                    return Err(self.runtime_error("Stack overflow"));
+                if self.frames.len() + 1 >= self.config.max_frames {
+                    return Err(self.stack_overflow());
                 }
                 let frame = CallFrame::new(self.stack.len() - arg_count as usize - 1, Rc::clone(f));
-                self.frames.push(frame);
+                self.frames.push(mem::replace(&mut self.frame, frame));
+            }
+            Value::Native(native) => {
+                let native = *native;
+                if arg_count != native.arity {
+                    return Err(self.runtime_error(&format!(
+                        "Expected {} arguments but got {} in call to {}()",
+                        native.arity, arg_count, native.name
+                    )));
+                }
+                let args_start = self.stack.len() - arg_count as usize;
+                let result = (native.fun)(&self.stack[args_start..])
+                    .map_err(|message| self.runtime_error(&message))?;
+                // the arguments and the native itself
+                self.stack.truncate(args_start - 1);
+                self.push_stack(result)?;
             }
             _ => return Err(self.runtime_error(&format!("Can only call functions, not {}", calee))),
         }
@@ -225,63 +750,66 @@ impl<'a> VM<'a> {
         Ok(())
     }
 
-    fn define_global(&mut self, index: u16) {
-        if let Value::String(s) = self.chunk().get_const(index) {
-            let ident = Rc::clone(s);
-            let val = self.pop_stack();
-            self.globals.insert(ident, val);
-        } else {
-            panic!("define global: expected string")
+    fn global_mut(&mut self, slot: u16) -> &mut Option<Value> {
+        if slot as usize >= self.globals.len() {
+            self.internal_error(&format!("global slot {} out of range", slot))
         }
+        &mut self.globals[slot as usize]
     }
 
-    fn get_global(&mut self, index: u16) -> Result<()> {
-        if let Value::String(ident) = self.chunk().get_const(index) {
-            let val = self.globals.get(ident).ok_or_else(|| {
-                self.runtime_error(&format!("Undefined global variable '{ident}'"))
-            })?;
-            let val = val.clone();
-            self.push_stack(val);
-            Ok(())
-        } else {
-            self.internal_error("get global: expected string")
+    fn undefined_global(&self, slot: u16) -> Error {
+        let name = &self.global_names[slot as usize];
+        self.runtime_error(&format!("Undefined global variable '{name}'"))
+    }
+
+    fn define_global(&mut self, slot: u16) {
+        let val = self.pop_stack();
+        *self.global_mut(slot) = Some(val);
+    }
+
+    fn get_global(&mut self, slot: u16) -> Result<()> {
+        match self.global_mut(slot) {
+            Some(val) => {
+                let val = val.clone();
+                self.push_stack(val)
+            }
+            None => Err(self.undefined_global(slot)),
         }
     }
 
-    fn set_global(&mut self, index: u16) -> Result<()> {
-        if let Value::String(s) = self.chunk().get_const(index) {
-            let ident = Rc::clone(s);
-            let val = self.peek_stack_unwrapped(0).clone();
-            let present = self.globals.contains_key(&ident);
-            if present {
-                self.globals.insert(ident, val);
+    fn set_global(&mut self, slot: u16) -> Result<()> {
+        let val = self.peek_stack_unwrapped(0).clone();
+        match self.global_mut(slot) {
+            Some(global) => {
+                *global = val;
                 Ok(())
-            } else {
-                Err(self.runtime_error(&format!("Undefined global variable '{ident}'")))
             }
-        } else {
-            self.internal_error("set global: expected string")
+            None => Err(self.undefined_global(slot)),
         }
     }
 
     fn print(&mut self) -> Result<()> {
         let val = self.pop_stack();
-        writeln!(self.lock, "{}", val)?;
+        writeln!(self.out, "{}", val)?;
         Ok(())
     }
 
-    fn add_const(&mut self, id: u16) {
+    fn add_const(&mut self, id: u16) -> Result<()> {
         // todo- remove that clone
-        self.push_stack(self.chunk().get_const(id).clone());
+        self.push_stack(self.chunk().get_const(id).clone())
     }
 
-    fn push_stack(&mut self, v: Value) {
-        self.stack.push(v)
+    fn push_stack(&mut self, v: Value) -> Result<()> {
+        if self.stack.len() >= self.config.max_stack {
+            return Err(self.stack_overflow());
+        }
+        self.stack.push(v);
+        Ok(())
     }
 
     fn negate(&mut self) -> Result<()> {
         match self.pop_stack() {
-            Value::Number(n) => self.push_stack(Value::Number(-n)),
+            Value::Number(n) => self.stack.push(Value::Number(-n)),
             v => return Err(self.runtime_error(&format!("Cannot negate {v}"))),
         };
         Ok(())
@@ -289,14 +817,20 @@ impl<'a> VM<'a> {
 
     fn not(&mut self) -> Result<()> {
         match self.pop_stack() {
-            Value::Boolean(b) => self.push_stack(Value::Boolean(!b)),
-            Value::Nil => self.push_stack(Value::Boolean(true)),
+            Value::Boolean(b) => self.stack.push(Value::Boolean(!b)),
+            Value::Nil => self.stack.push(Value::Boolean(true)),
             v => return Err(self.runtime_error(&format!("Cannot perform '!' operation on {v}"))),
         }
         Ok(())
     }
 
     fn add(&mut self) -> Result<()> {
+        // accounted for while the operands are on the stack, where measuring the heap finds them
+        if let (Value::String(s1), Value::String(s2)) =
+            (self.peek_stack_unwrapped(1), self.peek_stack_unwrapped(0))
+        {
+            self.track_alloc(mem::size_of::<String>() + s1.len() + s2.len())?;
+        }
         let b = self.pop_stack();
         let a = self.pop_stack();
         match (a, b) {
@@ -304,7 +838,10 @@ impl<'a> VM<'a> {
                 self.stack.push(Value::Number(a + b));
             }
             (Value::String(s1), Value::String(s2)) => {
-                self.stack.push(Value::String(Rc::new(format!("{s1}{s2}"))));
+                let mut s = String::with_capacity(s1.len() + s2.len());
+                s.push_str(&s1);
+                s.push_str(&s2);
+                self.stack.push(Value::String(Rc::new(s)));
             }
             (a, b) => return Err(self.runtime_error(&format!("Cannot add {a} and {b}"))),
         };
@@ -349,18 +886,7 @@ impl<'a> VM<'a> {
     //     self.stack.get_mut(self.stack.len() - 1 - offset)
     // }
 
-    fn peek_number(&self, offset: usize) -> Result<&f64> {
-        match self.peek_stack(offset) {
-            None => self.internal_error(&format!(
-                "Expected value at stack at index {} but found no value",
-                self.stack.len() - 1 - offset
-            )),
-
-            Some(Value::Number(n)) => Ok(n),
-            Some(v) => Err(self.runtime_error(&format!("Expected a number but got {}", v))),
-        }
-    }
-
+    #[cold]
     fn internal_error(&self, msg: &str) -> ! {
         panic!("Internal error: {}", msg)
     }
@@ -380,39 +906,89 @@ impl<'a> VM<'a> {
     fn comparison(&mut self, operator: OpCode) -> Result<()> {
         let b = self.pop_number()?;
         let a = self.pop_number()?;
-        self.push_stack(Value::Boolean(match operator {
+        self.stack.push(Value::Boolean(match operator {
             OpCode::Greater => a > b,
             OpCode::Less => a < b,
+            // "not greater" rather than `<=`, to match the unfused `Greater` `Not` pair on NaN
+            OpCode::LessEqual => a.partial_cmp(&b) != Some(std::cmp::Ordering::Greater),
+            OpCode::GreaterEqual => a.partial_cmp(&b) != Some(std::cmp::Ordering::Less),
             _ => unreachable!(),
         }));
         Ok(())
     }
 
-    fn equality(&mut self) {
+    fn equality(&mut self, negate: bool) -> Result<()> {
         let b = self.pop_stack();
         let a = self.pop_stack();
 
-        self.push_stack(Value::Boolean(a == b))
+        self.push_stack(Value::Boolean((a == b) != negate))
     }
 
     fn runtime_error(&self, msg: &str) -> Error {
-        let mut full_msg = format!("Runtime error: {} \nstack trace:", msg);
+        self.error_of_kind(RuntimeErrorKind::Script, msg)
+    }
 
-        for frame in self.frames.iter().rev() {
-            let func = &frame.function;
-            let line = func.chunk().get_line(frame.ip - 1);
-            full_msg.push_str(&format!("\n[line {}] in {}()", line, func.name()));
-        }
+    fn stack_overflow(&self) -> Error {
+        self.error_of_kind(RuntimeErrorKind::StackOverflow, "Stack overflow")
+    }
+
+    fn error_of_kind(&self, kind: RuntimeErrorKind, msg: &str) -> Error {
+        let trace = self
+            .frames()
+            .map(|frame| {
+                let func = &frame.function;
+                // a frame that was just entered has not executed anything yet
+                let line = func.chunk().get_line(frame.ip.saturating_sub(1));
+                (line, func.name().to_string())
+            })
+            .collect();
 
-        return Error::from(full_msg);
+        Error::from(RuntimeError {
+            kind,
+            message: msg.to_string(),
+            trace,
+        })
     }
 
     fn is_at_end(&self) -> bool {
         self.ip() >= self.chunk().code().len()
     }
 
-    fn advance(&mut self) -> &bytecode::OpCode {
-        *self.ip_mut() += 1;
-        &self.chunk().code()[self.ip() - 1]
+    #[inline]
+    fn read_byte(&mut self) -> u8 {
+        let frame = &mut self.frame;
+        let byte = frame.function.chunk().code()[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    #[inline]
+    fn read_index(&mut self) -> u16 {
+        // most indices fit in a single varint byte
+        let first = self.read_byte();
+        if first < 0x80 {
+            first as u16
+        } else {
+            self.read_index_long()
+        }
+    }
+
+    #[inline(never)]
+    fn read_index_long(&mut self) -> u16 {
+        let start = self.ip() - 1;
+        let Some((index, len)) = bytecode::read_varint(self.chunk().code(), start) else {
+            self.internal_error("truncated instruction operand")
+        };
+        *self.ip_mut() = start + len;
+        index
+    }
+
+    #[inline]
+    fn read_jump(&mut self) -> u16 {
+        let frame = &mut self.frame;
+        let code = frame.function.chunk().code();
+        let jump = u16::from_le_bytes([code[frame.ip], code[frame.ip + 1]]);
+        frame.ip += 2;
+        jump
     }
 }
@@ -0,0 +1,51 @@
+//! The statistics of `rlox bench`.
+
+use std::time::Duration;
+
+use rlox::{bench, Options};
+
+fn measurement(millis: &[u64], instructions: u64) -> bench::Measurement {
+    bench::Measurement {
+        name: "test".to_string(),
+        runs: millis.iter().map(|ms| Duration::from_millis(*ms)).collect(),
+        instructions,
+    }
+}
+
+fn assert_close(actual: f64, expected: f64) {
+    assert!(
+        (actual - expected).abs() < 1e-9,
+        "{} != {}",
+        actual,
+        expected
+    );
+}
+
+#[test]
+fn computes_the_mean_and_sample_standard_deviation() {
+    let measurement = measurement(&[10, 20, 30, 40], 1000);
+    assert_close(measurement.mean(), 0.025);
+    // the squares add up to 500 ms², over 3 degrees of freedom
+    assert_close(measurement.stddev(), (500.0f64 / 3.0).sqrt() / 1e3);
+    assert_close(measurement.instructions_per_second(), 40_000.0);
+}
+
+#[test]
+fn has_no_deviation_with_fewer_than_two_runs() {
+    assert_close(measurement(&[15], 0).stddev(), 0.0);
+    assert_close(measurement(&[], 0).mean(), 0.0);
+    assert_close(measurement(&[], 0).stddev(), 0.0);
+}
+
+#[test]
+fn measures_the_runs_and_counts_the_instructions_once() {
+    let path = std::env::temp_dir().join(format!("rlox-bench-{}.lox", std::process::id()));
+    std::fs::write(&path, "var a = 1;\nprint a + 2;\n").unwrap();
+    let measurement = bench::measure(&path, 3, &Options::default()).expect("the script runs");
+    std::fs::remove_file(&path).ok();
+
+    assert_eq!(measurement.runs.len(), 3);
+    // constant, define, get, constant, add and print, counted in the warm-up run only
+    assert_eq!(measurement.instructions, 6);
+    assert!(measurement.mean() > 0.0);
+}
@@ -0,0 +1,205 @@
+//! The `rlox` binary: its arguments, what it prints and how it exits.
+
+use std::{
+    fs,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+};
+
+// `source` in a file of its own.
+fn script(name: &str, source: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("rlox-cli-{}-{}.lox", std::process::id(), name));
+    fs::write(&path, source).expect("the script is written");
+    path
+}
+
+// Runs `rlox` with `args`, feeding it `stdin`.
+fn rlox(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("rlox starts");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .expect("stdin is written");
+    child.wait_with_output().expect("rlox exits")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+fn exit_code(output: &Output) -> i32 {
+    output.status.code().expect("rlox exited by itself")
+}
+
+#[test]
+fn exits_with_71_past_the_frame_and_stack_limits() {
+    let path = script("recurse", "fun f(n) { return f(n + 1); }\nf(0);\n");
+    let path = path.to_str().unwrap();
+    for args in [["--max-frames", "10", path], ["--max-stack", "20", path]] {
+        let output = rlox(&args, "");
+        assert_eq!(exit_code(&output), 71, "{:?}", args);
+        assert!(
+            stderr(&output).starts_with("Runtime error: Stack overflow"),
+            "{}",
+            stderr(&output)
+        );
+    }
+    fs::remove_file(path).ok();
+}
+
+#[test]
+fn rejects_limits_that_are_not_positive() {
+    for value in ["0", "-1", "many"] {
+        let output = rlox(&["eval", "--max-frames", value, "-e", "print 1;"], "");
+        assert_eq!(exit_code(&output), 64, "{}", value);
+        assert!(
+            stderr(&output).contains("--max-frames expects a positive integer"),
+            "{}",
+            stderr(&output)
+        );
+        assert_eq!(stdout(&output), "");
+    }
+}
+
+#[test]
+fn exits_with_71_past_the_instruction_budget() {
+    let output = rlox(
+        &[
+            "eval",
+            "--max-instructions",
+            "1000",
+            "-e",
+            "while (true) {}",
+        ],
+        "",
+    );
+    assert_eq!(exit_code(&output), 71);
+    assert!(
+        stderr(&output).starts_with("Runtime error: Instruction budget exhausted"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn exits_with_71_past_the_memory_limit() {
+    let output = rlox(
+        &[
+            "eval",
+            "--max-memory",
+            "10000",
+            "-e",
+            "var s = \"x\"; while (true) s = s + s;",
+        ],
+        "",
+    );
+    assert_eq!(exit_code(&output), 71);
+    assert!(
+        stderr(&output).starts_with("Runtime error: Out of memory"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn prints_warnings_only_for_scripts_in_files() {
+    let source = "fun f(a) { return 1; }\nprint f(2);\n";
+    let path = script("warnings", source);
+    let output = rlox(&[path.to_str().unwrap()], "");
+    assert_eq!(exit_code(&output), 0);
+    assert_eq!(
+        stderr(&output),
+        "warning[unused]: Unused parameter 'a'. at line 1\n"
+    );
+    fs::remove_file(path).ok();
+
+    let output = rlox(&["eval", "-e", source], "");
+    assert_eq!(stdout(&output), "1\n");
+    assert_eq!(stderr(&output), "");
+}
+
+const PRINT_ARGS: &str = "print argc();\nfor (var i = 0; i < argc(); i = i + 1) print arg(i);\n";
+
+#[test]
+fn passes_everything_after_the_script_to_it() {
+    let path = script("args", PRINT_ARGS);
+    let path = path.to_str().unwrap();
+    // options after the script are its arguments, `--` lets the script start with a dash
+    for args in [
+        vec![path, "x", "--max-frames", "-e"],
+        vec!["run", "--", path, "x", "--max-frames", "-e"],
+    ] {
+        let output = rlox(&args, "");
+        assert_eq!(exit_code(&output), 0, "{}", stderr(&output));
+        assert_eq!(stdout(&output), "3\nx\n--max-frames\n-e\n");
+    }
+    fs::remove_file(path).ok();
+}
+
+#[test]
+fn passes_everything_after_the_code_to_eval() {
+    let output = rlox(&["eval", "-e", PRINT_ARGS, "--trace", "--"], "");
+    assert_eq!(exit_code(&output), 0, "{}", stderr(&output));
+    assert_eq!(stdout(&output), "2\n--trace\n--\n");
+}
+
+#[test]
+fn reads_the_script_dash_from_stdin() {
+    let output = rlox(&["-", "a", "b"], PRINT_ARGS);
+    assert_eq!(exit_code(&output), 0, "{}", stderr(&output));
+    assert_eq!(stdout(&output), "2\na\nb\n");
+}
+
+#[test]
+fn exits_with_64_on_usage_errors() {
+    for args in [
+        vec!["--bogus"],
+        vec!["eval"],
+        vec!["eval", "-e"],
+        vec!["repl", "script.lox"],
+        vec!["disasm"],
+        vec!["-O7", "script.lox"],
+    ] {
+        let output = rlox(&args, "");
+        assert_eq!(exit_code(&output), 64, "{:?}", args);
+        assert!(stderr(&output).contains("Usage: rlox"), "{:?}", args);
+        assert_eq!(stdout(&output), "", "{:?}", args);
+    }
+}
+
+#[test]
+fn exits_with_65_on_compile_errors() {
+    let output = rlox(&["eval", "-e", "print 1;\nprint ;"], "");
+    assert_eq!(exit_code(&output), 65);
+    // nothing runs
+    assert_eq!(stdout(&output), "");
+    assert!(stderr(&output).contains("at line 2"), "{}", stderr(&output));
+}
+
+#[test]
+fn exits_with_70_on_runtime_errors() {
+    let path = script("runtime", "print 1;\nprint -nil;\nprint 2;\n");
+    let output = rlox(&[path.to_str().unwrap()], "");
+    fs::remove_file(&path).ok();
+    assert_eq!(exit_code(&output), 70);
+    assert_eq!(stdout(&output), "1\n");
+    assert!(
+        stderr(&output).starts_with("Runtime error: Cannot negate"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(stderr(&output).contains("[line 2] in <Main>()"));
+}
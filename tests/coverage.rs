@@ -0,0 +1,82 @@
+//! The lcov tracefile of a script: its functions, branches and lines.
+
+use rlox::{coverage::Coverage, vm::VM, Options};
+
+const SCRIPT: &str = "\
+fun never() {
+  return 1;
+}
+fun twice(n) {
+  if (n > 0) print n;
+  return n;
+}
+twice(1);
+twice(2);
+";
+
+fn lcov(source: &str) -> Vec<String> {
+    let code = rlox::compile(source, &Options::default()).expect("the script compiles");
+    let mut coverage = None;
+    let mut vm = VM::with_code(code);
+    vm.set_output(Box::new(std::io::sink()));
+    // the functions as the VM holds them, coverage finds them by address
+    let coverage = coverage.insert(Coverage::new(vm.call_stack()[0].function));
+    vm.set_tracer(Some(Box::new(|trace| coverage.record(trace))));
+    vm.run().expect("the script runs");
+    drop(vm);
+    coverage
+        .to_lcov("script.lox")
+        .lines()
+        .map(String::from)
+        .collect()
+}
+
+// The records of `lcov` whose kind is `kind`, without it.
+fn records<'a>(lcov: &'a [String], kind: &str) -> Vec<&'a str> {
+    lcov.iter()
+        .filter_map(|line| line.strip_prefix(kind)?.strip_prefix(':'))
+        .collect()
+}
+
+#[test]
+fn reports_functions_including_those_never_called() {
+    let lcov = lcov(SCRIPT);
+    assert_eq!(lcov[..2], ["TN:", "SF:script.lox"]);
+    assert_eq!(records(&lcov, "FN"), ["1,never", "4,twice"]);
+    assert_eq!(records(&lcov, "FNDA"), ["0,never", "2,twice"]);
+    assert_eq!(records(&lcov, "FNF"), ["2"]);
+    assert_eq!(records(&lcov, "FNH"), ["1"]);
+    assert_eq!(lcov.last().unwrap(), "end_of_record");
+}
+
+#[test]
+fn reports_both_sides_of_a_branch_that_only_went_one_way() {
+    let lcov = lcov(SCRIPT);
+    // the condition was true both times and never false
+    assert_eq!(records(&lcov, "BRDA"), ["5,0,0,2", "5,0,1,0"]);
+    assert_eq!(records(&lcov, "BRF"), ["2"]);
+    assert_eq!(records(&lcov, "BRH"), ["1"]);
+}
+
+#[test]
+fn marks_branches_whose_condition_never_ran() {
+    let source = "fun f(n) { if (n) print n; }\nvar a = false;\nif (a) f(1);";
+    let lcov = lcov(source);
+    assert_eq!(
+        records(&lcov, "BRDA"),
+        ["3,0,0,0", "3,0,1,1", "1,1,0,-", "1,1,1,-"]
+    );
+    assert_eq!(records(&lcov, "BRH"), ["1"]);
+}
+
+#[test]
+fn counts_each_line_as_its_most_executed_instruction() {
+    let lcov = lcov(SCRIPT);
+    // the body of `never` never runs, but its declaration does
+    assert_eq!(
+        records(&lcov, "DA"),
+        ["2,0", "3,1", "5,2", "6,2", "7,1", "8,1", "9,1"]
+    );
+    assert_eq!(records(&lcov, "LF"), ["7"]);
+    assert_eq!(records(&lcov, "LH"), ["6"]);
+}
@@ -0,0 +1,356 @@
+//! A scripted client driving `dap::serve` through a session, over in-memory streams.
+
+use std::{fs, path::PathBuf};
+
+use rlox::{
+    dap,
+    json::{self, Json},
+    Options,
+};
+
+const SCRIPT: &str = "\
+fun add(a, b) {
+    var sum = a + b;
+    return sum;
+}
+var x = add(1, 2);
+print x;
+print \"done\";
+";
+
+// The script in a file of its own, `launch` takes a path.
+fn script(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("rlox-dap-{}-{}.lox", std::process::id(), name));
+    fs::write(&path, SCRIPT).expect("the script is written");
+    path
+}
+
+// Runs a session of `requests`, each a command and its arguments, and returns every message
+// the server sent.
+fn session(requests: &[(&str, Json)]) -> Vec<Json> {
+    let mut input = Vec::new();
+    for (seq, (command, arguments)) in requests.iter().enumerate() {
+        let request = Json::object([
+            ("seq", (seq + 1).into()),
+            ("type", "request".into()),
+            ("command", (*command).into()),
+            ("arguments", arguments.clone()),
+        ]);
+        json::write_message(&mut input, &request).expect("the request is written");
+    }
+    let mut output = Vec::new();
+    dap::serve(input.as_slice(), &mut output, &Options::default()).expect("the session ends");
+
+    let mut output = output.as_slice();
+    let mut messages = Vec::new();
+    while let Some(message) = json::read_message(&mut output).expect("a framed message") {
+        messages.push(message);
+    }
+    messages
+}
+
+fn str_field<'a>(message: &'a Json, key: &str) -> &'a str {
+    message.get(key).and_then(Json::as_str).unwrap_or_default()
+}
+
+// The response to `command`, which has to have succeeded.
+fn response<'a>(messages: &'a [Json], command: &str) -> &'a Json {
+    let response = messages
+        .iter()
+        .find(|m| str_field(m, "type") == "response" && str_field(m, "command") == command)
+        .unwrap_or_else(|| panic!("no response to {}", command));
+    assert_eq!(
+        response.get("success").and_then(Json::as_bool),
+        Some(true),
+        "{}",
+        response
+    );
+    response.get("body").expect("a body")
+}
+
+// What happened, in order: responses by command, events by name with the reason they stopped.
+fn timeline(messages: &[Json]) -> Vec<String> {
+    messages
+        .iter()
+        .map(|m| match str_field(m, "type") {
+            "response" => str_field(m, "command").to_string(),
+            _ => match m.get("body").and_then(|body| body.get("reason")) {
+                Some(reason) => format!("{}({})", str_field(m, "event"), reason.as_str().unwrap()),
+                None => format!("{}!", str_field(m, "event")),
+            },
+        })
+        .collect()
+}
+
+fn names_and_values(body: &Json) -> Vec<(String, String)> {
+    body.get("variables")
+        .and_then(Json::as_array)
+        .expect("variables")
+        .iter()
+        .map(|v| (str_field(v, "name").into(), str_field(v, "value").into()))
+        .collect()
+}
+
+#[test]
+fn stops_at_a_breakpoint_then_steps_and_continues() {
+    let path = script("breakpoint");
+    let program = path.to_string_lossy().into_owned();
+    let messages = session(&[
+        ("initialize", Json::object([("adapterID", "rlox".into())])),
+        (
+            "launch",
+            Json::object([("program", program.as_str().into())]),
+        ),
+        (
+            "setBreakpoints",
+            Json::object([(
+                "breakpoints",
+                vec![Json::object([("line", 3.into())])].into(),
+            )]),
+        ),
+        ("configurationDone", Json::object::<&str>([])),
+        ("stackTrace", Json::object([("threadId", 1.into())])),
+        ("scopes", Json::object([("frameId", 0.into())])),
+        (
+            "variables",
+            Json::object([("variablesReference", 2.into())]),
+        ),
+        (
+            "variables",
+            Json::object([("variablesReference", 1.into())]),
+        ),
+        ("next", Json::object([("threadId", 1.into())])),
+        ("continue", Json::object([("threadId", 1.into())])),
+        ("disconnect", Json::object::<&str>([])),
+    ]);
+    fs::remove_file(&path).ok();
+
+    assert_eq!(
+        timeline(&messages),
+        [
+            "initialize",
+            "initialized!",
+            "launch",
+            "setBreakpoints",
+            "configurationDone",
+            "stopped(breakpoint)",
+            "stackTrace",
+            "scopes",
+            "variables",
+            "variables",
+            "next",
+            "stopped(step)",
+            "continue",
+            "output!",
+            "exited!",
+            "terminated!",
+            "disconnect",
+        ]
+    );
+    // sequence numbers go up by one across responses and events
+    for (i, message) in messages.iter().enumerate() {
+        assert_eq!(message.get("seq").and_then(Json::as_usize), Some(i + 1));
+    }
+
+    let breakpoints = response(&messages, "setBreakpoints").get("breakpoints");
+    let breakpoint = &breakpoints.and_then(Json::as_array).unwrap()[0];
+    assert_eq!(breakpoint.get("line").and_then(Json::as_usize), Some(3));
+
+    let frames = response(&messages, "stackTrace").get("stackFrames");
+    let frames: Vec<_> = frames
+        .and_then(Json::as_array)
+        .unwrap()
+        .iter()
+        .map(|f| {
+            let line = f.get("line").and_then(Json::as_usize).unwrap();
+            (str_field(f, "name").to_string(), line)
+        })
+        .collect();
+    assert_eq!(frames, [("add".to_string(), 3), ("<Main>".to_string(), 5)]);
+
+    let scopes = response(&messages, "scopes").get("scopes");
+    let scopes: Vec<_> = scopes
+        .and_then(Json::as_array)
+        .unwrap()
+        .iter()
+        .map(|s| {
+            let reference = s.get("variablesReference").and_then(Json::as_usize);
+            (str_field(s, "name"), reference.unwrap())
+        })
+        .collect();
+    assert_eq!(scopes, [("Locals", 2), ("Globals", 1)]);
+
+    let variables: Vec<_> = messages
+        .iter()
+        .filter(|m| str_field(m, "command") == "variables")
+        .map(|m| names_and_values(m.get("body").unwrap()))
+        .collect();
+    let pair = |name: &str, value: &str| (name.to_string(), value.to_string());
+    assert_eq!(
+        variables[0],
+        [pair("a", "1"), pair("b", "2"), pair("sum", "3")]
+    );
+    assert!(
+        variables[1].contains(&pair("add", "<fn add(2)>")),
+        "{:?}",
+        variables[1]
+    );
+    assert!(
+        variables[1].contains(&pair("x", "<undefined>")),
+        "{:?}",
+        variables[1]
+    );
+
+    let output = messages
+        .iter()
+        .find(|m| str_field(m, "event") == "output")
+        .and_then(|m| m.get("body"))
+        .unwrap();
+    assert_eq!(str_field(output, "category"), "stdout");
+    assert_eq!(str_field(output, "output"), "3\ndone\n");
+}
+
+#[test]
+fn reports_runtime_errors_as_exceptions() {
+    let path = script("error");
+    fs::write(&path, "var a = 1;\nprint -\"text\";\n").unwrap();
+    let program = path.to_string_lossy().into_owned();
+    let messages = session(&[
+        ("initialize", Json::object::<&str>([])),
+        (
+            "launch",
+            Json::object([("program", program.as_str().into())]),
+        ),
+        ("configurationDone", Json::object::<&str>([])),
+        ("stackTrace", Json::object([("threadId", 1.into())])),
+        ("disconnect", Json::object::<&str>([])),
+    ]);
+    fs::remove_file(&path).ok();
+
+    assert_eq!(
+        timeline(&messages),
+        [
+            "initialize",
+            "initialized!",
+            "launch",
+            "configurationDone",
+            "output!",
+            "stopped(exception)",
+            "stackTrace",
+            "disconnect",
+        ]
+    );
+    let frames = response(&messages, "stackTrace").get("stackFrames");
+    let top = &frames.and_then(Json::as_array).unwrap()[0];
+    assert_eq!(top.get("line").and_then(Json::as_usize), Some(2));
+}
+
+#[test]
+fn answers_unknown_requests_with_an_error() {
+    let messages = session(&[("evaluate", Json::object::<&str>([]))]);
+    assert_eq!(messages.len(), 1);
+    assert_eq!(
+        messages[0].get("success").and_then(Json::as_bool),
+        Some(false)
+    );
+    assert_eq!(
+        str_field(&messages[0], "message"),
+        "Unsupported request 'evaluate'"
+    );
+}
+
+fn exit_codes(messages: &[Json]) -> Vec<usize> {
+    messages
+        .iter()
+        .filter(|m| str_field(m, "event") == "exited")
+        .map(|m| {
+            let body = m.get("body").unwrap();
+            body.get("exitCode").and_then(Json::as_usize).unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn exits_with_70_once_resumed_after_a_runtime_error() {
+    let path = script("exit-code");
+    fs::write(&path, "print -\"text\";\n").unwrap();
+    let program = path.to_string_lossy().into_owned();
+    let messages = session(&[
+        ("initialize", Json::object::<&str>([])),
+        (
+            "launch",
+            Json::object([("program", program.as_str().into())]),
+        ),
+        ("configurationDone", Json::object::<&str>([])),
+        ("continue", Json::object([("threadId", 1.into())])),
+        ("next", Json::object([("threadId", 1.into())])),
+        ("disconnect", Json::object::<&str>([])),
+    ]);
+    fs::remove_file(&path).ok();
+
+    assert_eq!(
+        timeline(&messages),
+        [
+            "initialize",
+            "initialized!",
+            "launch",
+            "configurationDone",
+            "output!",
+            "stopped(exception)",
+            "continue",
+            "exited!",
+            "terminated!",
+            "next",
+            "disconnect",
+        ]
+    );
+    assert_eq!(exit_codes(&messages), [70]);
+}
+
+#[test]
+fn tells_the_client_the_script_exited_only_once() {
+    let path = script("exited");
+    let program = path.to_string_lossy().into_owned();
+    let messages = session(&[
+        ("initialize", Json::object::<&str>([])),
+        (
+            "launch",
+            Json::object([("program", program.as_str().into())]),
+        ),
+        ("configurationDone", Json::object::<&str>([])),
+        ("continue", Json::object([("threadId", 1.into())])),
+        ("stepIn", Json::object([("threadId", 1.into())])),
+        ("disconnect", Json::object::<&str>([])),
+    ]);
+    fs::remove_file(&path).ok();
+
+    assert_eq!(
+        timeline(&messages),
+        [
+            "initialize",
+            "initialized!",
+            "launch",
+            "configurationDone",
+            "output!",
+            "exited!",
+            "terminated!",
+            "continue",
+            "stepIn",
+            "disconnect",
+        ]
+    );
+    assert_eq!(exit_codes(&messages), [0]);
+}
+
+#[test]
+fn refuses_to_run_before_a_script_is_launched() {
+    for command in ["continue", "next", "stepIn", "stepOut"] {
+        let messages = session(&[(command, Json::object([("threadId", 1.into())]))]);
+        assert_eq!(messages.len(), 1, "{}", command);
+        assert_eq!(
+            messages[0].get("success").and_then(Json::as_bool),
+            Some(false)
+        );
+        assert_eq!(str_field(&messages[0], "message"), "No script was launched");
+    }
+}
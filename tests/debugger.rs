@@ -0,0 +1,123 @@
+//! Stepping through a script with `Debugger`, as `rlox debug` and the DAP server do.
+
+use rlox::{
+    bytecode::Value,
+    debugger::{Debugger, Resume, Stop},
+    vm::VmConfig,
+    Options,
+};
+
+const SCRIPT: &str = "\
+fun add(a, b) {
+    var sum = a + b;
+    return sum;
+}
+var x = 1;
+
+var y = add(x, 2);
+print y;
+";
+
+fn debugger() -> Debugger<'static> {
+    let code = rlox::compile(SCRIPT, &Options::default()).expect("the script compiles");
+    let mut debugger = Debugger::new(code, VmConfig::default());
+    debugger.vm_mut().set_output(Box::new(std::io::sink()));
+    debugger
+}
+
+// The function and line the debugger is paused at.
+fn at(debugger: &Debugger) -> (String, usize) {
+    let frames = debugger.frames();
+    (frames[0].function.name().to_string(), frames[0].line())
+}
+
+fn resume(debugger: &mut Debugger, mode: Resume) -> Stop {
+    debugger.resume(mode).expect("the script runs")
+}
+
+fn main_at(line: usize) -> (String, usize) {
+    ("<Main>".to_string(), line)
+}
+
+fn add_at(line: usize) -> (String, usize) {
+    ("add".to_string(), line)
+}
+
+#[test]
+fn steps_into_and_out_of_a_call() {
+    let mut debugger = debugger();
+    // the function is defined where its declaration ends
+    assert_eq!(at(&debugger), main_at(4));
+    assert_eq!(resume(&mut debugger, Resume::StepIn), Stop::Step);
+    assert_eq!(at(&debugger), main_at(5));
+    resume(&mut debugger, Resume::StepIn);
+    assert_eq!(at(&debugger), main_at(7));
+
+    resume(&mut debugger, Resume::StepIn);
+    assert_eq!(at(&debugger), add_at(2));
+    assert_eq!(debugger.frames().len(), 2);
+    resume(&mut debugger, Resume::StepIn);
+    assert_eq!(at(&debugger), add_at(3));
+
+    // back in the middle of line 7, which defines `y` once the call returns
+    resume(&mut debugger, Resume::StepOut);
+    assert_eq!(at(&debugger), main_at(7));
+    assert_eq!(debugger.frames().len(), 1);
+    resume(&mut debugger, Resume::StepIn);
+    assert_eq!(at(&debugger), main_at(8));
+    assert_eq!(resume(&mut debugger, Resume::StepIn), Stop::Finished);
+}
+
+#[test]
+fn steps_over_a_call() {
+    let mut debugger = debugger();
+    resume(&mut debugger, Resume::StepOver);
+    resume(&mut debugger, Resume::StepOver);
+    assert_eq!(at(&debugger), main_at(7));
+    resume(&mut debugger, Resume::StepOver);
+    assert_eq!(at(&debugger), main_at(8));
+    assert_eq!(debugger.lookup(0, "y"), Some(Some(Value::Number(3.0))));
+}
+
+#[test]
+fn steps_over_to_the_caller_at_a_return() {
+    let mut debugger = debugger();
+    debugger.set_breakpoint(3);
+    assert_eq!(resume(&mut debugger, Resume::Continue), Stop::Breakpoint(3));
+    resume(&mut debugger, Resume::StepOver);
+    assert_eq!(at(&debugger), main_at(7));
+}
+
+#[test]
+fn moves_breakpoints_to_the_next_line_with_code() {
+    let mut debugger = debugger();
+    // line 6 is blank and line 1 only names the function, whose code starts on line 2
+    assert_eq!(debugger.set_breakpoint(6), Some(7));
+    assert_eq!(debugger.set_breakpoint(1), Some(2));
+    assert_eq!(debugger.set_breakpoint(100), None);
+    assert_eq!(debugger.breakpoints().collect::<Vec<_>>(), [2, 7]);
+
+    assert_eq!(resume(&mut debugger, Resume::Continue), Stop::Breakpoint(7));
+    assert_eq!(resume(&mut debugger, Resume::Continue), Stop::Breakpoint(2));
+    assert!(debugger.clear_breakpoint(7));
+    assert!(!debugger.clear_breakpoint(6));
+    assert_eq!(resume(&mut debugger, Resume::Continue), Stop::Finished);
+}
+
+#[test]
+fn reads_locals_of_any_frame_and_globals_by_name() {
+    let mut debugger = debugger();
+    // `x` is declared but not defined yet
+    assert_eq!(debugger.lookup(0, "x"), Some(None));
+    assert_eq!(debugger.lookup(0, "nothing"), None);
+
+    debugger.set_breakpoint(3);
+    resume(&mut debugger, Resume::Continue);
+    assert_eq!(debugger.lookup(0, "a"), Some(Some(Value::Number(1.0))));
+    assert_eq!(debugger.lookup(0, "b"), Some(Some(Value::Number(2.0))));
+    assert_eq!(debugger.lookup(0, "sum"), Some(Some(Value::Number(3.0))));
+    assert_eq!(debugger.lookup(0, "x"), Some(Some(Value::Number(1.0))));
+    // the caller sees its globals but not the locals of `add`
+    assert_eq!(debugger.lookup(1, "sum"), None);
+    assert_eq!(debugger.lookup(1, "y"), Some(None));
+}
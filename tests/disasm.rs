@@ -0,0 +1,105 @@
+//! The listing of `rlox disasm --json`, which tools read instead of the text listing.
+
+use std::process::Command;
+
+use rlox::{disasm, json::Json, Options};
+
+const SOURCE: &str = "fun add(a, b) { return a + b; }
+var x = add(1, 2);
+if (x > 2) print \"big\";
+";
+
+fn listing() -> Json {
+    let code = rlox::compile(SOURCE, &Options::default()).expect("the script compiles");
+    disasm::to_json(&code)
+}
+
+fn field<'a>(json: &'a Json, key: &str) -> &'a Json {
+    json.get(key)
+        .unwrap_or_else(|| panic!("no '{}' in {}", key, json))
+}
+
+fn array<'a>(json: &'a Json, key: &str) -> &'a [Json] {
+    field(json, key).as_array().expect("an array")
+}
+
+#[test]
+fn lists_the_globals_and_every_function_main_first() {
+    let listing = listing();
+    let globals: Vec<_> = array(&listing, "globals")
+        .iter()
+        .map(|name| name.as_str().unwrap())
+        .collect();
+    assert_eq!(globals, ["add", "x"]);
+
+    let functions = array(&listing, "functions");
+    assert_eq!(functions.len(), 2);
+    assert_eq!(field(&functions[0], "name").as_str(), Some("<Main>"));
+    assert_eq!(field(&functions[0], "arity").as_usize(), Some(0));
+    assert_eq!(field(&functions[1], "name").as_str(), Some("add"));
+    assert_eq!(field(&functions[1], "arity").as_usize(), Some(2));
+
+    // function constants refer to their entry in `functions`
+    let constants = array(&functions[0], "constants");
+    assert_eq!(field(&constants[0], "type").as_str(), Some("function"));
+    assert_eq!(field(&constants[0], "function").as_usize(), Some(1));
+    let last = constants.last().unwrap();
+    assert_eq!(field(last, "type").as_str(), Some("string"));
+    assert_eq!(field(last, "value").as_str(), Some("big"));
+}
+
+#[test]
+fn lists_each_instruction_with_its_operands() {
+    let listing = listing();
+    for function in array(&listing, "functions") {
+        let size = field(function, "size").as_usize().unwrap();
+        let code = array(function, "code");
+        let offsets: Vec<_> = code
+            .iter()
+            .map(|ins| field(ins, "offset").as_usize().unwrap())
+            .collect();
+        assert_eq!(offsets[0], 0);
+        assert!(offsets.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(*offsets.last().unwrap() < size);
+
+        for ins in code {
+            assert!(field(ins, "line").as_usize().unwrap() >= 1);
+            let op = field(ins, "op").as_str().unwrap();
+            let operand = match op {
+                "OP_CONSTANT" => Some("constant"),
+                "OP_DEFINE_GLOBAL" | "OP_GET_GLOBAL" | "OP_SET_GLOBAL" => {
+                    assert!(field(ins, "name").as_str().is_some());
+                    Some("slot")
+                }
+                "OP_GET_LOCAL" | "OP_SET_LOCAL" => Some("slot"),
+                "OP_JUMP" | "OP_JUMP_IF_FALSE" | "OP_LOOP" => Some("target"),
+                "OP_CALL" => Some("args"),
+                _ => None,
+            };
+            if let Some(operand) = operand {
+                assert!(field(ins, operand).as_usize().is_some(), "{}", ins);
+            }
+            // jumps land on an instruction or the end of the code
+            if operand == Some("target") {
+                let target = field(ins, "target").as_usize().unwrap();
+                assert!(target == size || offsets.contains(&target), "{}", ins);
+            }
+        }
+    }
+}
+
+#[test]
+fn prints_the_listing_as_one_line_of_json() {
+    let path = std::env::temp_dir().join(format!("rlox-disasm-{}.lox", std::process::id()));
+    std::fs::write(&path, SOURCE).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .args(["disasm", path.to_str().unwrap(), "--json"])
+        .output()
+        .expect("rlox runs");
+    std::fs::remove_file(&path).ok();
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 1);
+    assert_eq!(Json::parse(&stdout).unwrap(), listing());
+}
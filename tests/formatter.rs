@@ -0,0 +1,42 @@
+//! Formats every `tests/fmt/<case>.lox` and compares it with `tests/fmt/<case>.expected`.
+
+use std::{fs, path::Path};
+
+use rlox::formatter;
+
+#[test]
+fn formats_golden_cases() {
+    let mut cases: Vec<_> = fs::read_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fmt"))
+        .expect("tests/fmt exists")
+        .map(|entry| entry.expect("a directory entry").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lox"))
+        .collect();
+    cases.sort();
+    assert!(!cases.is_empty());
+
+    let mut failures = Vec::new();
+    for case in cases.iter() {
+        let source = fs::read_to_string(case).expect("the case is readable");
+        let expected = fs::read_to_string(case.with_extension("expected"))
+            .expect("the case has an .expected file");
+        let formatted = formatter::format(&source).expect("the case parses");
+        if formatted != expected {
+            failures.push(format!(
+                "{}:\n--- expected\n{}--- formatted\n{}",
+                case.display(),
+                expected,
+                formatted
+            ));
+        }
+        // formatting a formatted script changes nothing
+        let again = formatter::format(&formatted).expect("the output parses");
+        if again != formatted {
+            failures.push(format!(
+                "{}: formatting twice changes it:\n{}",
+                case.display(),
+                again
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
@@ -0,0 +1,29 @@
+//! Runs the golden scripts in `tests/lox`, as `rlox test tests/lox` does, at every
+//! optimization level, and compares them with the reference interpreter as
+//! `rlox test --differential tests/lox` does.
+
+use rlox::{golden, OptLevel, Options};
+
+const SCRIPTS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/lox");
+
+#[test]
+fn golden_scripts_pass() {
+    for opt_level in [OptLevel::None, OptLevel::Peephole, OptLevel::Fold] {
+        let options = Options {
+            opt_level,
+            ..Options::default()
+        };
+        // the failures are printed, and shown along with the error
+        if let Err(error) = golden::run(SCRIPTS, false, &options) {
+            panic!("{:?}: {}", opt_level, error);
+        }
+    }
+}
+
+#[test]
+fn golden_scripts_match_the_reference_interpreter() {
+    // every optimization level is compared with the reference
+    if let Err(error) = golden::run(SCRIPTS, true, &Options::default()) {
+        panic!("{}", error);
+    }
+}
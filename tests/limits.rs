@@ -0,0 +1,205 @@
+//! The limits of `VmConfig`, each stopping a script with its own `RuntimeErrorKind`.
+
+use rlox::{
+    bytecode::OpCode,
+    vm::{RuntimeError, VmConfig, VM},
+    Options, RuntimeErrorKind,
+};
+
+const RECURSE: &str = "fun f(n) { return f(n + 1); } f(0);";
+
+fn compile(source: &str, config: VmConfig) -> rlox::bytecode::FunctionObj {
+    let options = Options {
+        vm: config,
+        ..Options::default()
+    };
+    rlox::compile(source, &options).expect("the script compiles")
+}
+
+// The error `vm` stops with, which has to be a runtime error.
+fn runtime_error(result: rlox::Result<()>) -> RuntimeError {
+    let error = result.expect_err("the script fails");
+    error
+        .downcast_ref::<RuntimeError>()
+        .unwrap_or_else(|| panic!("not a runtime error: {}", error))
+        .clone()
+}
+
+fn run(source: &str, config: VmConfig) -> RuntimeError {
+    let mut vm = VM::with_config(compile(source, config), config);
+    vm.set_output(Box::new(std::io::sink()));
+    runtime_error(vm.run())
+}
+
+#[test]
+fn stops_deep_recursion_at_max_frames() {
+    let config = VmConfig {
+        max_frames: 10,
+        ..VmConfig::default()
+    };
+    let error = run(RECURSE, config);
+    assert_eq!(error.kind(), RuntimeErrorKind::StackOverflow);
+    assert_eq!(error.message(), "Stack overflow");
+    // the main function counts as a frame
+    assert_eq!(error.trace().len(), 10);
+    assert_eq!(error.trace().last().unwrap().1, "<Main>");
+}
+
+#[test]
+fn stops_deep_recursion_with_the_default_limits() {
+    let error = run(RECURSE, VmConfig::default());
+    assert_eq!(error.kind(), RuntimeErrorKind::StackOverflow);
+    assert_eq!(error.trace().len(), VmConfig::default().max_frames);
+}
+
+#[test]
+fn stops_a_growing_stack_at_max_stack() {
+    // every frame holds the function, its argument and a local
+    let source = "fun f(n) { var m = n + 1; return f(m); } f(0);";
+    let config = VmConfig {
+        max_stack: 40,
+        ..VmConfig::default()
+    };
+    let error = run(source, config);
+    assert_eq!(error.kind(), RuntimeErrorKind::StackOverflow);
+    assert!(error.trace().len() < 20, "{} frames", error.trace().len());
+}
+
+#[test]
+fn rejects_too_many_locals_at_compile_time() {
+    let config = VmConfig {
+        max_locals: 3,
+        ..VmConfig::default()
+    };
+    let options = Options {
+        vm: config,
+        ..Options::default()
+    };
+    // the slot of the function itself counts, the error is printed as it is found
+    assert!(rlox::compile("{ var a; var b; }", &options).is_ok());
+    let error = rlox::compile("{ var a; var b; var c; }", &options).expect_err("too many");
+    assert_eq!(rlox::exit_code(&error), 65);
+}
+
+#[test]
+fn stops_an_endless_loop_at_max_instructions() {
+    let config = VmConfig {
+        max_instructions: Some(1000),
+        ..VmConfig::default()
+    };
+    let mut vm = VM::with_config(compile("while (true) {}", config), config);
+    let error = runtime_error(vm.run());
+    assert_eq!(error.kind(), RuntimeErrorKind::BudgetExhausted);
+    assert_eq!(error.message(), "Instruction budget exhausted");
+    assert_eq!(vm.instructions_executed(), 1000);
+}
+
+#[test]
+fn lets_a_script_use_its_whole_budget() {
+    let source = "var a = 1; print a + 2;";
+    let mut vm = VM::with_code(compile(source, VmConfig::default()));
+    vm.set_output(Box::new(std::io::sink()));
+    vm.count_instructions();
+    vm.run().expect("the script runs");
+    let needed = vm.instructions_executed();
+
+    for (budget, fits) in [(needed, true), (needed - 1, false)] {
+        let config = VmConfig {
+            max_instructions: Some(budget),
+            ..VmConfig::default()
+        };
+        let mut vm = VM::with_config(compile(source, config), config);
+        vm.set_output(Box::new(std::io::sink()));
+        assert_eq!(vm.run().is_ok(), fits, "budget of {}", budget);
+    }
+}
+
+#[test]
+fn stops_an_endless_loop_interrupted_from_another_thread() {
+    let mut vm = VM::with_code(compile("while (true) {}", VmConfig::default()));
+    let handle = vm.interrupt_handle();
+    let interrupter = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        handle.interrupt();
+    });
+    let error = runtime_error(vm.run());
+    interrupter.join().unwrap();
+    assert_eq!(error.kind(), RuntimeErrorKind::Interrupted);
+    assert_eq!(error.message(), "Execution interrupted");
+    assert_eq!(rlox::exit_code(&error.into()), 130);
+}
+
+#[test]
+fn resumes_after_an_interrupt_is_reset() {
+    let source = "var i = 0; while (i < 3) { print i; i = i + 1; }";
+    let mut printed = Vec::new();
+    {
+        let mut vm = VM::with_code(compile(source, VmConfig::default()));
+        vm.set_output(Box::new(&mut printed));
+        let handle = vm.interrupt_handle();
+        // interrupted right before the first backward jump, which then has to run again
+        let interrupter = handle.clone();
+        let mut loops = 0;
+        vm.set_tracer(Some(Box::new(move |trace| {
+            if let OpCode::Loop(_) = trace.ins {
+                loops += 1;
+                if loops == 1 {
+                    interrupter.interrupt();
+                }
+            }
+        })));
+        assert_eq!(
+            runtime_error(vm.run()).kind(),
+            RuntimeErrorKind::Interrupted
+        );
+        handle.reset();
+        vm.run().expect("the script goes on");
+    }
+    assert_eq!(String::from_utf8(printed).unwrap(), "0\n1\n2\n");
+}
+
+#[test]
+fn stops_a_growing_string_at_max_memory() {
+    let config = VmConfig {
+        max_memory: Some(10_000),
+        ..VmConfig::default()
+    };
+    let error = run("var s = \"x\"; while (true) s = s + s;", config);
+    assert_eq!(error.kind(), RuntimeErrorKind::OutOfMemory);
+    assert_eq!(error.message(), "Out of memory");
+    assert_eq!(rlox::exit_code(&error.into()), 71);
+}
+
+#[test]
+fn does_not_count_freed_strings() {
+    // far more garbage than the limit, next to a live string that takes half of it
+    let source = format!(
+        "var kept = \"{}\";
+         for (var i = 0; i < 10000; i = i + 1) {{ var t = \"abc\" + \"def\"; }}
+         print kept + \"!\" == kept;",
+        "k".repeat(30_000)
+    );
+    let config = VmConfig {
+        max_memory: Some(64_000),
+        ..VmConfig::default()
+    };
+    let mut printed = Vec::new();
+    {
+        let mut vm = VM::with_config(compile(&source, config), config);
+        vm.set_output(Box::new(&mut printed));
+        vm.run().expect("the garbage is not counted");
+        assert!(vm.bytes_allocated() <= 64_000);
+    }
+    assert_eq!(String::from_utf8(printed).unwrap(), "false\n");
+}
+
+#[test]
+fn counts_the_operands_of_a_concatenation() {
+    // the two halves only live on the stack while the whole is allocated: 9 times `a` at once
+    let source = format!("var a = \"{}\"; print (a + a) + (a + a);", "a".repeat(1000));
+    let config = VmConfig {
+        max_memory: Some(7000),
+        ..VmConfig::default()
+    };
+    assert_eq!(run(&source, config).kind(), RuntimeErrorKind::OutOfMemory);
+}
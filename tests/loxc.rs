@@ -0,0 +1,154 @@
+//! Writing precompiled files and reading them back.
+
+use rlox::{
+    bytecode::FunctionObj,
+    disasm,
+    loxc::{self, FORMAT_VERSION, MAGIC},
+    vm::VM,
+    Options,
+};
+
+const SCRIPT: &str = r#"
+var greeting = "hello";
+fun outer(a, b) {
+    var scale = 2.5;
+    fun inner(c) {
+        fun innermost() {
+            return nil;
+        }
+        innermost();
+        return c * 0.5;
+    }
+    return inner(a + b) * scale;
+}
+print greeting;
+print outer(1, 3);
+print true and !false;
+print -0.0;
+"#;
+
+// magic, version and checksum
+const HEADER_SIZE: usize = 10;
+
+fn compile() -> FunctionObj {
+    rlox::compile(SCRIPT, &Options::default()).expect("the script compiles")
+}
+
+fn run(code: FunctionObj) -> String {
+    let mut printed = Vec::new();
+    {
+        let mut vm = VM::with_code(code);
+        vm.set_output(Box::new(&mut printed));
+        vm.run().expect("the script runs");
+    }
+    String::from_utf8(printed).expect("UTF-8 output")
+}
+
+// The CRC-32 the header holds, so that tests can change the payload and still get past it.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// `bytes` with the payload replaced and its checksum updated.
+fn with_payload(bytes: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut out = bytes[..HEADER_SIZE].to_vec();
+    out[6..10].copy_from_slice(&crc32(payload).to_le_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+fn decode_error(bytes: &[u8]) -> String {
+    match loxc::decode(bytes) {
+        Ok(_) => panic!("decoded invalid bytes"),
+        Err(error) => error.to_string(),
+    }
+}
+
+#[test]
+fn round_trips_nested_functions_and_constants() {
+    let code = compile();
+    let bytes = loxc::encode(&code);
+    assert!(loxc::is_precompiled(&bytes));
+
+    let decoded = loxc::decode(&bytes).expect("the file decodes");
+    assert_eq!(disasm::to_text(&decoded), disasm::to_text(&code));
+    assert_eq!(disasm::functions(&decoded).len(), 4);
+    // names, lines and locals aren't in the listing, but they are encoded again
+    assert_eq!(loxc::encode(&decoded), bytes);
+    assert_eq!(run(decoded), run(code));
+}
+
+#[test]
+fn rejects_bad_magic() {
+    let mut bytes = loxc::encode(&compile());
+    bytes[0] = b'l';
+    assert_eq!(decode_error(&bytes), "Not a precompiled rlox file");
+}
+
+#[test]
+fn rejects_an_unsupported_version() {
+    let mut bytes = loxc::encode(&compile());
+    let version = FORMAT_VERSION + 1;
+    bytes[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&version.to_le_bytes());
+    assert_eq!(
+        decode_error(&bytes),
+        format!(
+            "Unsupported .loxc format version {}, this rlox reads version {}",
+            version, FORMAT_VERSION
+        )
+    );
+}
+
+#[test]
+fn rejects_a_checksum_mismatch() {
+    let mut bytes = loxc::encode(&compile());
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x01;
+    assert_eq!(
+        decode_error(&bytes),
+        "Corrupt .loxc file: checksum mismatch"
+    );
+}
+
+#[test]
+fn rejects_truncated_input() {
+    let bytes = loxc::encode(&compile());
+    assert_eq!(
+        decode_error(&bytes[..HEADER_SIZE - 1]),
+        "Corrupt .loxc file: truncated header"
+    );
+    // cut in the middle of the payload, with a checksum that matches what is left
+    let payload = &bytes[HEADER_SIZE..];
+    for len in [0, 1, payload.len() / 2, payload.len() - 1] {
+        let error = decode_error(&with_payload(&bytes, &payload[..len]));
+        assert!(
+            error == "Corrupt .loxc file: unexpected end of file"
+                || error == "Corrupt .loxc file: length past the end of file",
+            "{} bytes: {}",
+            len,
+            error
+        );
+    }
+}
+
+#[test]
+fn rejects_trailing_bytes() {
+    let bytes = loxc::encode(&compile());
+    let mut payload = bytes[HEADER_SIZE..].to_vec();
+    payload.push(0);
+    assert_eq!(
+        decode_error(&with_payload(&bytes, &payload)),
+        "Corrupt .loxc file: trailing bytes after the main function"
+    );
+}
@@ -0,0 +1,263 @@
+//! A scripted editor driving `lsp::serve` through a session, over in-memory streams.
+
+use rlox::{
+    json::{self, Json},
+    lsp, Options,
+};
+
+const URI: &str = "file:///script.lox";
+
+const SCRIPT: &str = "\
+fun add(a, b) {
+    var sum = a + b;
+    return sum;
+}
+var x = add(1, 2);
+print x;
+";
+
+// A request when it has an id, a notification otherwise.
+struct Message {
+    id: Option<usize>,
+    method: &'static str,
+    params: Json,
+}
+
+fn request(id: usize, method: &'static str, params: Json) -> Message {
+    Message {
+        id: Some(id),
+        method,
+        params,
+    }
+}
+
+fn notification(method: &'static str, params: Json) -> Message {
+    Message {
+        id: None,
+        method,
+        params,
+    }
+}
+
+fn document(uri: &str) -> Json {
+    Json::object([("uri", uri.into())])
+}
+
+fn open(text: &str) -> Message {
+    let document = Json::object([
+        ("uri", URI.into()),
+        ("languageId", "lox".into()),
+        ("version", 1.into()),
+        ("text", text.into()),
+    ]);
+    notification(
+        "textDocument/didOpen",
+        Json::object([("textDocument", document)]),
+    )
+}
+
+fn change(uri: &str, text: &str) -> Message {
+    let change = Json::object([("text", text.into())]);
+    notification(
+        "textDocument/didChange",
+        Json::object([
+            ("textDocument", document(uri)),
+            ("contentChanges", vec![change].into()),
+        ]),
+    )
+}
+
+// A request about the 0-based `line` and `character` of the script.
+fn at(id: usize, method: &'static str, line: usize, character: usize) -> Message {
+    let position = Json::object([("line", line.into()), ("character", character.into())]);
+    request(
+        id,
+        method,
+        Json::object([("textDocument", document(URI)), ("position", position)]),
+    )
+}
+
+// Runs a session of `messages`, shutting the server down at the end, and returns every
+// message the server sent.
+fn session(messages: Vec<Message>) -> Vec<Json> {
+    let mut input = Vec::new();
+    let shutdown = [
+        request(0, "shutdown", Json::Null),
+        notification("exit", Json::Null),
+    ];
+    for message in messages.iter().chain(shutdown.iter()) {
+        let mut fields = vec![
+            ("jsonrpc", "2.0".into()),
+            ("method", message.method.into()),
+            ("params", message.params.clone()),
+        ];
+        if let Some(id) = message.id {
+            fields.push(("id", id.into()));
+        }
+        json::write_message(&mut input, &Json::object(fields)).expect("the message is written");
+    }
+    let mut output = Vec::new();
+    lsp::serve(input.as_slice(), &mut output, &Options::default()).expect("the session ends");
+
+    let mut output = output.as_slice();
+    let mut messages = Vec::new();
+    while let Some(message) = json::read_message(&mut output).expect("a framed message") {
+        messages.push(message);
+    }
+    messages
+}
+
+// The result of the request `id`, which has to have succeeded.
+fn result(messages: &[Json], id: usize) -> &Json {
+    let response = messages
+        .iter()
+        .find(|m| m.get("id").and_then(Json::as_usize) == Some(id))
+        .unwrap_or_else(|| panic!("no response to {}", id));
+    assert!(response.get("error").is_none(), "{}", response);
+    response.get("result").expect("a result")
+}
+
+// The messages of each `publishDiagnostics` notification, in order.
+fn published(messages: &[Json]) -> Vec<Vec<String>> {
+    messages
+        .iter()
+        .filter(|m| {
+            m.get("method").and_then(Json::as_str) == Some("textDocument/publishDiagnostics")
+        })
+        .map(|m| {
+            let params = m.get("params").unwrap();
+            assert_eq!(params.get("uri").and_then(Json::as_str), Some(URI));
+            params
+                .get("diagnostics")
+                .and_then(Json::as_array)
+                .unwrap()
+                .iter()
+                .map(|d| d.get("message").and_then(Json::as_str).unwrap().to_string())
+                .collect()
+        })
+        .collect()
+}
+
+// The 0-based start line and character of `range`.
+fn start(range: &Json) -> (usize, usize) {
+    let start = range.get("start").unwrap();
+    (
+        start.get("line").and_then(Json::as_usize).unwrap(),
+        start.get("character").and_then(Json::as_usize).unwrap(),
+    )
+}
+
+#[test]
+fn publishes_errors_and_warnings_as_the_document_changes() {
+    let messages = session(vec![
+        request(1, "initialize", Json::object::<&str>([])),
+        open(SCRIPT),
+        change(URI, "var a = ;\n"),
+        change(URI, "fun f(a) { return 1; }\n"),
+        // a document that was never opened has no diagnostics
+        change("file:///other.lox", "var b = ;\n"),
+    ]);
+    let capabilities = result(&messages, 1).get("capabilities").unwrap();
+    assert_eq!(
+        capabilities.get("hoverProvider").and_then(Json::as_bool),
+        Some(true)
+    );
+
+    let published = published(&messages);
+    assert_eq!(published.len(), 3);
+    assert!(published[0].is_empty(), "{:?}", published[0]);
+    assert_eq!(published[1].len(), 1);
+    assert!(
+        published[1][0].starts_with("Unexpected token 'Semicolon'"),
+        "{:?}",
+        published[1]
+    );
+    assert_eq!(published[2], ["Unused parameter 'a'."]);
+}
+
+#[test]
+fn finds_the_definition_of_a_name() {
+    let messages = session(vec![
+        open(SCRIPT),
+        // `sum` in `return sum;`
+        at(1, "textDocument/definition", 2, 12),
+        // `add` in the call
+        at(2, "textDocument/definition", 4, 9),
+        // the `print` keyword names nothing
+        at(3, "textDocument/definition", 5, 2),
+    ]);
+    let definition = result(&messages, 1);
+    assert_eq!(definition.get("uri").and_then(Json::as_str), Some(URI));
+    assert_eq!(start(definition.get("range").unwrap()), (1, 8));
+    assert_eq!(start(result(&messages, 2).get("range").unwrap()), (0, 4));
+    assert_eq!(result(&messages, 3), &Json::Null);
+}
+
+#[test]
+fn describes_a_name_on_hover() {
+    let messages = session(vec![
+        open(SCRIPT),
+        at(1, "textDocument/hover", 4, 9),
+        at(2, "textDocument/hover", 1, 14),
+    ]);
+    let hover = |id| {
+        let contents = result(&messages, id).get("contents").unwrap();
+        assert_eq!(
+            contents.get("kind").and_then(Json::as_str),
+            Some("markdown")
+        );
+        contents
+            .get("value")
+            .and_then(Json::as_str)
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!(hover(1), "```lox\nfun add(a, b)\n```\nTakes 2 arguments.");
+    assert_eq!(hover(2), "```lox\na\n```\nparameter in add()");
+}
+
+#[test]
+fn lists_the_symbols_of_the_document() {
+    let messages = session(vec![
+        open(SCRIPT),
+        request(
+            1,
+            "textDocument/documentSymbol",
+            Json::object([("textDocument", document(URI))]),
+        ),
+    ]);
+    let name = |symbol: &Json| {
+        symbol
+            .get("name")
+            .and_then(Json::as_str)
+            .unwrap()
+            .to_string()
+    };
+    let symbols = result(&messages, 1).as_array().unwrap();
+    assert_eq!(symbols.iter().map(name).collect::<Vec<_>>(), ["add", "x"]);
+    // parameters are left out, locals are nested in their function
+    let children = symbols[0].get("children").and_then(Json::as_array).unwrap();
+    assert_eq!(children.iter().map(name).collect::<Vec<_>>(), ["sum"]);
+    assert_eq!(start(symbols[0].get("selectionRange").unwrap()), (0, 4));
+}
+
+#[test]
+fn completes_keywords_and_declared_names() {
+    let messages = session(vec![open(SCRIPT), at(1, "textDocument/completion", 5, 0)]);
+    let items = result(&messages, 1).as_array().unwrap();
+    let label = |item: &Json| {
+        item.get("label")
+            .and_then(Json::as_str)
+            .unwrap()
+            .to_string()
+    };
+    let labels: Vec<_> = items.iter().map(label).collect();
+    for expected in ["while", "print", "add", "a", "b", "sum", "x"] {
+        assert!(labels.contains(&expected.to_string()), "{:?}", labels);
+    }
+    let add = items.iter().find(|item| label(item) == "add").unwrap();
+    assert_eq!(
+        add.get("detail").and_then(Json::as_str),
+        Some("fun add(a, b)")
+    );
+}
@@ -0,0 +1,96 @@
+//! Deeply nested code is rejected with a compile error rather than overflowing the stack of the
+//! parser, the resolver or the compiler. The tests run on the 2 MiB stack of test threads.
+
+use rlox::{fuzz::Target, parser::Parser, vm::VM, Options};
+
+// Source nesting `depth` levels of each construct that nests.
+fn nested(depth: usize) -> Vec<(&'static str, String)> {
+    vec![
+        (
+            "parentheses",
+            format!("print {}1{};", "(".repeat(depth), ")".repeat(depth)),
+        ),
+        ("operators", format!("print {}1;", "1 + ".repeat(depth))),
+        ("unary", format!("print {}1;", "-".repeat(depth))),
+        ("assignments", format!("var a; {}1;", "a = ".repeat(depth))),
+        (
+            "blocks",
+            format!("{}print 1;{}", "{".repeat(depth), "}".repeat(depth)),
+        ),
+        ("ifs", format!("{}print 1;", "if (true) ".repeat(depth))),
+        (
+            "functions",
+            format!(
+                "{}print 1;{}",
+                "fun f() { ".repeat(depth),
+                " }".repeat(depth)
+            ),
+        ),
+    ]
+}
+
+fn diagnostics(source: &str) -> Vec<String> {
+    let (_, diagnostics) = Parser::with_source(source).parse_recovering();
+    diagnostics
+        .iter()
+        .map(|diagnostic| diagnostic.message().to_string())
+        .collect()
+}
+
+#[test]
+fn compiles_and_runs_code_nested_below_the_limit() {
+    for (name, source) in nested(Parser::MAX_DEPTH / 2) {
+        let code = rlox::compile(&source, &Options::default())
+            .unwrap_or_else(|error| panic!("{}: {}", name, error));
+        let mut vm = VM::with_code(code);
+        vm.set_output(Box::new(std::io::sink()));
+        vm.run()
+            .unwrap_or_else(|error| panic!("{}: {}", name, error));
+    }
+}
+
+#[test]
+fn rejects_code_nested_past_the_limit() {
+    for depth in [Parser::MAX_DEPTH + 1, 100_000] {
+        for (name, source) in nested(depth) {
+            let diagnostics = diagnostics(&source);
+            assert_eq!(
+                diagnostics.len(),
+                1,
+                "{} {}: {:?}",
+                name,
+                depth,
+                diagnostics
+            );
+            assert!(
+                diagnostics[0].starts_with("Code is nested too deeply."),
+                "{}: {}",
+                name,
+                diagnostics[0]
+            );
+
+            let error = rlox::compile(&source, &Options::default()).expect_err(name);
+            assert_eq!(rlox::exit_code(&error), 65, "{}", name);
+        }
+    }
+}
+
+#[test]
+fn stops_parsing_at_code_nested_too_deeply() {
+    // what follows would only report the brackets left open
+    let source = format!("{}\nvar a = ;", "(".repeat(100_000));
+    assert_eq!(diagnostics(&source).len(), 1);
+    // errors before it are still reported
+    let source = format!("var a = ;\nprint {};", "(".repeat(100_000));
+    assert_eq!(diagnostics(&source).len(), 2);
+}
+
+#[test]
+fn survives_the_fuzz_targets() {
+    // inputs are cut to 4096 bytes, which nest far deeper than the limit
+    for (_, source) in nested(2000) {
+        for target in Target::ALL {
+            target.run(source.as_bytes());
+        }
+    }
+}
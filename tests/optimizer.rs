@@ -0,0 +1,156 @@
+//! The optimizer may change how a script runs but never what it does: every script here has to
+//! print the same and fail the same at every level.
+
+use rlox::{bytecode::FunctionObj, disasm, verifier, vm::VM, OptLevel, Options, RuntimeErrorKind};
+
+const LEVELS: [OptLevel; 3] = [OptLevel::None, OptLevel::Peephole, OptLevel::Fold];
+
+fn compile(source: &str, opt_level: OptLevel) -> FunctionObj {
+    let options = Options {
+        opt_level,
+        ..Options::default()
+    };
+    rlox::compile(source, &options).expect("the script compiles")
+}
+
+// What the script printed, and the error it stopped with if any.
+fn run(source: &str, opt_level: OptLevel) -> (String, Option<(RuntimeErrorKind, String)>) {
+    let code = compile(source, opt_level);
+    verifier::verify(&code).expect("the optimized code verifies");
+    let mut printed = Vec::new();
+    let result = {
+        let mut vm = VM::with_code(code);
+        vm.set_output(Box::new(&mut printed));
+        vm.run()
+    };
+    let error = result.err().map(|error| {
+        let error = error
+            .downcast_ref::<rlox::vm::RuntimeError>()
+            .expect("a runtime error");
+        (error.kind(), error.message().to_string())
+    });
+    (String::from_utf8(printed).expect("UTF-8 output"), error)
+}
+
+fn assert_same_at_every_level(source: &str) -> String {
+    let (expected, error) = run(source, OptLevel::None);
+    for level in LEVELS {
+        assert_eq!(
+            run(source, level),
+            (expected.clone(), error.clone()),
+            "{:?}",
+            level
+        );
+    }
+    expected
+}
+
+#[test]
+fn folds_constant_arithmetic() {
+    let source = "print 1 + 2 * 3 - -4; print !(1 < 2); print \"a\" + \"b\";";
+    let code = disasm::to_text(&compile(source, OptLevel::Fold));
+    for op in [
+        "OP_ADD",
+        "OP_MULTIPLY",
+        "OP_SUBTRACT",
+        "OP_NEGATE",
+        "OP_NOT",
+        "OP_LESS",
+    ] {
+        assert!(!code.contains(op), "{} left in:\n{}", op, code);
+    }
+    assert_eq!(assert_same_at_every_level(source), "11\nfalse\nab\n");
+}
+
+#[test]
+fn removes_values_pushed_only_to_be_popped() {
+    let source = "1; \"unused\"; nil; print 2;";
+    for level in [OptLevel::Peephole, OptLevel::Fold] {
+        let code = disasm::to_text(&compile(source, level));
+        assert!(!code.contains("OP_POP"), "{:?}:\n{}", level, code);
+    }
+    assert_eq!(assert_same_at_every_level(source), "2\n");
+}
+
+#[test]
+fn fuses_comparison_pairs() {
+    let source = "var a = 1; var b = 2; print a <= b; print a >= b; print a != b;";
+    let code = disasm::to_text(&compile(source, OptLevel::Peephole));
+    for op in ["OP_LESS_EQUAL", "OP_GREATER_EQUAL", "OP_NOT_EQUAL"] {
+        assert!(code.contains(op), "no {} in:\n{}", op, code);
+    }
+    assert!(!code.contains("OP_NOT "), "unfused OP_NOT in:\n{}", code);
+    assert_eq!(assert_same_at_every_level(source), "true\nfalse\ntrue\n");
+}
+
+#[test]
+fn relocates_jumps_over_rewritten_code() {
+    // every branch and loop body has code that shrinks
+    let source = r#"
+        fun classify(n) {
+            if (n <= 1 + 1) {
+                1 + 2;
+                return "small";
+            } else if (n >= 2 * 5 and !(n != 12)) {
+                return "twelve";
+            }
+            return "other";
+        }
+        for (var i = 0; i <= 3 * 4; i = i + 1) {
+            "skipped";
+            if (!(i > 10) or i == 12) print classify(i);
+        }
+        var n = 0;
+        while (n <= 2 + 1) {
+            nil;
+            n = n + 1;
+        }
+        print n;
+        print false and 1 / 0 or "short-circuit";
+    "#;
+    let sizes: Vec<_> = LEVELS
+        .iter()
+        .map(|level| compile(source, *level).chunk().len())
+        .collect();
+    assert!(sizes[1] < sizes[0] && sizes[2] < sizes[1], "{:?}", sizes);
+
+    let output = assert_same_at_every_level(source);
+    assert_eq!(output.lines().filter(|line| *line == "small").count(), 3);
+    assert!(output.ends_with("twelve\n4\nshort-circuit\n"), "{}", output);
+}
+
+#[test]
+fn keeps_nan_comparisons() {
+    // the fused comparisons have to stay the negation of the unfused ones, which NaN is not
+    // ordered by
+    let source = r#"
+        var nan = 0 / 0;
+        print nan <= 1;
+        print nan >= 1;
+        print nan < 1;
+        print nan > 1;
+        print nan != nan;
+        print nan == nan;
+        print 0 / 0 <= 0 / 0;
+        print 0 / 0 >= 1;
+        print 0 / 0 != 0 / 0;
+        if (nan <= 1) print "branch";
+        while (nan >= 1) {
+            print "loop";
+            nan = 0;
+        }
+    "#;
+    assert_eq!(
+        assert_same_at_every_level(source),
+        "true\ntrue\nfalse\nfalse\ntrue\nfalse\ntrue\ntrue\ntrue\nbranch\nloop\n"
+    );
+}
+
+#[test]
+fn keeps_runtime_errors() {
+    let source = "print 1 + 2; print -\"a\" <= 1;";
+    let (output, error) = run(source, OptLevel::None);
+    assert_eq!(output, "3\n");
+    assert_eq!(error.map(|(kind, _)| kind), Some(RuntimeErrorKind::Script));
+    assert_same_at_every_level(source);
+}
@@ -0,0 +1,72 @@
+//! What the profiler counts per function and per stack of calls.
+
+use rlox::{profiler::Profiler, vm::VM, Options};
+
+const SCRIPT: &str = "\
+fun leaf() { return 1; }
+fun f(n) { return leaf() + n; }
+f(1);
+f(2);
+leaf();
+";
+
+fn profile(source: &str) -> Profiler {
+    let code = rlox::compile(source, &Options::default()).expect("the script compiles");
+    let mut profiler = Profiler::new();
+    {
+        let mut vm = VM::with_code(code);
+        vm.set_output(Box::new(std::io::sink()));
+        vm.set_tracer(Some(Box::new(|trace| profiler.record(trace))));
+        vm.run().expect("the script runs");
+    }
+    profiler.finish();
+    profiler
+}
+
+#[test]
+fn counts_the_calls_and_instructions_of_each_function() {
+    let profiler = profile(SCRIPT);
+    let counts = |name: &str| {
+        let stats = &profiler.functions()[name];
+        (stats.calls, stats.instructions)
+    };
+    // a call is counted in the caller, `leaf` runs a constant and a return
+    assert_eq!(counts("leaf"), (3, 6));
+    assert_eq!(counts("f"), (2, 10));
+    assert_eq!(counts("<Main>"), (1, 15));
+    assert_eq!(profiler.functions().len(), 3);
+
+    for stats in profiler.functions().values() {
+        assert!(stats.exclusive <= stats.inclusive);
+    }
+}
+
+#[test]
+fn folds_the_instructions_by_stack_of_calls() {
+    assert_eq!(
+        profile(SCRIPT).folded(),
+        "<Main> 15\n<Main>;f 10\n<Main>;f;leaf 4\n<Main>;leaf 2\n"
+    );
+}
+
+#[test]
+fn counts_every_call_of_a_recursive_function() {
+    let source = "fun down(n) { if (n > 0) down(n - 1); }\ndown(3);";
+    let profiler = profile(source);
+    assert_eq!(profiler.functions()["down"].calls, 4);
+    let folded = profiler.folded();
+    let stacks: Vec<_> = folded
+        .lines()
+        .map(|line| line.rsplit_once(' ').unwrap().0)
+        .collect();
+    assert_eq!(
+        stacks,
+        [
+            "<Main>",
+            "<Main>;down",
+            "<Main>;down;down",
+            "<Main>;down;down;down",
+            "<Main>;down;down;down;down",
+        ]
+    );
+}
@@ -0,0 +1,73 @@
+//! The warnings of the resolver, one test per lint, and silencing them with
+//! `// rlox: allow(...)`.
+
+use rlox::{
+    parser::Parser,
+    resolver::{self, Lint},
+};
+
+// The lint and line of each warning for `source`.
+fn warnings(source: &str) -> Vec<(Lint, usize)> {
+    let program = Parser::with_source(source)
+        .parse()
+        .expect("the script parses");
+    resolver::resolve(source, &program)
+        .iter()
+        .map(|warning| (warning.lint, warning.span.line))
+        .collect()
+}
+
+#[test]
+fn warns_of_unused_locals_and_parameters() {
+    let source = "fun f(a, b) {\n  var c = 1;\n  var d = 2;\n  return b + d;\n}\nf(1, 2);";
+    assert_eq!(warnings(source), [(Lint::Unused, 1), (Lint::Unused, 2)]);
+    // only locals and parameters are checked
+    assert_eq!(warnings("var g = 1;"), []);
+}
+
+#[test]
+fn warns_of_code_after_a_return() {
+    let source = "fun f() {\n  return 1;\n  print 2;\n  print 3;\n}\nf();";
+    // once, at the first statement that cannot run
+    assert_eq!(warnings(source), [(Lint::Unreachable, 3)]);
+    let source = "fun f(a) {\n  if (a) return 1;\n  print 2;\n}\nf(true);";
+    assert_eq!(warnings(source), []);
+}
+
+#[test]
+fn warns_of_locals_shadowing_an_outer_local() {
+    let source = "{\n  var a = 1;\n  {\n    var a = 2;\n    print a;\n  }\n  print a;\n}";
+    assert_eq!(warnings(source), [(Lint::Shadow, 4)]);
+    // shadowing a global is fine
+    assert_eq!(warnings("var a = 1;\n{\n  var a = 2;\n  print a;\n}"), []);
+}
+
+#[test]
+fn warns_of_assignments_to_undeclared_globals() {
+    assert_eq!(warnings("total = 1;"), [(Lint::Undeclared, 1)]);
+    // declared later in the file is declared
+    assert_eq!(warnings("fun f() { total = 1; }\nvar total;\nf();"), []);
+}
+
+#[test]
+fn warns_of_calls_with_the_wrong_number_of_arguments() {
+    let source = "fun f(a, b) { return a + b; }\nf(1);\nf(1, 2);\nf(1, 2, 3);";
+    assert_eq!(warnings(source), [(Lint::Arity, 2), (Lint::Arity, 4)]);
+    // once reassigned, the name may hold any function
+    let source = "fun f(a) { return a; }\nfun g() { return 0; }\nf = g;\nf();";
+    assert_eq!(warnings(source), []);
+}
+
+#[test]
+fn allows_the_lints_named_in_a_comment() {
+    let source = "fun f(a) {\n  return 1;\n  print 2;\n}\nf();";
+    assert_eq!(
+        warnings(source),
+        [(Lint::Unused, 1), (Lint::Unreachable, 3), (Lint::Arity, 5)]
+    );
+    let allowed = format!("// rlox: allow(unused, arity)\n{}", source);
+    assert_eq!(warnings(&allowed), [(Lint::Unreachable, 4)]);
+    // unknown names are ignored
+    let allowed = format!("{}\n  //rlox:allow( unreachable , typo )", source);
+    assert_eq!(warnings(&allowed), [(Lint::Unused, 1), (Lint::Arity, 5)]);
+}
@@ -0,0 +1,148 @@
+//! Bytecode the verifier rejects, built by hand since the compiler never emits it.
+
+use std::rc::Rc;
+
+use rlox::{
+    bytecode::{Chunk, FunctionObj, OpCode, Value},
+    verifier::{self, VerifyErrorKind},
+};
+
+fn chunk(code: &[OpCode], constants: Vec<Value>) -> Chunk {
+    let mut chunk = Chunk::new();
+    for constant in constants {
+        chunk.add_const(constant);
+    }
+    for ins in code {
+        chunk.write_ins(*ins, 1);
+    }
+    chunk
+}
+
+fn main(code: &[OpCode], constants: Vec<Value>, globals: &[&str]) -> FunctionObj {
+    let mut main = FunctionObj::new_main();
+    *main.chunk_mut() = chunk(code, constants);
+    main.set_global_names(globals.iter().map(|name| name.to_string()).collect());
+    main
+}
+
+fn main_of(code: &[OpCode]) -> FunctionObj {
+    main(code, Vec::new(), &[])
+}
+
+// The kind and offset of the error `main` is rejected with.
+fn rejected(main: &FunctionObj) -> (VerifyErrorKind, usize) {
+    let error = verifier::verify(main).expect_err("the code is rejected");
+    (error.kind().clone(), error.offset())
+}
+
+#[test]
+fn accepts_well_formed_code() {
+    let code = [
+        OpCode::Constant(0),
+        OpCode::DefineGlobal(0),
+        OpCode::GetGlobal(0),
+        OpCode::Print,
+        OpCode::Nil,
+        OpCode::Return,
+    ];
+    let main = main(&code, vec![Value::Number(1.0)], &["a"]);
+    assert_eq!(verifier::verify(&main), Ok(()));
+}
+
+#[test]
+fn rejects_constants_out_of_range() {
+    let code = [OpCode::Constant(0), OpCode::Constant(1), OpCode::Print];
+    let main = main(&code, vec![Value::Nil], &[]);
+    assert_eq!(
+        rejected(&main),
+        (VerifyErrorKind::ConstantOutOfRange { index: 1, len: 1 }, 2)
+    );
+}
+
+#[test]
+fn rejects_jumps_into_an_instruction_or_past_the_end() {
+    // `Constant(0)` takes two bytes, the jump lands on its operand
+    let code = [OpCode::Jump(Some(1)), OpCode::Constant(0), OpCode::Print];
+    let main = main(&code, vec![Value::Nil], &[]);
+    assert_eq!(
+        rejected(&main),
+        (VerifyErrorKind::JumpOutOfRange { target: 4 }, 0)
+    );
+
+    // the end of the code is a valid target, one past it is not
+    let code = [OpCode::Jump(Some(1)), OpCode::Nil];
+    assert_eq!(verifier::verify(&main_of(&code)), Ok(()));
+    let code = [OpCode::Jump(Some(2)), OpCode::Nil];
+    assert_eq!(
+        rejected(&main_of(&code)),
+        (VerifyErrorKind::JumpOutOfRange { target: 5 }, 0)
+    );
+
+    let code = [OpCode::Nil, OpCode::Loop(5)];
+    assert_eq!(
+        rejected(&main_of(&code)),
+        (VerifyErrorKind::JumpOutOfRange { target: -1 }, 1)
+    );
+}
+
+#[test]
+fn rejects_locals_past_the_top_of_the_frame() {
+    // slot 0 holds the main function, a function of one parameter also has slot 1
+    let code = [OpCode::Nil, OpCode::GetLocal(1), OpCode::Print];
+    assert_eq!(verifier::verify(&main_of(&code)), Ok(()));
+    let code = [OpCode::GetLocal(1), OpCode::Print];
+    assert_eq!(
+        rejected(&main_of(&code)),
+        (VerifyErrorKind::LocalOutOfRange { slot: 1, depth: 1 }, 0)
+    );
+
+    let code = [OpCode::GetLocal(2), OpCode::Return];
+    let fun = FunctionObj::with_chunk("f".to_string(), 1, chunk(&code, Vec::new()));
+    let main = main(&[], vec![Value::Function(Rc::new(fun))], &[]);
+    let error = verifier::verify(&main).expect_err("the code is rejected");
+    assert_eq!(error.function(), "f");
+    assert_eq!(
+        error.kind(),
+        &VerifyErrorKind::LocalOutOfRange { slot: 2, depth: 2 }
+    );
+}
+
+#[test]
+fn rejects_globals_missing_from_the_names_of_main() {
+    let code = [OpCode::GetGlobal(1), OpCode::Print];
+    assert_eq!(
+        rejected(&main(&code, Vec::new(), &["a"])),
+        (VerifyErrorKind::GlobalOutOfRange { slot: 1, len: 1 }, 0)
+    );
+
+    // functions have no names of their own, they use those of main
+    let code = [OpCode::GetGlobal(0), OpCode::Return];
+    let fun = FunctionObj::with_chunk("f".to_string(), 0, chunk(&code, Vec::new()));
+    let constants = vec![Value::Function(Rc::new(fun))];
+    assert_eq!(
+        verifier::verify(&main(&[], constants.clone(), &["a"])),
+        Ok(())
+    );
+    assert_eq!(
+        rejected(&main(&[], constants, &[])),
+        (VerifyErrorKind::GlobalOutOfRange { slot: 0, len: 0 }, 0)
+    );
+}
+
+#[test]
+fn rejects_paths_joining_with_different_stack_depths() {
+    // only one side of the branch pushes a value before they join at `Print`
+    let code = [
+        OpCode::True,
+        OpCode::JumpIfFalse(Some(1)),
+        OpCode::Nil,
+        OpCode::Print,
+    ];
+    let (kind, offset) = rejected(&main_of(&code));
+    assert!(
+        matches!(kind, VerifyErrorKind::InconsistentStack { .. }),
+        "{:?}",
+        kind
+    );
+    assert_eq!(offset, 5);
+}